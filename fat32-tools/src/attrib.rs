@@ -0,0 +1,91 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Args;
+use fat32::{DIR_ATTR_ARCHIVE, DIR_ATTR_DIRECTORY, DIR_ATTR_HIDDEN, DIR_ATTR_READ_ONLY, DIR_ATTR_SYSTEM};
+
+use crate::{image_path, open_driver, VolumeArgs};
+
+#[derive(Args)]
+pub struct AttribArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// Changes like +r, -h or +sa, followed by the paths to apply them to. Without changes the attributes are shown.
+    #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true, value_name = "[CHANGES] PATHS")]
+    args: Vec<String>,
+}
+
+const FLAGS: [(char, u8); 4] = [
+    ('r', DIR_ATTR_READ_ONLY),
+    ('h', DIR_ATTR_HIDDEN),
+    ('s', DIR_ATTR_SYSTEM),
+    ('a', DIR_ATTR_ARCHIVE),
+];
+
+/// Attributes as letters, e.g. d-h-- for a hidden directory
+pub fn attr_string(attr: u8) -> String {
+    let directory = if attr & DIR_ATTR_DIRECTORY != 0 { 'd' } else { '-' };
+
+    std::iter::once(directory)
+    .chain(FLAGS.iter().map(|(letter, flag)| if attr & flag != 0 { *letter } else { '-' }))
+    .collect()
+}
+
+/// Parses a change like +rh into the bits it sets or clears
+fn parse_change(change: &str) -> Option<(bool, u8)> {
+    let set = match change.chars().next()? {
+        '+' => true,
+        '-' => false,
+        _=> return None,
+    };
+
+    let mut bits = 0;
+    for c in change[1..].chars() {
+        bits |= FLAGS.iter().find(|(letter, _)| *letter == c.to_ascii_lowercase())?.1;
+    }
+
+    (bits != 0).then_some((set, bits))
+}
+
+pub fn run(args: AttribArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let mut set = 0;
+    let mut clear = 0;
+    let mut paths = vec![];
+
+    for arg in &args.args {
+        match parse_change(arg) {
+            Some((true, bits)) if paths.is_empty() => set |= bits,
+            Some((false, bits)) if paths.is_empty() => clear |= bits,
+            _=> paths.push(image_path(&PathBuf::from(arg))),
+        }
+    }
+
+    if paths.is_empty() {
+        return Err("No paths given".into());
+    }
+
+    let changing = set != 0 || clear != 0;
+    let driver = open_driver(&args.volume, changing)?;
+    let mut failed = false;
+
+    for path in paths {
+        let result = driver.search_by_path(&path).and_then(|file| {
+            if changing {
+                driver.set_attributes(&path, (file.attributes() | set) & !clear)
+            } else {
+                Ok(file)
+            }
+        });
+
+        match result {
+            Ok(file) => println!("{} {}", attr_string(file.attributes()), path.display()),
+            Err(error) => {
+                eprintln!("{}: {}", path.display(), error);
+                failed = true;
+            }
+        }
+    }
+
+    Ok(if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
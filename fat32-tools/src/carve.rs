@@ -0,0 +1,59 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Args;
+use fat32::carve::{carve, write_carved, CarveOptions, FileKind};
+
+use crate::{open_driver, parse_offset, print, VolumeArgs};
+
+#[derive(Args)]
+pub struct CarveArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// Directory to write the recovered files to, named after their first cluster. Only reports what was found if not given
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Kinds of files to look for: jpg, png, pdf, zip or mp4, all of them by default
+    #[arg(short, long = "type", value_name = "TYPE", value_delimiter = ',')]
+    types: Vec<String>,
+    /// Largest file to recover, K, M or G suffixes allowed
+    #[arg(long, value_name = "SIZE")]
+    max_size: Option<String>,
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+pub fn run(args: CarveArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let driver = open_driver(&args.volume, false)?;
+
+    let kinds = args.types.iter().map(|name| {
+        FileKind::ALL.into_iter()
+        .find(|kind| kind.extension() == name.to_lowercase() || (*kind == FileKind::Jpeg && name.eq_ignore_ascii_case("jpeg")))
+        .ok_or_else(|| format!("Unknown file type {}", name))
+    })
+    .collect::<Result<Vec<_>, _>>()?;
+
+    let options = CarveOptions {
+        kinds,
+        max_size: args.max_size.as_deref().map(parse_offset).transpose()?.map(|size| size as usize),
+    };
+
+    let report = carve(&driver, &options)?;
+
+    if let Some(output) = &args.output {
+        std::fs::create_dir_all(output)?;
+
+        for file in &report.files {
+            let path = output.join(format!("{:08}.{}", file.clusters.start, file.kind.extension()));
+            write_carved(&driver, file, BufWriter::new(File::create(&path)?))?;
+        }
+    }
+
+    print(&report, args.json)?;
+
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,38 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Args;
+use fat32::FatFs;
+
+use crate::{image_path, open_driver, VolumeArgs};
+
+#[derive(Args)]
+pub struct CatArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// Files to print, one after the other
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+pub fn run(args: CatArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let fs = FatFs::new(open_driver(&args.volume, false)?);
+    let mut stdout = std::io::stdout().lock();
+    let mut failed = false;
+
+    for path in &args.paths {
+        let path = image_path(path);
+
+        let result = fs.open(&path)
+        .map_err(std::io::Error::from)
+        .and_then(|mut file| std::io::copy(&mut file, &mut stdout));
+
+        if let Err(error) = result {
+            eprintln!("{}: {}", path.display(), error);
+            failed = true;
+        }
+    }
+
+    Ok(if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
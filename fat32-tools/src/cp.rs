@@ -0,0 +1,145 @@
+use std::error::Error;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::Args;
+use fat32::{is_plain_name, Fat32Error, FatFs};
+
+use crate::{image_path, open_driver, VolumeArgs};
+
+#[derive(Args)]
+pub struct CpArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// Copy directories and everything in them
+    #[arg(short, long)]
+    recursive: bool,
+    /// Files to copy, paths inside the image start with ::, e.g. ::/dir/file.txt
+    #[arg(required = true, num_args = 1..)]
+    sources: Vec<String>,
+    /// Where to copy to, an existing directory or the new name of a single source
+    destination: String,
+}
+
+/// The path inside the image of an argument like ::/dir/file.txt
+fn in_image(arg: &str) -> Option<PathBuf> {
+    arg.strip_prefix("::").map(|path| image_path(Path::new(path)))
+}
+
+pub fn copy_in(fs: &FatFs, local: &Path, target: &Path, recursive: bool) -> Result<(), Box<dyn Error>> {
+    let driver = fs.driver();
+
+    if std::fs::metadata(local)?.is_dir() {
+        if !recursive {
+            return Err(format!("{} is a directory, use -r to copy it", local.display()).into());
+        }
+
+        match driver.mkdir(target) {
+            Err(Fat32Error::AlreadyExists) if fs.metadata(target)?.is_dir => {},
+            result => { result?; },
+        }
+
+        let mut children = std::fs::read_dir(local)?.collect::<Result<Vec<_>, _>>()?;
+        children.sort_by_key(|child| child.file_name());
+
+        for child in children {
+            copy_in(fs, &child.path(), &target.join(child.file_name()), recursive)?;
+        }
+
+        return Ok(());
+    }
+
+    let file = File::open(local)?;
+    // Pipes and devices don't know their length
+    let metadata = file.metadata()?;
+    let len = metadata.is_file().then_some(metadata.len());
+
+    // Existing files are overwritten, like cp does
+    driver.replace_file(target, file, len)?;
+
+    Ok(())
+}
+
+pub fn copy_out(fs: &FatFs, image: &Path, local: &Path, recursive: bool) -> Result<(), Box<dyn Error>> {
+    let metadata = fs.metadata(image)?;
+
+    if metadata.is_dir {
+        if !recursive {
+            return Err(format!("::{} is a directory, use -r to copy it", image.display()).into());
+        }
+
+        std::fs::create_dir_all(local)?;
+
+        for child in fs.read_dir(image)? {
+            let child = child?;
+            if !is_plain_name(&child.file_name()) {
+                return Err(Fat32Error::InvalidName(child.file_name().to_string_lossy().into_owned()).into());
+            }
+            copy_out(fs, child.path(), &local.join(child.file_name()), recursive)?;
+        }
+
+        return Ok(());
+    }
+
+    let mut file = fs.open(image)?;
+    let mut output = File::create(local)?;
+
+    std::io::copy(&mut file, &mut output)?;
+    output.set_modified(metadata.modified)?;
+
+    Ok(())
+}
+
+pub fn run(args: CpArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let destination = in_image(&args.destination);
+    let sources = args.sources.iter().map(|source| (source, in_image(source))).collect::<Vec<_>>();
+
+    let copying_in = destination.is_some();
+    if sources.iter().any(|(_, image)| image.is_some() == copying_in) {
+        return Err("Either the sources or the destination have to be in the image, marked with ::".into());
+    }
+
+    let fs = FatFs::new(open_driver(&args.volume, copying_in)?);
+
+    let destination_is_dir = match &destination {
+        Some(image) => fs.metadata(image).is_ok_and(|metadata| metadata.is_dir),
+        None => Path::new(&args.destination).is_dir(),
+    };
+    if !destination_is_dir && sources.len() > 1 {
+        return Err(format!("{} is not a directory", args.destination).into());
+    }
+
+    let mut failed = false;
+
+    for (source, image) in sources {
+        let result = match (&image, &destination) {
+            (None, Some(destination)) => {
+                let local = Path::new(source);
+                let target = match local.file_name() {
+                    Some(name) if destination_is_dir => destination.join(name),
+                    _=> destination.clone(),
+                };
+
+                copy_in(&fs, local, &target, args.recursive)
+            },
+            (Some(image), None) => {
+                let destination = Path::new(&args.destination);
+                let target = match image.file_name() {
+                    Some(name) if destination_is_dir => destination.join(name),
+                    _=> destination.to_owned(),
+                };
+
+                copy_out(&fs, image, &target, args.recursive)
+            },
+            _=> unreachable!(),
+        };
+
+        if let Err(error) = result {
+            eprintln!("{}: {}", source, error);
+            failed = true;
+        }
+    }
+
+    Ok(if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
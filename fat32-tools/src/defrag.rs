@@ -0,0 +1,54 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Args;
+use fat32::defrag::DefragOptions;
+use fat32::Fat32Error;
+
+use crate::{open_driver, print, VolumeArgs};
+
+#[derive(Args)]
+pub struct DefragArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// Only defragment these paths, e.g. /boot/kernel.img
+    paths: Vec<PathBuf>,
+    /// Move all data to the beginning of the volume
+    #[arg(long)]
+    compact: bool,
+    /// Only report how fragmented the volume is
+    #[arg(long, conflicts_with = "compact")]
+    analyze: bool,
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+pub fn run(args: DefragArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let driver = open_driver(&args.volume, !args.analyze)?;
+
+    if args.analyze {
+        print(&fat32::defrag::fragmentation(&driver)?, args.json)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let options = DefragOptions {
+        compact: args.compact,
+        paths: args.paths,
+    };
+    let report = match fat32::defrag::defrag(&driver, &options) {
+        Err(Fat32Error::Inconsistent) => {
+            eprintln!("The volume has consistency errors, run `fat32 fsck --repair` on it first");
+            return Ok(ExitCode::FAILURE);
+        },
+        report => report?,
+    };
+    print(&report, args.json)?;
+
+    if report.skipped.is_empty() {
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(ExitCode::FAILURE)
+    }
+}
@@ -0,0 +1,128 @@
+use std::error::Error;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Args;
+use fat32::check::CheckReport;
+use fat32::repair::{LostChainPolicy, RepairLog, RepairOptions, RepairPlan};
+use serde::Serialize;
+
+use crate::{open_driver, VolumeArgs};
+
+/// Exit code used by fsck when errors were found and corrected
+const EXIT_CORRECTED: u8 = 1;
+/// Exit code used by fsck when errors were found and left uncorrected
+const EXIT_UNCORRECTED: u8 = 4;
+
+#[derive(Args)]
+pub struct FsckArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+    /// Repair the problems found, after showing what will be done
+    #[arg(long)]
+    repair: bool,
+    /// Only show the repairs that would be made
+    #[arg(long, requires = "repair")]
+    dry_run: bool,
+    /// Don't ask for confirmation before repairing
+    #[arg(short, long, requires = "repair")]
+    yes: bool,
+    /// Salvage lost cluster chains into FOUND.000 instead of freeing them
+    #[arg(long, requires = "repair")]
+    salvage: bool,
+    /// Append the log of applied repairs to this file
+    #[arg(long, requires = "repair")]
+    log: Option<PathBuf>,
+}
+
+fn confirm(prompt: &str) -> Result<bool, Box<dyn Error>> {
+    eprint!("{} [y/N] ", prompt);
+    std::io::stderr().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Everything fsck found and did, printed as a single JSON document
+#[derive(Default, Serialize)]
+struct FsckOutput {
+    report: Option<CheckReport>,
+    plan: Option<RepairPlan>,
+    log: Option<RepairLog>,
+}
+
+pub fn run(args: FsckArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let mut output = FsckOutput::default();
+    let result = fsck(&args, &mut output);
+
+    // Whatever was done before an error is still worth showing
+    if args.json && output.report.is_some() {
+        println!("{}", serde_json::to_string_pretty(&output)?);
+    }
+
+    result
+}
+
+/// Prints each stage as it's done, unless everything is printed as JSON at the end
+fn fsck(args: &FsckArgs, output: &mut FsckOutput) -> Result<ExitCode, Box<dyn Error>> {
+    let writable = args.repair && !args.dry_run;
+    let driver = open_driver(&args.volume, writable)?;
+    let report = output.report.insert(fat32::check::check(&driver)?);
+
+    if !args.json {
+        println!("{}", report);
+    }
+
+    if report.is_clean() {
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if !args.repair {
+        return Ok(ExitCode::from(EXIT_UNCORRECTED));
+    }
+
+    let lost_chains = if args.salvage { LostChainPolicy::Salvage } else { LostChainPolicy::Free };
+    let plan = output.plan.insert(fat32::repair::plan(&driver, report, &RepairOptions { lost_chains })?);
+
+    if !args.json {
+        println!("{}", plan);
+    }
+
+    if args.dry_run || plan.is_empty() {
+        return Ok(ExitCode::from(EXIT_UNCORRECTED));
+    }
+
+    if !args.yes {
+        // The JSON only comes at the end, the plan still has to be shown before asking
+        if args.json {
+            eprintln!("{}", plan);
+        }
+
+        if !confirm(&format!("Apply {} repair(s)?", plan.actions.len()))? {
+            return Ok(ExitCode::from(EXIT_UNCORRECTED));
+        }
+    }
+
+    let log = output.log.insert(fat32::repair::repair(&driver, plan)?);
+
+    if !args.json {
+        println!("{}", log);
+    }
+
+    if let Some(log_path) = &args.log {
+        let mut log_file = std::fs::OpenOptions::new().create(true).append(true).open(log_path)?;
+        writeln!(log_file, "{}", serde_json::to_string(log)?)?;
+    }
+
+    if log.n_failed() == 0 && plan.skipped.is_empty() {
+        Ok(ExitCode::from(EXIT_CORRECTED))
+    } else {
+        Ok(ExitCode::from(EXIT_UNCORRECTED))
+    }
+}
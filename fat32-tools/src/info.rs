@@ -0,0 +1,91 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::process::ExitCode;
+
+use clap::Args;
+use serde::Serialize;
+
+use crate::{open_driver, print, VolumeArgs};
+
+#[derive(Args)]
+pub struct InfoArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+}
+
+#[derive(Args)]
+pub struct DfArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// Print the usage as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+pub fn run(args: InfoArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let driver = open_driver(&args.volume, false)?;
+    let bpb = driver.bpb();
+
+    println!("{:#?}", bpb);
+    println!();
+    println!("Bytes per cluster:   {}", bpb.bytes_per_cluster());
+    println!("FAT start sector:    {}", bpb.fat_start_sector());
+    println!("FAT sectors:         {} ({} x {})", bpb.fat_sectors(), bpb.bpb_num_fats, bpb.bpb_fat_sz32);
+    println!("Data start sector:   {}", bpb.data_start_sector());
+    println!("Data sectors:        {}", bpb.data_sectors());
+    println!("Clusters:            {}", bpb.cluster_count());
+    println!("Volume ID:           {:04X}-{:04X}", bpb.bs_vol_id >> 16, bpb.bs_vol_id & 0xFFFF);
+    println!("Label:               {}", driver.label()?.unwrap_or_default());
+
+    let fs_info = driver.fs_info()?;
+    if fs_info.is_valid() {
+        let hint = |value: Option<u32>| value.map_or(String::from("unknown"), |value| value.to_string());
+
+        println!("FSInfo free count:   {}", hint(fs_info.free_count()));
+        println!("FSInfo next free:    {}", hint(fs_info.next_free()));
+    } else {
+        println!("FSInfo:              invalid");
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+#[derive(Serialize)]
+struct Usage {
+    cluster_size: u64,
+    total_clusters: u64,
+    free_clusters: u64,
+    total_bytes: u64,
+    used_bytes: u64,
+    free_bytes: u64,
+}
+
+impl Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let percent = if self.total_bytes == 0 { 0 } else { (self.used_bytes * 100).div_ceil(self.total_bytes) };
+
+        writeln!(f, "{:>14} {:>14} {:>14} {:>5}", "Size", "Used", "Avail", "Use%")?;
+        write!(f, "{:>14} {:>14} {:>14} {:>4}%", self.total_bytes, self.used_bytes, self.free_bytes, percent)
+    }
+}
+
+pub fn run_df(args: DfArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let driver = open_driver(&args.volume, false)?;
+
+    let cluster_size = driver.bytes_per_cluster() as u64;
+    let total_clusters = driver.cluster_count() as u64;
+    let free_clusters = driver.free_clusters()? as u64;
+
+    let usage = Usage {
+        cluster_size,
+        total_clusters,
+        free_clusters,
+        total_bytes: total_clusters * cluster_size,
+        used_bytes: (total_clusters - free_clusters) * cluster_size,
+        free_bytes: free_clusters * cluster_size,
+    };
+
+    print(&usage, args.json)?;
+
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,37 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Args;
+use fat32::FatFs;
+
+use crate::{image_path, open_driver, print, VolumeArgs};
+
+#[derive(Args)]
+pub struct InspectArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// Dump the raw entries of this directory, can be given more than once
+    #[arg(short, long = "dir", value_name = "PATH")]
+    dirs: Vec<PathBuf>,
+    /// Also dump every directory below the ones given, or below the root if none are
+    #[arg(short, long)]
+    recursive: bool,
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+pub fn run(args: InspectArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let fs = FatFs::new(open_driver(&args.volume, false)?);
+
+    let mut dirs = args.dirs.iter().map(|dir| image_path(dir)).collect::<Vec<_>>();
+    if dirs.is_empty() && args.recursive {
+        dirs.push(PathBuf::from("/"));
+    }
+
+    let inspection = fat32::inspect::inspect(&fs, &dirs, args.recursive)?;
+    print(&inspection, args.json)?;
+
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,34 @@
+use std::error::Error;
+use std::process::ExitCode;
+
+use clap::Args;
+
+use crate::{open_driver, VolumeArgs};
+
+#[derive(Args)]
+pub struct LabelArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// New label, up to 11 characters. Without it the current label is shown.
+    #[arg(conflicts_with = "clear")]
+    label: Option<String>,
+    /// Remove the label
+    #[arg(long)]
+    clear: bool,
+}
+
+pub fn run(args: LabelArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let writable = args.label.is_some() || args.clear;
+    let driver = open_driver(&args.volume, writable)?;
+
+    if writable {
+        driver.set_label(args.label.as_deref())?;
+    } else {
+        match driver.label()? {
+            Some(label) => println!("{}", label),
+            None => println!("Volume has no label"),
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
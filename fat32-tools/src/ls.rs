@@ -0,0 +1,73 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use chrono::{DateTime, Local};
+use clap::Args;
+use fat32::{FatFs, Metadata, DIR_ATTR_HIDDEN, DIR_ATTR_SYSTEM};
+
+use crate::attrib::attr_string;
+use crate::{image_path, open_driver, VolumeArgs};
+
+#[derive(Args)]
+pub struct LsArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// Directory or file to list
+    #[arg(default_value = "/")]
+    path: PathBuf,
+    /// Show attributes, size and modification time
+    #[arg(short, long)]
+    long: bool,
+    /// Include hidden and system entries
+    #[arg(short, long)]
+    all: bool,
+    /// List subdirectories too, with their full paths
+    #[arg(short = 'R', long)]
+    recursive: bool,
+}
+
+fn print_entry(name: &Path, metadata: &Metadata, long: bool) {
+    if long {
+        let modified = DateTime::<Local>::from(metadata.modified);
+
+        println!("{} {:>10} {} {}", attr_string(metadata.attributes), metadata.len, modified.format("%Y-%m-%d %H:%M"), name.display());
+    } else {
+        println!("{}", name.display());
+    }
+}
+
+pub fn run(args: LsArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let fs = FatFs::new(open_driver(&args.volume, false)?);
+    let path = image_path(&args.path);
+
+    let metadata = fs.metadata(&path)?;
+    if metadata.is_file() {
+        print_entry(&path, &metadata, args.long);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let shown = |metadata: &Metadata| args.all || metadata.attributes & (DIR_ATTR_HIDDEN | DIR_ATTR_SYSTEM) == 0;
+    let mut failed = false;
+
+    let entries: Box<dyn Iterator<Item = _>> = if args.recursive {
+        Box::new(fs.walk(&path).filter_entry(|entry| shown(&entry.metadata())))
+    } else {
+        Box::new(fs.read_dir(&path)?.filter(|entry| entry.as_ref().map_or(true, |entry| shown(&entry.metadata()))))
+    };
+
+    for entry in entries {
+        match entry {
+            Ok(entry) => {
+                let name = if args.recursive { entry.path().to_owned() } else { PathBuf::from(entry.file_name()) };
+                print_entry(&name, &entry.metadata(), args.long);
+            },
+            Err(error) => {
+                eprintln!("{}: {}", path.display(), error);
+                failed = true;
+            }
+        }
+    }
+
+    Ok(if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
@@ -0,0 +1,169 @@
+mod attrib;
+mod carve;
+mod cat;
+mod cp;
+mod defrag;
+mod fsck;
+mod info;
+mod inspect;
+mod label;
+mod ls;
+mod mkdir;
+mod mv;
+mod rm;
+mod shell;
+mod timeline;
+mod undelete;
+
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::{Args, Parser, Subcommand};
+use fat32::{Drive, Driver};
+use serde::Serialize;
+
+#[derive(Parser)]
+#[command(name = "fat32", about = "Work with FAT32 images without mounting them")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check the consistency of a volume
+    Fsck(fsck::FsckArgs),
+    /// Make files and directories contiguous
+    Defrag(defrag::DefragArgs),
+    /// List a directory
+    Ls(ls::LsArgs),
+    /// Print files to stdout
+    Cat(cat::CatArgs),
+    /// Copy files into or out of the volume, image paths start with ::
+    Cp(cp::CpArgs),
+    /// Remove files or directories
+    Rm(rm::RmArgs),
+    /// Create directories
+    Mkdir(mkdir::MkdirArgs),
+    /// Move or rename files and directories
+    Mv(mv::MvArgs),
+    /// Show or change the read-only, hidden, system and archive attributes
+    Attrib(attrib::AttribArgs),
+    /// Show or change the volume label
+    Label(label::LabelArgs),
+    /// Dump the boot sector and the geometry derived from it
+    Info(info::InfoArgs),
+    /// Show how much space is used and free
+    Df(info::DfArgs),
+    /// Dump the on-disk structures: boot sector, FSInfo, FAT usage and raw directory entries
+    Inspect(inspect::InspectArgs),
+    /// List deleted files and directories, and restore or extract them
+    Undelete(undelete::UndeleteArgs),
+    /// Recover JPEG, PNG, PDF, ZIP and MP4 files from free clusters by their signatures
+    Carve(carve::CarveArgs),
+    /// Print the MAC times of live and deleted entries as a Sleuth Kit body file
+    Timeline(timeline::TimelineArgs),
+    /// Explore a volume interactively
+    Shell(shell::ShellArgs),
+}
+
+/// Which volume to work on, shared by every command
+#[derive(Args)]
+pub struct VolumeArgs {
+    /// Path to the FAT32 image or block device, optionally followed by @@OFFSET in bytes (K, M or G suffixes allowed)
+    image: String,
+    /// Use partition N of a partitioned image or disk, counting from 1
+    #[arg(long, value_name = "N")]
+    partition: Option<usize>,
+}
+
+/// Parses an offset like 1048576, 1024K or 1M
+fn parse_offset(offset: &str) -> Result<u64, Box<dyn Error>> {
+    let (digits, multiplier) = match offset.char_indices().last() {
+        Some((ix, 'k' | 'K')) => (&offset[..ix], 1 << 10),
+        Some((ix, 'm' | 'M')) => (&offset[..ix], 1 << 20),
+        Some((ix, 'g' | 'G')) => (&offset[..ix], 1 << 30),
+        _=> (offset, 1),
+    };
+
+    let value = digits.parse::<u64>().map_err(|_| format!("Invalid offset: {}", offset))?;
+
+    Ok(value * multiplier)
+}
+
+impl VolumeArgs {
+    /// The image path and the byte offset given after @@, if any
+    fn image(&self) -> Result<(PathBuf, Option<u64>), Box<dyn Error>> {
+        match self.image.rsplit_once("@@") {
+            Some((path, offset)) => Ok((PathBuf::from(path), Some(parse_offset(offset)?))),
+            None => Ok((PathBuf::from(&self.image), None)),
+        }
+    }
+}
+
+pub fn open_driver(volume: &VolumeArgs, writable: bool) -> Result<Driver, Box<dyn Error>> {
+    let (image, offset) = volume.image()?;
+
+    let file = std::fs::OpenOptions::new().read(true).write(writable).open(&image)?;
+    let mut drive = Drive::from_file(file)?;
+
+    match (offset, volume.partition) {
+        (Some(_), Some(_)) => return Err("@@offset and --partition can't be used together".into()),
+        (Some(offset), None) => drive = drive.with_offset(offset),
+        (None, Some(number)) => {
+            let partition = fat32::partition::partition(&drive, number)?;
+            log::info!("Partition {} starts at byte {}", number, partition.start);
+            drive = drive.with_offset(partition.start);
+        },
+        (None, None) => {},
+    }
+
+    Ok(Driver::new(drive)?)
+}
+
+/// Makes a path inside the volume absolute, so they can be given as `dir/file` too
+pub fn image_path(path: &Path) -> PathBuf {
+    Path::new("/").join(path)
+}
+
+/// Prints a report as JSON or in its human readable form
+pub fn print<T: Serialize + std::fmt::Display>(value: &T, json: bool) -> Result<(), Box<dyn Error>> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value)?);
+    } else {
+        println!("{}", value);
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<ExitCode, Box<dyn Error>> {
+    simple_logger::SimpleLogger::new()
+    .with_level(log::LevelFilter::Warn)
+    .env()
+    .init()
+    .unwrap();
+
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Fsck(args) => fsck::run(args),
+        Command::Defrag(args) => defrag::run(args),
+        Command::Ls(args) => ls::run(args),
+        Command::Cat(args) => cat::run(args),
+        Command::Cp(args) => cp::run(args),
+        Command::Rm(args) => rm::run(args),
+        Command::Mkdir(args) => mkdir::run(args),
+        Command::Mv(args) => mv::run(args),
+        Command::Attrib(args) => attrib::run(args),
+        Command::Label(args) => label::run(args),
+        Command::Info(args) => info::run(args),
+        Command::Df(args) => info::run_df(args),
+        Command::Inspect(args) => inspect::run(args),
+        Command::Undelete(args) => undelete::run(args),
+        Command::Carve(args) => carve::run(args),
+        Command::Timeline(args) => timeline::run(args),
+        Command::Shell(args) => shell::run(args),
+    }
+}
@@ -0,0 +1,57 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use clap::Args;
+use fat32::{Driver, Fat32Error, Fat32Result};
+
+use crate::{image_path, open_driver, VolumeArgs};
+
+#[derive(Args)]
+pub struct MkdirArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// Create missing parents too, and don't fail if the directory exists
+    #[arg(short, long)]
+    parents: bool,
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+fn mkdir_parents(driver: &Driver, path: &Path) -> Fat32Result<()> {
+    let mut ancestors = path.ancestors().collect::<Vec<_>>();
+    ancestors.reverse();
+
+    for ancestor in ancestors.into_iter().skip(1) {
+        match driver.search_by_path(ancestor) {
+            Ok(existing) if existing.is_dir() => {},
+            Ok(_) => return Err(Fat32Error::NotADir),
+            Err(Fat32Error::NotFound) => { driver.mkdir(ancestor)?; },
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn run(args: MkdirArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let driver = open_driver(&args.volume, true)?;
+    let mut failed = false;
+
+    for path in &args.paths {
+        let path = image_path(path);
+
+        let result = if args.parents {
+            mkdir_parents(&driver, &path)
+        } else {
+            driver.mkdir(&path).map(|_| ())
+        };
+
+        if let Err(error) = result {
+            eprintln!("{}: {}", path.display(), error);
+            failed = true;
+        }
+    }
+
+    Ok(if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
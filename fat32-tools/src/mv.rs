@@ -0,0 +1,44 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Args;
+
+use crate::{image_path, open_driver, VolumeArgs};
+
+#[derive(Args)]
+pub struct MvArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    #[arg(required = true, num_args = 1..)]
+    sources: Vec<PathBuf>,
+    /// An existing directory to move into, or the new name of a single source
+    destination: PathBuf,
+}
+
+pub fn run(args: MvArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let driver = open_driver(&args.volume, true)?;
+    let destination = image_path(&args.destination);
+
+    let destination_is_dir = driver.search_by_path(&destination).is_ok_and(|existing| existing.is_dir());
+    if !destination_is_dir && args.sources.len() > 1 {
+        return Err(format!("{} is not a directory", destination.display()).into());
+    }
+
+    let mut failed = false;
+
+    for source in &args.sources {
+        let source = image_path(source);
+        let target = match source.file_name() {
+            Some(name) if destination_is_dir => destination.join(name),
+            _=> destination.clone(),
+        };
+
+        if let Err(error) = driver.rename(&source, &target) {
+            eprintln!("{}: {}", source.display(), error);
+            failed = true;
+        }
+    }
+
+    Ok(if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
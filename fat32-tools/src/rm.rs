@@ -0,0 +1,42 @@
+use std::error::Error;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Args;
+
+use crate::{image_path, open_driver, VolumeArgs};
+
+#[derive(Args)]
+pub struct RmArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// Remove directories and everything in them
+    #[arg(short, long)]
+    recursive: bool,
+    #[arg(required = true)]
+    paths: Vec<PathBuf>,
+}
+
+pub fn run(args: RmArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let driver = open_driver(&args.volume, true)?;
+    let mut failed = false;
+
+    for path in &args.paths {
+        let path = image_path(path);
+
+        let result = driver.search_by_path(&path).and_then(|file| {
+            match (file.is_dir(), args.recursive) {
+                (true, true) => driver.remove_dir_all(&path),
+                (true, false) => Err(fat32::Fat32Error::IsDir),
+                (false, _) => driver.remove_file(&path),
+            }
+        });
+
+        if let Err(error) = result {
+            eprintln!("{}: {}", path.display(), error);
+            failed = true;
+        }
+    }
+
+    Ok(if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
@@ -0,0 +1,497 @@
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+
+use chrono::{DateTime, Local};
+use clap::Args;
+use fat32::inspect::RawEntry;
+use fat32::{extents_of, FatDirectory, FatFs, DIR_ATTR_HIDDEN, DIR_ATTR_SYSTEM};
+use rustyline::completion::{escape, extract_word, unescape, Completer, FilenameCompleter, Pair, Quote};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{CompletionType, Config, Context, Editor, Helper};
+
+use crate::attrib::attr_string;
+use crate::cp::{copy_in, copy_out};
+use crate::{open_driver, VolumeArgs};
+
+const HELP: &str = "\
+cd [DIR]                    Change the current directory, / by default
+pwd                         Print the current directory
+ls [-l] [-a] [PATH]         List a directory, -a includes hidden, deleted and dot entries
+stat PATH                   Show everything the entry of PATH records
+cat FILE                    Print a file
+hexdump FILE [OFFSET [LEN]] Dump a file in hex, OFFSET and LEN may be given in hex with 0x
+chain PATH                  Print the cluster chain of a file or directory
+entry PATH                  Dump the raw 32 byte entries of PATH, long name entries included
+get PATH [LOCAL]            Copy a file or directory out of the image
+put LOCAL [PATH]            Copy a file or directory into the image, needs --write
+help                        Show this help
+exit                        Leave the shell";
+
+const COMMANDS: [&str; 13] = ["cd", "pwd", "ls", "stat", "cat", "hexdump", "chain", "entry", "get", "put", "help", "exit", "quit"];
+/// Kept in the home directory
+const HISTORY_FILE: &str = ".fat32_shell_history";
+const HEXDUMP_WIDTH: usize = 16;
+
+#[derive(Args)]
+pub struct ShellArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// Open the image writable, which put needs
+    #[arg(long)]
+    write: bool,
+}
+
+type ShellResult<T> = Result<T, Box<dyn Error>>;
+
+fn is_break_char(c: char) -> bool {
+    c == ' '
+}
+
+/// Splits a command line into words, honouring quotes and backslash escapes
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut word: Option<String> = None;
+    let mut quote = None;
+
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', quote) if quote != Some('\'') => {
+                let escaped = chars.next().ok_or("Trailing backslash")?;
+                word.get_or_insert_default().push(escaped);
+            },
+            ('\'' | '"', None) => {
+                quote = Some(c);
+                word.get_or_insert_default();
+            },
+            (c, Some(open)) if c == open => quote = None,
+            (c, None) if c.is_whitespace() => words.extend(word.take()),
+            (c, _) => word.get_or_insert_default().push(c),
+        }
+    }
+
+    if quote.is_some() {
+        return Err(String::from("Unterminated quote"));
+    }
+    words.extend(word);
+
+    Ok(words)
+}
+
+/// Parses a decimal or 0x prefixed hex number
+fn parse_number(text: &str) -> ShellResult<u64> {
+    let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+
+    parsed.map_err(|_| format!("Invalid number: {}", text).into())
+}
+
+fn format_time(time: std::time::SystemTime) -> String {
+    DateTime::<Local>::from(time).format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn hex_line(offset: u64, bytes: &[u8]) -> String {
+    let hex = (0..HEXDUMP_WIDTH)
+    .map(|ix| bytes.get(ix).map_or(String::from("  "), |byte| format!("{:02x}", byte)))
+    .collect::<Vec<_>>()
+    .join(" ");
+
+    let ascii = bytes.iter()
+    .map(|byte| if byte.is_ascii_graphic() || *byte == b' ' { *byte as char } else { '.' })
+    .collect::<String>();
+
+    format!("{:08x}  {}  |{}|", offset, hex, ascii)
+}
+
+struct Shell {
+    fs: FatFs,
+    cwd: PathBuf,
+    writable: bool,
+    local_files: FilenameCompleter,
+}
+
+impl Shell {
+    /// Makes `arg` absolute and resolves `.` and `..` in it
+    fn resolve(&self, arg: &str) -> PathBuf {
+        let mut path = PathBuf::from("/");
+
+        for component in self.cwd.join(arg).components() {
+            match component {
+                Component::Normal(name) => path.push(name),
+                Component::ParentDir => { path.pop(); },
+                _=> {}
+            }
+        }
+
+        path
+    }
+    fn lookup(&self, arg: Option<&String>) -> ShellResult<(PathBuf, FatDirectory)> {
+        let arg = arg.ok_or("Missing path")?;
+        let path = self.resolve(arg);
+        let directory = self.fs.driver().search_by_path(&path)?;
+
+        Ok((path, directory))
+    }
+    /// Runs one command line, returning false when the shell should exit
+    fn execute(&mut self, words: &[String]) -> ShellResult<bool> {
+        let args = &words[1..];
+
+        match words[0].as_str() {
+            "cd" => self.cd(args)?,
+            "pwd" => println!("{}", self.cwd.display()),
+            "ls" => self.ls(args)?,
+            "stat" => self.stat(args)?,
+            "cat" => self.cat(args)?,
+            "hexdump" => self.hexdump(args)?,
+            "chain" => self.chain(args)?,
+            "entry" => self.entry(args)?,
+            "get" => self.get(args)?,
+            "put" => self.put(args)?,
+            "help" => println!("{}", HELP),
+            "exit" | "quit" => return Ok(false),
+            other => return Err(format!("Unknown command {}, try help", other).into()),
+        }
+
+        Ok(true)
+    }
+    fn cd(&mut self, args: &[String]) -> ShellResult<()> {
+        let arg = args.first().map_or("/", String::as_str);
+
+        // Spelled the way it's stored, so the prompt shows the real names
+        let path = self.fs.canonicalize(self.resolve(arg))?;
+        if !self.fs.metadata(&path)?.is_dir {
+            return Err(fat32::Fat32Error::NotADir.into());
+        }
+
+        self.cwd = path;
+
+        Ok(())
+    }
+    fn print_entry(file: &FatDirectory, long: bool) {
+        let note = if file.is_deleted() {
+            " (deleted)"
+        } else if file.is_volume_id() {
+            " (volume label)"
+        } else {
+            ""
+        };
+
+        if long {
+            println!(
+                "{} {:>10} {:>8} {} {}{}",
+                attr_string(file.attributes()), file.file_size(), file.cluster_num(), format_time(file.write_time()), file.name().to_string_lossy(), note
+            );
+        } else {
+            println!("{}{}", file.name().to_string_lossy(), note);
+        }
+    }
+    fn ls(&self, args: &[String]) -> ShellResult<()> {
+        let (flags, paths): (Vec<&String>, Vec<&String>) = args.iter().partition(|arg| arg.starts_with('-') && arg.len() > 1);
+
+        let mut long = false;
+        let mut all = false;
+        for flag in flags.iter().flat_map(|flag| flag.chars().skip(1)) {
+            match flag {
+                'l' => long = true,
+                'a' => all = true,
+                other => return Err(format!("Unknown option -{}", other).into()),
+            }
+        }
+
+        let path = self.resolve(paths.first().map_or(".", |path| path.as_str()));
+        let driver = self.fs.driver();
+        let directory = driver.search_by_path(&path)?;
+
+        if !directory.is_dir() {
+            Self::print_entry(&directory, long);
+            return Ok(());
+        }
+
+        let mut files = driver.files(&directory);
+        while let Some(file) = files.next()? {
+            let hidden = file.is_deleted() || file.is_volume_id() || file.is_current_dir() || file.is_parent_dir()
+            || file.attributes() & (DIR_ATTR_HIDDEN | DIR_ATTR_SYSTEM) != 0;
+
+            if all || !hidden {
+                Self::print_entry(&file, long);
+            }
+        }
+
+        Ok(())
+    }
+    fn stat(&self, args: &[String]) -> ShellResult<()> {
+        let (path, file) = self.lookup(args.first())?;
+        let driver = self.fs.driver();
+
+        let n_clusters = match file.cluster_num() {
+            0 if file.is_file() => 0,
+            _=> driver.chain(chain_start(&file, driver))?.len(),
+        };
+
+        println!("Path:          {}", path.display());
+        println!("Short name:    {}", file.entry().short_name().to_string_lossy());
+        println!("Type:          {}", if file.is_dir() { "directory" } else { "file" });
+        println!("Size:          {}", file.file_size());
+        println!("Attributes:    {} ({:#04x})", attr_string(file.attributes()), file.attributes());
+        println!("First cluster: {}", file.cluster_num());
+        println!("Clusters:      {}", n_clusters);
+        match file.location() {
+            Some(location) => println!("Entry:         cluster {}, index {}", location.cluster, location.index),
+            None => println!("Entry:         none, this is the root directory"),
+        }
+        println!("Created:       {}", format_time(file.create_time()));
+        println!("Modified:      {}", format_time(file.write_time()));
+        println!("Accessed:      {}", format_time(file.access_time()));
+
+        Ok(())
+    }
+    fn cat(&self, args: &[String]) -> ShellResult<()> {
+        let (path, _) = self.lookup(args.first())?;
+
+        let mut file = self.fs.open(&path)?;
+        let mut stdout = std::io::stdout().lock();
+        std::io::copy(&mut file, &mut stdout)?;
+        stdout.flush()?;
+
+        Ok(())
+    }
+    fn hexdump(&self, args: &[String]) -> ShellResult<()> {
+        let (path, _) = self.lookup(args.first())?;
+
+        let offset = args.get(1).map(|offset| parse_number(offset)).transpose()?.unwrap_or(0);
+        let length = args.get(2).map(|length| parse_number(length)).transpose()?.unwrap_or(u64::MAX);
+
+        let mut file = self.fs.open(&path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = file.take(length);
+
+        let mut stdout = std::io::stdout().lock();
+        let mut line = [0; HEXDUMP_WIDTH];
+        let mut position = offset;
+
+        loop {
+            let mut n_read = 0;
+            while n_read < HEXDUMP_WIDTH {
+                match reader.read(&mut line[n_read..])? {
+                    0 => break,
+                    n => n_read += n,
+                }
+            }
+            if n_read == 0 {
+                break;
+            }
+
+            writeln!(stdout, "{}", hex_line(position, &line[..n_read]))?;
+            position += n_read as u64;
+        }
+
+        Ok(())
+    }
+    fn chain(&self, args: &[String]) -> ShellResult<()> {
+        let (_, file) = self.lookup(args.first())?;
+        let driver = self.fs.driver();
+
+        if file.is_file() && file.cluster_num() == 0 {
+            println!("Empty file, no clusters");
+            return Ok(());
+        }
+
+        let chain = driver.chain(chain_start(&file, driver))?;
+        let extents = extents_of(&chain);
+
+        println!("{} cluster(s) in {} extent(s)", chain.len(), extents.len());
+        for extent in extents {
+            match extent.length {
+                1 => println!("  {}", extent.start),
+                _=> println!("  {}-{} ({})", extent.start, extent.end() - 1, extent.length),
+            }
+        }
+
+        Ok(())
+    }
+    fn entry(&self, args: &[String]) -> ShellResult<()> {
+        let (path, file) = self.lookup(args.first())?;
+        let driver = self.fs.driver();
+
+        let parent = driver.search_by_path(path.parent().ok_or("The root directory has no entry")?)?;
+
+        for slot in driver.entry_slots(&parent, &file)? {
+            let raw = driver.raw_entry(slot)?;
+
+            println!("cluster {}, index {}:", slot.cluster, slot.index);
+            for (ix, chunk) in raw.chunks(HEXDUMP_WIDTH).enumerate() {
+                println!("  {}", hex_line((ix * HEXDUMP_WIDTH) as u64, chunk));
+            }
+            println!("  {}", RawEntry::decode(slot, raw).kind);
+        }
+
+        Ok(())
+    }
+    fn get(&self, args: &[String]) -> ShellResult<()> {
+        let (path, _) = self.lookup(args.first())?;
+
+        let local = match args.get(1) {
+            Some(local) => PathBuf::from(local),
+            None => PathBuf::from(path.file_name().ok_or("Give a local path to copy the root directory to")?),
+        };
+        let local = match path.file_name() {
+            Some(name) if local.is_dir() => local.join(name),
+            _=> local,
+        };
+
+        copy_out(&self.fs, &path, &local, true)
+    }
+    fn put(&self, args: &[String]) -> ShellResult<()> {
+        if !self.writable {
+            return Err("The image was opened read-only, start the shell with --write".into());
+        }
+
+        let local = Path::new(args.first().ok_or("Missing local path")?);
+        let target = self.resolve(args.get(1).map_or(".", String::as_str));
+
+        let target = match local.file_name() {
+            Some(name) if self.fs.metadata(&target).is_ok_and(|metadata| metadata.is_dir) => target.join(name),
+            _=> target,
+        };
+
+        copy_in(&self.fs, local, &target, true)
+    }
+    fn complete_image_path(&self, word: &str) -> Vec<Pair> {
+        let (directory, prefix) = match word.rfind('/') {
+            Some(ix) => word.split_at(ix + 1),
+            None => ("", word),
+        };
+
+        let driver = self.fs.driver();
+        let Ok(parent) = driver.search_by_path(&self.resolve(directory)) else {
+            return vec![];
+        };
+
+        let mut candidates = vec![];
+
+        let mut files = driver.files(&parent);
+        while let Ok(Some(file)) = files.next() {
+            if file.is_deleted() || file.is_volume_id() || file.is_current_dir() || file.is_parent_dir() {
+                continue;
+            }
+
+            let name = file.name().to_string_lossy();
+            if !name.starts_with(prefix) {
+                continue;
+            }
+
+            let suffix = if file.is_dir() { "/" } else { "" };
+            candidates.push(Pair {
+                display: format!("{}{}", name, suffix),
+                replacement: escape(format!("{}{}{}", directory, name, suffix), Some('\\'), is_break_char, Quote::None),
+            });
+        }
+
+        candidates
+    }
+}
+
+/// Where the chain of `file` starts, `..` entries store 0 for the root
+fn chain_start(file: &FatDirectory, driver: &fat32::Driver) -> usize {
+    match file.cluster_num() {
+        0 => driver.bpb().bpb_root_clus as usize,
+        cluster => cluster,
+    }
+}
+
+impl Completer for Shell {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, word) = extract_word(line, pos, Some('\\'), is_break_char);
+        let before = split_words(&line[..start]).unwrap_or_default();
+
+        let Some(command) = before.first() else {
+            let commands = COMMANDS.iter()
+            .filter(|command| command.starts_with(word))
+            .map(|command| Pair { display: command.to_string(), replacement: format!("{} ", command) })
+            .collect();
+
+            return Ok((start, commands));
+        };
+
+        // The arguments that name files on the host
+        if matches!((command.as_str(), before.len()), ("put", 1) | ("get", 2)) {
+            return self.local_files.complete(line, pos, ctx);
+        }
+
+        Ok((start, self.complete_image_path(&unescape(word, Some('\\')))))
+    }
+}
+
+impl Hinter for Shell {
+    type Hint = String;
+}
+impl Highlighter for Shell {}
+impl Validator for Shell {}
+impl Helper for Shell {}
+
+pub fn run(args: ShellArgs) -> ShellResult<ExitCode> {
+    let fs = FatFs::new(open_driver(&args.volume, args.write)?);
+
+    let config = Config::builder()
+    .completion_type(CompletionType::List)
+    .auto_add_history(true)
+    .build();
+
+    let mut editor = Editor::<Shell, DefaultHistory>::with_config(config)?;
+    editor.set_helper(Some(Shell {
+        fs,
+        cwd: PathBuf::from("/"),
+        writable: args.write,
+        local_files: FilenameCompleter::new(),
+    }));
+
+    let history = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(history) = &history {
+        // There's none the first time
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        let prompt = format!("{}> ", editor.helper().unwrap().cwd.display());
+
+        let line = match editor.readline(&prompt) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(error) => return Err(error.into()),
+        };
+
+        let words = match split_words(&line) {
+            Ok(words) if words.is_empty() => continue,
+            Ok(words) => words,
+            Err(error) => {
+                eprintln!("{}", error);
+                continue;
+            }
+        };
+
+        match editor.helper_mut().unwrap().execute(&words) {
+            Ok(true) => {},
+            Ok(false) => break,
+            Err(error) => eprintln!("{}: {}", words[0], error),
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(error) = editor.save_history(history) {
+            log::warn!("Couldn't save the history to {}: {}", history.display(), error);
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,49 @@
+use std::error::Error;
+use std::process::ExitCode;
+
+use chrono::FixedOffset;
+use clap::Args;
+use fat32::timeline::{timeline, TimestampZone};
+use fat32::FatFs;
+
+use crate::{open_driver, VolumeArgs};
+
+#[derive(Args)]
+pub struct TimelineArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// Read the timestamps as UTC instead of the local time zone
+    #[arg(long, conflicts_with = "offset")]
+    utc: bool,
+    /// Read the timestamps as local time at this offset from UTC, like +02:00
+    #[arg(long, value_name = "OFFSET", allow_hyphen_values = true)]
+    offset: Option<FixedOffset>,
+    /// Leave out deleted entries
+    #[arg(long)]
+    no_deleted: bool,
+    /// Print the entries as JSON instead of body file lines
+    #[arg(long)]
+    json: bool,
+}
+
+pub fn run(args: TimelineArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let fs = FatFs::new(open_driver(&args.volume, false)?);
+
+    let zone = match (args.utc, args.offset) {
+        (true, _) => TimestampZone::Utc,
+        (false, Some(offset)) => TimestampZone::Offset(offset),
+        (false, None) => TimestampZone::Local,
+    };
+
+    let entries = timeline(&fs, zone, !args.no_deleted)?;
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        for entry in &entries {
+            println!("{}", entry);
+        }
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
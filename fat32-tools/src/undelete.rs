@@ -0,0 +1,115 @@
+use std::error::Error;
+use std::fs::File;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::Args;
+use fat32::recover::{deleted_entries, extract, restore, DeletedEntries, DeletedEntry, Recoverability};
+use fat32::{EntryLocation, FatFs};
+
+use crate::{image_path, open_driver, VolumeArgs};
+
+#[derive(Args)]
+pub struct UndeleteArgs {
+    #[command(flatten)]
+    volume: VolumeArgs,
+    /// Directories to look for deleted entries in, the root if none are given
+    dirs: Vec<PathBuf>,
+    /// Also look in every directory below them
+    #[arg(short, long)]
+    recursive: bool,
+    /// Undelete the entry at CLUSTER:INDEX in place, as listed
+    #[arg(long, value_name = "CLUSTER:INDEX", value_parser = parse_location, conflicts_with = "extract")]
+    restore: Option<EntryLocation>,
+    /// First character to give the short name when restoring, instead of the one worked out
+    #[arg(long, value_name = "CHAR", requires = "restore")]
+    first_char: Option<char>,
+    /// Copy the contents of the entry at CLUSTER:INDEX out to --output, leaving the volume alone
+    #[arg(long, value_name = "CLUSTER:INDEX", value_parser = parse_location, requires = "output")]
+    extract: Option<EntryLocation>,
+    /// Where to write the extracted file
+    #[arg(short, long, requires = "extract")]
+    output: Option<PathBuf>,
+    /// Print the list as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+fn parse_location(text: &str) -> Result<EntryLocation, String> {
+    let invalid = || format!("Invalid entry location {}, expected CLUSTER:INDEX", text);
+
+    let (cluster, index) = text.split_once(':').ok_or_else(invalid)?;
+
+    Ok(EntryLocation {
+        cluster: cluster.parse().map_err(|_| invalid())?,
+        index: index.parse().map_err(|_| invalid())?,
+    })
+}
+
+fn find(entries: &[DeletedEntry], location: EntryLocation) -> Result<&DeletedEntry, Box<dyn Error>> {
+    entries.iter()
+    .find(|entry| entry.location == location)
+    .ok_or_else(|| format!("No deleted entry at {}:{}", location.cluster, location.index).into())
+}
+
+pub fn run(args: UndeleteArgs) -> Result<ExitCode, Box<dyn Error>> {
+    let fs = FatFs::new(open_driver(&args.volume, args.restore.is_some())?);
+
+    let mut dirs = args.dirs.iter().map(|dir| image_path(dir)).collect::<Vec<_>>();
+    if dirs.is_empty() {
+        dirs.push(PathBuf::from("/"));
+    }
+
+    let DeletedEntries { entries, unreadable } = deleted_entries(&fs, &dirs, args.recursive)?;
+    for directory in &unreadable {
+        eprintln!("{}: {}", directory.path.display(), directory.error);
+    }
+
+    if let Some(location) = args.restore {
+        let entry = find(&entries, location)?;
+        let first_char = match args.first_char {
+            Some(c) if !c.is_ascii() => return Err(format!("Invalid first character {}", c).into()),
+            first_char => first_char.map(|c| c.to_ascii_uppercase() as u8),
+        };
+
+        let restored = match restore(&fs, entry, first_char) {
+            Ok(restored) => restored,
+            Err(error) => {
+                eprintln!("{}: {}", entry.path.display(), error);
+                return Ok(ExitCode::FAILURE);
+            }
+        };
+        fs.driver().sync()?;
+
+        println!("Restored {}", entry.directory.join(restored.name()).display());
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let (Some(location), Some(output)) = (args.extract, &args.output) {
+        let entry = find(&entries, location)?;
+        if entry.recoverability != Recoverability::Recoverable {
+            eprintln!("{}: {}, the contents are likely damaged", entry.path.display(), entry.recoverability);
+        }
+
+        let size = match extract(fs.driver(), entry, File::create(output)?) {
+            Ok(size) => size,
+            Err(error) => {
+                eprintln!("{}: {}", entry.path.display(), error);
+                return Ok(ExitCode::FAILURE);
+            }
+        };
+
+        println!("Extracted {} bytes of {} to {}", size, entry.path.display(), output.display());
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        for entry in &entries {
+            println!("{}", entry);
+        }
+    }
+
+    Ok(if unreadable.is_empty() { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use serde::Serialize;
+
+use crate::{fat_is_eoc, fat_is_free, Driver, Fat32Error, Fat32Result, FAT_EOC, FAT_FREE};
+
+/// A run of consecutive clusters
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Extent {
+    pub start: usize,
+    pub length: usize,
+}
+
+impl Extent {
+    pub fn end(&self) -> usize {
+        self.start + self.length
+    }
+    pub fn clusters(&self) -> Range<usize> {
+        self.start..self.end()
+    }
+}
+
+/// Groups clusters, in chain order, into runs of consecutive clusters
+pub fn extents_of(clusters: &[usize]) -> Vec<Extent> {
+    let mut extents: Vec<Extent> = vec![];
+
+    for cluster in clusters {
+        match extents.last_mut() {
+            Some(last) if last.end() == *cluster => last.length += 1,
+            _=> extents.push(Extent { start: *cluster, length: 1 }),
+        }
+    }
+
+    extents
+}
+
+pub type ReservationId = u64;
+
+/// Free clusters set aside for a file expected to grow, so that it can stay contiguous.
+/// Reservations only live in memory and are dropped when the volume is closed.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Reservation {
+    pub id: ReservationId,
+    pub extent: Extent,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocationStrategy {
+    /// Take the first free clusters after the previous allocation, starting from FSI_Nxt_Free
+    NextFit,
+    /// Take the smallest free run that holds every cluster, or the fewest, largest runs if none does
+    BestFit,
+    /// Like best-fit, but fail with `NoSpace` rather than return more than one extent
+    Contiguous,
+    /// Take clusters from the front of a reservation, continuing with next-fit once it runs out
+    Reserved(ReservationId),
+}
+
+#[derive(Default)]
+pub(crate) struct AllocatorState {
+    /// Loaded from FSInfo on first use
+    loaded: bool,
+    next_free: usize,
+    free_count: Option<u32>,
+    /// What's left of each reservation
+    reservations: HashMap<ReservationId, Extent>,
+    next_reservation: ReservationId,
+    /// The FSInfo hints need to be written back
+    dirty: bool,
+    /// Copy of the first FAT, read on the first allocation and kept in step with every write to the FAT since
+    fat: Option<Vec<u32>>,
+}
+
+impl AllocatorState {
+    /// Updates the cached FAT after `values` were written to it from `start` on
+    pub(crate) fn fat_written(&mut self, start: usize, values: &[u32]) {
+        if let Some(fat) = &mut self.fat {
+            for (entry, value) in fat[start..start + values.len()].iter_mut().zip(values) {
+                *entry = value & 0x0FFFFFFF;
+            }
+        }
+    }
+    fn is_reserved(&self, cluster: usize) -> bool {
+        self.reservations.values().any(|extent| extent.clusters().contains(&cluster))
+    }
+}
+
+/// Maximal runs of free clusters that aren't reserved
+fn free_runs(fat: &[u32], state: &AllocatorState) -> Vec<Extent> {
+    let mut runs = vec![];
+    let mut current: Option<Extent> = None;
+
+    for (cluster, value) in fat.iter().enumerate().skip(2) {
+        if fat_is_free(*value) && !state.is_reserved(cluster) {
+            match &mut current {
+                Some(run) => run.length += 1,
+                None => current = Some(Extent { start: cluster, length: 1 }),
+            }
+        } else if let Some(run) = current.take() {
+            runs.push(run);
+        }
+    }
+
+    runs.extend(current);
+
+    runs
+}
+
+fn next_fit(fat: &[u32], state: &AllocatorState, start: usize, count: usize) -> Vec<usize> {
+    let n_clusters = fat.len() - 2;
+    let start = if start >= 2 && start < fat.len() { start } else { 2 };
+
+    (0..n_clusters)
+    .map(|i| 2 + (start - 2 + i) % n_clusters)
+    .filter(|cluster| fat_is_free(fat[*cluster]) && !state.is_reserved(*cluster))
+    .take(count)
+    .collect()
+}
+
+fn best_fit(fat: &[u32], state: &AllocatorState, count: usize, contiguous: bool) -> Fat32Result<Vec<usize>> {
+    let mut runs = free_runs(fat, state);
+
+    let fitting = runs.iter()
+    .filter(|run| run.length >= count)
+    .min_by_key(|run| run.length);
+
+    if let Some(run) = fitting {
+        return Ok((run.start..run.start + count).collect());
+    }
+
+    if contiguous {
+        return Err(Fat32Error::NoSpace);
+    }
+
+    // Nothing fits, fill the largest runs first to keep the number of fragments down
+    runs.sort_by_key(|run| std::cmp::Reverse(run.length));
+
+    Ok(runs.iter().flat_map(|run| run.clusters()).take(count).collect())
+}
+
+impl Driver {
+    fn load_allocator(&self, state: &mut AllocatorState) -> Fat32Result<()> {
+        if state.loaded {
+            return Ok(());
+        }
+
+        let fs_info = self.fs_info()?;
+        state.next_free = fs_info.next_free().map_or(2, |cluster| cluster as usize);
+        state.free_count = fs_info.free_count();
+        state.loaded = true;
+
+        Ok(())
+    }
+    fn load_fat(&self, state: &mut AllocatorState) -> Fat32Result<()> {
+        if state.fat.is_none() {
+            state.fat = Some(self.read_fat_table(0)?);
+        }
+
+        Ok(())
+    }
+    /// Writes a run of consecutive FAT entries to every copy of the FAT
+    fn write_fat_run(&self, state: &mut AllocatorState, start: usize, values: &[u32]) -> Fat32Result<()> {
+        let bpb = &self.bpb;
+
+        for fat_index in 0..bpb.bpb_num_fats as usize {
+            let byte_offset = (fat_index * bpb.bpb_fat_sz32 as usize) * bpb.bytes_per_sector() + start * 4;
+            let fat_start = bpb.fat_start_sector();
+
+            let mut bytes = vec![0; values.len() * 4];
+            self.read_sector(fat_start, byte_offset, &mut bytes)?;
+
+            for (entry, value) in bytes.chunks_exact_mut(4).zip(values) {
+                let reserved = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) & 0xF0000000;
+                entry.copy_from_slice(&(reserved | (value & 0x0FFFFFFF)).to_le_bytes());
+            }
+
+            self.write_sector(fat_start, byte_offset, &bytes)?;
+        }
+
+        state.fat_written(start, values);
+
+        Ok(())
+    }
+    /// Links `clusters` into a chain in the FAT, one write per extent
+    fn link_chain(&self, state: &mut AllocatorState, after: Option<usize>, clusters: &[usize]) -> Fat32Result<()> {
+        let extents = extents_of(clusters);
+
+        for (ix, extent) in extents.iter().enumerate() {
+            let next = extents.get(ix + 1).map_or(FAT_EOC, |next| next.start as u32);
+
+            let values = (extent.start + 1..extent.end())
+            .map(|cluster| cluster as u32)
+            .chain(std::iter::once(next))
+            .collect::<Vec<_>>();
+
+            self.write_fat_run(state, extent.start, &values)?;
+        }
+
+        if let (Some(after), Some(first)) = (after, clusters.first()) {
+            self.write_fat_run(state, after, &[*first as u32])?;
+        }
+
+        Ok(())
+    }
+    fn allocate_after(&self, after: Option<usize>, count: usize, strategy: AllocationStrategy) -> Fat32Result<Vec<Extent>> {
+        if count == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut state = self.allocator.lock();
+        self.load_allocator(&mut state)?;
+        self.load_fat(&mut state)?;
+
+        let fat = state.fat.as_deref().unwrap();
+
+        let clusters = match strategy {
+            AllocationStrategy::NextFit => {
+                let start = after.map_or(state.next_free, |after| after + 1);
+                next_fit(fat, &state, start, count)
+            },
+            AllocationStrategy::BestFit => best_fit(fat, &state, count, false)?,
+            AllocationStrategy::Contiguous => best_fit(fat, &state, count, true)?,
+            AllocationStrategy::Reserved(id) => {
+                let reserved = state.reservations.get(&id).copied().ok_or(Fat32Error::NotFound)?;
+                let taken = usize::min(count, reserved.length);
+
+                let mut clusters = reserved.clusters().take(taken).collect::<Vec<_>>();
+                clusters.extend(next_fit(fat, &state, reserved.end(), count - taken));
+                clusters
+            },
+        };
+
+        if clusters.len() < count {
+            return Err(Fat32Error::NoSpace);
+        }
+
+        self.link_chain(&mut state, after, &clusters)?;
+
+        if let AllocationStrategy::Reserved(id) = strategy {
+            let reserved = state.reservations.get_mut(&id).unwrap();
+            let taken = usize::min(count, reserved.length);
+            reserved.start += taken;
+            reserved.length -= taken;
+        }
+
+        state.next_free = clusters.last().unwrap() + 1;
+        state.free_count = state.free_count.map(|free_count| free_count.saturating_sub(count as u32));
+        state.dirty = true;
+
+        Ok(extents_of(&clusters))
+    }
+    /// Allocates a new chain of `count` clusters, returned as extents in chain order
+    pub fn allocate(&self, count: usize, strategy: AllocationStrategy) -> Fat32Result<Vec<Extent>> {
+        self.allocate_after(None, count, strategy)
+    }
+    /// Allocates `count` clusters and links them after `last_cluster`, the current end of a chain
+    pub fn extend(&self, last_cluster: usize, count: usize, strategy: AllocationStrategy) -> Fat32Result<Vec<Extent>> {
+        self.allocate_after(Some(last_cluster), count, strategy)
+    }
+    /// Links the given free clusters into a chain, in order, for putting back a chain that was freed
+    pub(crate) fn claim(&self, clusters: &[usize]) -> Fat32Result<()> {
+        let mut state = self.allocator.lock();
+        self.load_allocator(&mut state)?;
+        self.load_fat(&mut state)?;
+
+        let fat = state.fat.as_deref().unwrap();
+        if let Some(taken) = clusters.iter().find(|cluster| !self.is_valid_cluster(**cluster) || !fat_is_free(fat[**cluster]) || state.is_reserved(**cluster)) {
+            return Err(Fat32Error::ClusterInUse(*taken as u32));
+        }
+
+        self.link_chain(&mut state, None, clusters)?;
+
+        state.free_count = state.free_count.map(|free_count| free_count.saturating_sub(clusters.len() as u32));
+        state.dirty = true;
+
+        Ok(())
+    }
+    /// Cuts `clusters`, a chain that was just allocated, down to its first `keep`, freeing the rest. The next-fit hint
+    /// moves back to the first cluster freed, so the next file starts where this one really ended
+    pub(crate) fn truncate_new_chain(&self, clusters: &[usize], keep: usize) -> Fat32Result<()> {
+        if keep > 0 {
+            self.write_fat(clusters[keep - 1], FAT_EOC)?;
+        }
+        self.free_chain(clusters[keep])?;
+
+        let mut state = self.allocator.lock();
+        if state.next_free == clusters.last().unwrap() + 1 {
+            state.next_free = clusters[keep];
+        }
+
+        Ok(())
+    }
+    /// Frees every cluster of the chain starting at `start`, returning how many were freed
+    pub fn free_chain(&self, start: usize) -> Fat32Result<usize> {
+        let clusters = self.chain(start)?;
+
+        let mut state = self.allocator.lock();
+        self.load_allocator(&mut state)?;
+
+        for extent in extents_of(&clusters) {
+            self.write_fat_run(&mut state, extent.start, &vec![FAT_FREE; extent.length])?;
+        }
+
+        state.free_count = state.free_count.map(|free_count| free_count + clusters.len() as u32);
+        state.dirty = true;
+
+        Ok(clusters.len())
+    }
+    /// Sets aside a contiguous run of `count` free clusters, chosen best-fit
+    pub fn reserve(&self, count: usize) -> Fat32Result<Reservation> {
+        if count == 0 {
+            return Err(Fat32Error::InvalidArgument("can't reserve 0 clusters"));
+        }
+
+        let mut state = self.allocator.lock();
+        self.load_fat(&mut state)?;
+
+        let clusters = best_fit(state.fat.as_deref().unwrap(), &state, count, true)?;
+
+        let id = state.next_reservation;
+        state.next_reservation += 1;
+
+        let extent = Extent { start: clusters[0], length: count };
+        state.reservations.insert(id, extent);
+
+        Ok(Reservation { id, extent })
+    }
+    /// Gives back whatever is left of a reservation
+    pub fn release(&self, id: ReservationId) -> Fat32Result<()> {
+        let mut state = self.allocator.lock();
+        state.reservations.remove(&id).ok_or(Fat32Error::NotFound)?;
+
+        Ok(())
+    }
+    /// Clusters of the chain starting at `start`, stopping at the end of the chain or an invalid link
+    pub fn chain(&self, start: usize) -> Fat32Result<Vec<usize>> {
+        let mut clusters = vec![];
+        let mut cluster = start;
+
+        while self.is_valid_cluster(cluster) && clusters.len() < self.bpb.cluster_count() {
+            clusters.push(cluster);
+
+            let value = self.read_fat_entry(cluster)?;
+            if fat_is_eoc(value) {
+                break;
+            }
+
+            cluster = value as usize;
+        }
+
+        Ok(clusters)
+    }
+    /// The chain starting at `start` as extents
+    pub fn extents(&self, start: usize) -> Fat32Result<Vec<Extent>> {
+        Ok(extents_of(&self.chain(start)?))
+    }
+    /// Number of free clusters, from the FSInfo hint if it's plausible, otherwise counted from the FAT
+    pub fn free_clusters(&self) -> Fat32Result<u32> {
+        {
+            let mut state = self.allocator.lock();
+            self.load_allocator(&mut state)?;
+
+            if let Some(free_count) = state.free_count.filter(|free_count| *free_count as usize <= self.bpb.cluster_count()) {
+                return Ok(free_count);
+            }
+        }
+
+        self.recompute_free_space()
+    }
+    /// Counts the free clusters in the FAT and updates the FSInfo hints from it
+    pub fn recompute_free_space(&self) -> Fat32Result<u32> {
+        let mut state = self.allocator.lock();
+        self.load_allocator(&mut state)?;
+
+        // Counted on disk rather than in the cache, which is refreshed along the way
+        let fat = self.read_fat_table(0)?;
+        let mut free = (2..fat.len()).filter(|cluster| fat_is_free(fat[*cluster]));
+
+        let next_free = free.next();
+        let free_count = next_free.map_or(0, |_| free.count() + 1) as u32;
+
+        state.fat = Some(fat);
+
+        state.next_free = next_free.unwrap_or(2);
+        state.free_count = Some(free_count);
+        state.dirty = true;
+
+        Ok(free_count)
+    }
+    /// Writes the allocator's FSInfo hints back if they changed
+    pub(crate) fn flush_fs_info(&self) -> Fat32Result<()> {
+        let mut state = self.allocator.lock();
+        if !state.dirty {
+            return Ok(());
+        }
+
+        let mut fs_info = self.fs_info()?;
+        if fs_info.is_valid() {
+            fs_info.fsi_nxt_free = state.next_free as u32;
+            if let Some(free_count) = state.free_count {
+                fs_info.fsi_free_count = free_count;
+            }
+            fs_info.write_to(self)?;
+        }
+
+        state.dirty = false;
+
+        Ok(())
+    }
+}
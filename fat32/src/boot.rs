@@ -237,6 +237,10 @@ impl BPB {
     pub fn data_sectors(&self) -> usize {
         self.bpb_tot_sec32 as usize - self.data_start_sector()
     }
+    /// Number of clusters in the data region, valid cluster numbers are `2..cluster_count() + 2`
+    pub fn cluster_count(&self) -> usize {
+        self.data_sectors() / self.sectors_per_cluster()
+    }
     #[inline]
     pub fn bytes_per_sector(&self) -> usize {
         self.bpb_bytes_per_sec as usize
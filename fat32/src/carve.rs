@@ -0,0 +1,521 @@
+use std::fmt::Display;
+use std::io::Write;
+
+use serde::Serialize;
+
+use crate::{fat_is_free, Driver, Extent, Fat32Error, Fat32Result};
+
+/// Files larger than this are cut off unless `CarveOptions::max_size` says otherwise
+const DEFAULT_MAX_SIZE: usize = 64 << 20;
+/// Enough to hold every signature
+const HEADER_SIZE: usize = 16;
+/// Box types that can appear at the top level of an MP4 file
+const MP4_BOXES: [&[u8; 4]; 16] = [
+    b"ftyp", b"moov", b"mdat", b"free", b"skip", b"wide", b"uuid", b"meta",
+    b"moof", b"mfra", b"pdin", b"styp", b"sidx", b"udta", b"prft", b"emsg",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileKind {
+    Jpeg,
+    Png,
+    Pdf,
+    Zip,
+    Mp4,
+}
+
+impl FileKind {
+    pub const ALL: [FileKind; 5] = [FileKind::Jpeg, FileKind::Png, FileKind::Pdf, FileKind::Zip, FileKind::Mp4];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FileKind::Jpeg => "jpg",
+            FileKind::Png => "png",
+            FileKind::Pdf => "pdf",
+            FileKind::Zip => "zip",
+            FileKind::Mp4 => "mp4",
+        }
+    }
+    /// Recognizes the start of a file from its signature
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(FileKind::Jpeg)
+        } else if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(FileKind::Png)
+        } else if header.starts_with(b"%PDF-") {
+            Some(FileKind::Pdf)
+        } else if header.starts_with(b"PK\x03\x04") {
+            Some(FileKind::Zip)
+        } else if header.get(4..8) == Some(b"ftyp") {
+            Some(FileKind::Mp4)
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for FileKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CarveOptions {
+    /// Only look for these kinds of files, every kind if empty
+    pub kinds: Vec<FileKind>,
+    /// Largest file to reassemble in bytes, 64 MiB if None
+    pub max_size: Option<usize>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CarvedFile {
+    pub kind: FileKind,
+    /// The free clusters the file was put together from
+    pub clusters: Extent,
+    pub size: usize,
+    /// Whether the end of the file was found, otherwise it ran into allocated clusters or the size limit
+    pub complete: bool,
+}
+
+impl Display for CarvedFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:<4} {:>10} bytes in clusters {}-{}{}",
+            self.kind, self.size, self.clusters.start, self.clusters.end() - 1, if self.complete { "" } else { " (truncated)" })
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CarveReport {
+    pub free_clusters: usize,
+    pub files: Vec<CarvedFile>,
+}
+
+impl Display for CarveReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for file in &self.files {
+            writeln!(f, "{}", file)?;
+        }
+
+        write!(f, "{} file(s) found in {} free cluster(s)", self.files.len(), self.free_clusters)
+    }
+}
+
+/// Where a file being reassembled stands
+#[derive(Debug, PartialEq, Eq)]
+enum End {
+    /// The file ends after this many bytes
+    At(usize),
+    /// The end isn't in the data read so far
+    NeedMore,
+    /// The data doesn't have the structure of the format, the signature was a coincidence
+    Invalid,
+}
+
+fn u16_be(data: &[u8], offset: usize) -> usize {
+    u16::from_be_bytes([data[offset], data[offset + 1]]) as usize
+}
+fn u32_be(data: &[u8], offset: usize) -> usize {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize
+}
+
+fn find(data: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    data.get(from..)?.windows(needle.len()).position(|window| window == needle).map(|ix| from + ix)
+}
+
+/// Follows the marker segments, skipping thumbnails in APPn segments, and the entropy coded data after SOS up to EOI
+fn jpeg_end(data: &[u8]) -> End {
+    let mut pos = 2;
+
+    loop {
+        if pos + 2 > data.len() {
+            return End::NeedMore;
+        }
+        if data[pos] != 0xFF {
+            return End::Invalid;
+        }
+
+        match data[pos + 1] {
+            0xFF => pos += 1,
+            0xD9 => return End::At(pos + 2),
+            0x01 | 0xD0..=0xD7 => pos += 2,
+            marker => {
+                if pos + 4 > data.len() {
+                    return End::NeedMore;
+                }
+
+                let length = u16_be(data, pos + 2);
+                if length < 2 {
+                    return End::Invalid;
+                }
+                pos += 2 + length;
+
+                if marker == 0xDA {
+                    // Within the scan 0xFF is either stuffed with 0x00 or starts a restart marker
+                    let next_marker = (pos..data.len().saturating_sub(1))
+                    .find(|ix| data[*ix] == 0xFF && !matches!(data[*ix + 1], 0x00 | 0xD0..=0xD7 | 0xFF));
+
+                    match next_marker {
+                        Some(ix) => pos = ix,
+                        None => return End::NeedMore,
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Follows the chunks up to IEND
+fn png_end(data: &[u8]) -> End {
+    let mut pos = 8;
+
+    loop {
+        if pos + 8 > data.len() {
+            return End::NeedMore;
+        }
+
+        let length = u32_be(data, pos);
+        let kind = &data[pos + 4..pos + 8];
+        if !kind.iter().all(u8::is_ascii_alphabetic) {
+            return End::Invalid;
+        }
+
+        pos += 12 + length;
+
+        if kind == b"IEND" {
+            return if pos <= data.len() { End::At(pos) } else { End::NeedMore };
+        }
+    }
+}
+
+/// Up to the first end of file marker, so incremental updates after it are lost
+fn pdf_end(data: &[u8]) -> End {
+    let Some(ix) = find(data, b"%%EOF", 5) else {
+        return End::NeedMore;
+    };
+
+    let mut end = ix + 5;
+    while end < data.len() && end < ix + 7 && matches!(data[end], b'\r' | b'\n') {
+        end += 1;
+    }
+
+    End::At(end)
+}
+
+/// Up to the end of the end of central directory record, including its comment
+fn zip_end(data: &[u8]) -> End {
+    let Some(ix) = find(data, b"PK\x05\x06", 4) else {
+        return End::NeedMore;
+    };
+    if ix + 22 > data.len() {
+        return End::NeedMore;
+    }
+
+    let comment_length = u16::from_le_bytes([data[ix + 20], data[ix + 21]]) as usize;
+    let end = ix + 22 + comment_length;
+
+    if end <= data.len() { End::At(end) } else { End::NeedMore }
+}
+
+/// MP4 files have no end marker, the file ends where the boxes stop making sense
+fn mp4_end(data: &[u8], exhausted: bool) -> End {
+    let mut pos = 0;
+
+    loop {
+        if pos == data.len() && exhausted {
+            return End::At(pos);
+        }
+        if pos + 16 > data.len() {
+            return End::NeedMore;
+        }
+
+        if !MP4_BOXES.iter().any(|kind| &data[pos + 4..pos + 8] == *kind) {
+            return if pos == 0 { End::Invalid } else { End::At(pos) };
+        }
+
+        let size = match u32_be(data, pos) {
+            // A 64 bit size follows the type
+            1 => u64::from_be_bytes(data[pos + 8..pos + 16].try_into().unwrap()) as usize,
+            // The box extends to the end of the file, which is unknown here
+            0 => return End::NeedMore,
+            size => size,
+        };
+        if size < 8 {
+            return End::At(pos);
+        }
+
+        pos = pos.saturating_add(size);
+        if pos > data.len() {
+            return End::NeedMore;
+        }
+    }
+}
+
+fn find_end(kind: FileKind, data: &[u8], exhausted: bool) -> End {
+    match kind {
+        FileKind::Jpeg => jpeg_end(data),
+        FileKind::Png => png_end(data),
+        FileKind::Pdf => pdf_end(data),
+        FileKind::Zip => zip_end(data),
+        FileKind::Mp4 => mp4_end(data, exhausted),
+    }
+}
+
+/// Reassembles the file starting at `start` from the free clusters following it. Returns None if it turns out not to
+/// be a file of that kind after all
+fn carve_from(driver: &Driver, fat: &[u32], start: usize, kind: FileKind, max_size: usize) -> Fat32Result<Option<CarvedFile>> {
+    let bytes_per_cluster = driver.bytes_per_cluster();
+    let end_cluster = driver.cluster_count() + 2;
+
+    let mut data = vec![];
+    let mut cluster = start;
+    // Parsing from the start after every cluster would be quadratic, so the data read doubles between attempts
+    let mut check_at = bytes_per_cluster;
+
+    let (size, complete) = loop {
+        let exhausted = cluster >= end_cluster || !fat_is_free(fat[cluster]) || data.len() >= max_size;
+
+        if !exhausted {
+            let offset = data.len();
+            data.resize(offset + bytes_per_cluster, 0);
+            driver.read_cluster(cluster, 0, &mut data[offset..])?;
+            cluster += 1;
+
+            if data.len() < check_at {
+                continue;
+            }
+            check_at *= 2;
+        }
+
+        match find_end(kind, &data, exhausted) {
+            // The end can be up to a cluster past the limit
+            End::At(size) if size > max_size => break (max_size, false),
+            End::At(size) => break (size, true),
+            End::Invalid => return Ok(None),
+            End::NeedMore if exhausted => break (usize::min(data.len(), max_size), false),
+            End::NeedMore => {},
+        }
+    };
+
+    Ok(Some(CarvedFile {
+        kind,
+        clusters: Extent { start, length: size.div_ceil(bytes_per_cluster).max(1) },
+        size,
+        complete,
+    }))
+}
+
+/// Looks for files at the start of every free cluster and reassembles them, assuming they were allocated contiguously
+pub fn carve(driver: &Driver, options: &CarveOptions) -> Fat32Result<CarveReport> {
+    let fat = driver.read_fat_table(0)?;
+    let max_size = options.max_size.unwrap_or(DEFAULT_MAX_SIZE);
+    let end_cluster = driver.cluster_count() + 2;
+
+    let mut report = CarveReport::default();
+    let mut header = vec![0; usize::min(HEADER_SIZE, driver.bytes_per_cluster())];
+    let mut cluster = 2;
+
+    while cluster < end_cluster {
+        if !fat_is_free(fat[cluster]) {
+            cluster += 1;
+            continue;
+        }
+
+        driver.read_cluster(cluster, 0, &mut header)?;
+
+        let kind = FileKind::detect(&header)
+        .filter(|kind| options.kinds.is_empty() || options.kinds.contains(kind));
+
+        let carved = match kind {
+            Some(kind) => carve_from(driver, &fat, cluster, kind, max_size)?,
+            None => None,
+        };
+
+        match carved {
+            Some(file) => {
+                report.free_clusters += file.clusters.length;
+                cluster = file.clusters.end();
+                report.files.push(file);
+            },
+            None => {
+                report.free_clusters += 1;
+                cluster += 1;
+            },
+        }
+    }
+
+    Ok(report)
+}
+
+/// Writes the contents of a carved file to `writer`
+pub fn write_carved<W: Write>(driver: &Driver, file: &CarvedFile, mut writer: W) -> Fat32Result<()> {
+    let mut buffer = vec![0; driver.bytes_per_cluster()];
+    let mut remaining = file.size;
+
+    for cluster in file.clusters.clusters() {
+        let length = usize::min(remaining, buffer.len());
+        driver.read_cluster(cluster, 0, &mut buffer[..length])?;
+        writer.write_all(&buffer[..length]).map_err(Fat32Error::IOError)?;
+
+        remaining -= length;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jpeg() -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8];
+        // APP0 with a fake EOI inside, which has to be skipped
+        data.extend_from_slice(&[0xFF, 0xE0, 0x00, 0x06, 0xFF, 0xD9, 0x00, 0x00]);
+        data.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x04, 0x01, 0x02]);
+        // Scan data with a stuffed 0xFF and a restart marker
+        data.extend_from_slice(&[0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD0, 0x56]);
+        data.extend_from_slice(&[0xFF, 0xD9]);
+
+        data
+    }
+
+    fn png() -> Vec<u8> {
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend_from_slice(&[0, 0, 0, 13]);
+        data.extend_from_slice(b"IHDR");
+        data.extend_from_slice(&[0; 13 + 4]);
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(b"IEND");
+        data.extend_from_slice(&[0xAE, 0x42, 0x60, 0x82]);
+
+        data
+    }
+
+    fn mp4_box(size: u32, kind: &[u8; 4], body: usize) -> Vec<u8> {
+        let mut data = size.to_be_bytes().to_vec();
+        data.extend_from_slice(kind);
+        data.resize(8 + body, 0);
+
+        data
+    }
+
+    #[test]
+    fn detects_signatures() {
+        assert_eq!(FileKind::detect(&jpeg()), Some(FileKind::Jpeg));
+        assert_eq!(FileKind::detect(&png()), Some(FileKind::Png));
+        assert_eq!(FileKind::detect(b"%PDF-1.7"), Some(FileKind::Pdf));
+        assert_eq!(FileKind::detect(b"PK\x03\x04"), Some(FileKind::Zip));
+        assert_eq!(FileKind::detect(&mp4_box(16, b"ftyp", 8)), Some(FileKind::Mp4));
+
+        assert_eq!(FileKind::detect(&[0xFF, 0xD8, 0x00]), None);
+        assert_eq!(FileKind::detect(b"\x89PNG\r\n\x1a\r"), None);
+        assert_eq!(FileKind::detect(b"PK\x05\x06"), None);
+        assert_eq!(FileKind::detect(b"ftyp"), None);
+        assert_eq!(FileKind::detect(&[]), None);
+    }
+
+    #[test]
+    fn finds_the_end_of_jpegs() {
+        let data = jpeg();
+        assert_eq!(jpeg_end(&data), End::At(data.len()));
+
+        let mut padded = data.clone();
+        padded.extend_from_slice(&[0; 100]);
+        assert_eq!(jpeg_end(&padded), End::At(data.len()));
+
+        for length in [2, 5, 10, data.len() - 1] {
+            assert_eq!(jpeg_end(&data[..length]), End::NeedMore, "cut at {}", length);
+        }
+
+        // Not a marker where one should be
+        assert_eq!(jpeg_end(&[0xFF, 0xD8, 0x00, 0xE0, 0x00, 0x10]), End::Invalid);
+        // A segment length too short to cover itself
+        assert_eq!(jpeg_end(&[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x01]), End::Invalid);
+    }
+
+    #[test]
+    fn finds_the_end_of_pngs() {
+        let data = png();
+        assert_eq!(png_end(&data), End::At(data.len()));
+
+        let mut padded = data.clone();
+        padded.extend_from_slice(&[0xFF; 100]);
+        assert_eq!(png_end(&padded), End::At(data.len()));
+
+        for length in [8, 20, data.len() - 1] {
+            assert_eq!(png_end(&data[..length]), End::NeedMore, "cut at {}", length);
+        }
+
+        let mut invalid = data.clone();
+        invalid[12..16].copy_from_slice(b"IH\0R");
+        assert_eq!(png_end(&invalid), End::Invalid);
+    }
+
+    #[test]
+    fn finds_the_end_of_pdfs() {
+        let data = b"%PDF-1.4\n1 0 obj\nendobj\n%%EOF\r\n".to_vec();
+        assert_eq!(pdf_end(&data), End::At(data.len()));
+
+        // No more than a CR LF is kept after the marker
+        let mut padded = data.clone();
+        padded.extend_from_slice(b"\n\0\0");
+        assert_eq!(pdf_end(&padded), End::At(data.len()));
+
+        assert_eq!(pdf_end(&data[..data.len() - 4]), End::NeedMore);
+        // The signature itself can't be mistaken for the end
+        assert_eq!(pdf_end(b"%PDF-"), End::NeedMore);
+    }
+
+    #[test]
+    fn finds_the_end_of_zips() {
+        let mut data = b"PK\x03\x04".to_vec();
+        data.extend_from_slice(&[0; 30]);
+        data.extend_from_slice(b"PK\x05\x06");
+        data.extend_from_slice(&[0; 16]);
+        data.extend_from_slice(&3u16.to_le_bytes());
+        data.extend_from_slice(b"abc");
+        assert_eq!(zip_end(&data), End::At(data.len()));
+
+        let mut padded = data.clone();
+        padded.extend_from_slice(&[0; 100]);
+        assert_eq!(zip_end(&padded), End::At(data.len()));
+
+        // Cut in the record and in the comment
+        assert_eq!(zip_end(&data[..40]), End::NeedMore);
+        assert_eq!(zip_end(&data[..data.len() - 1]), End::NeedMore);
+        assert_eq!(zip_end(&data[..30]), End::NeedMore);
+    }
+
+    #[test]
+    fn finds_the_end_of_mp4s() {
+        let mut data = mp4_box(16, b"ftyp", 8);
+        data.extend(mp4_box(24, b"moov", 16));
+        // A 64 bit size of 32 after the type
+        let mut large = mp4_box(1, b"mdat", 24);
+        large[8..16].copy_from_slice(&32u64.to_be_bytes());
+        data.extend(large);
+        let end = data.len();
+
+        // Whatever follows isn't a box
+        data.extend_from_slice(&[0xAB; 32]);
+        assert_eq!(mp4_end(&data, false), End::At(end));
+        assert_eq!(mp4_end(&data[..end], true), End::At(end));
+        assert_eq!(mp4_end(&data[..end], false), End::NeedMore);
+        assert_eq!(mp4_end(&data[..end - 1], true), End::NeedMore);
+
+        // A box extending to the end of the file
+        let mut to_end = mp4_box(16, b"ftyp", 8);
+        to_end.extend(mp4_box(0, b"mdat", 64));
+        assert_eq!(mp4_end(&to_end, false), End::NeedMore);
+        assert_eq!(mp4_end(&to_end, true), End::NeedMore);
+
+        // A size too small to hold the box header ends the file before it
+        let mut too_small = mp4_box(16, b"ftyp", 8);
+        too_small.extend(mp4_box(4, b"free", 8));
+        assert_eq!(mp4_end(&too_small, false), End::At(16));
+
+        assert_eq!(mp4_end(&mp4_box(16, b"abcd", 8), false), End::Invalid);
+        assert_eq!(mp4_end(&mp4_box(16, b"ftyp", 0), false), End::NeedMore);
+    }
+}
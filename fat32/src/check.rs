@@ -0,0 +1,462 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::{fat_is_bad, fat_is_eoc, fat_is_free, Driver, EntryLocation, Fat32Result, FatDirectory};
+
+const NO_OWNER: u32 = u32::MAX;
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CheckIssue {
+    /// Allocated clusters that aren't reachable from any directory entry
+    LostChain { start: u32, length: usize },
+    /// A cluster that belongs to the chains of two different entries
+    CrossLinked { cluster: u32, path: PathBuf, other: PathBuf },
+    /// The cluster chain of a file doesn't match its DIR_FileSize
+    SizeMismatch { path: PathBuf, file_size: usize, clusters: usize, expected_clusters: usize },
+    /// A chain points to a free, reserved, bad or out of range cluster.
+    /// `cluster` is 0 when the directory entry itself holds the invalid value
+    InvalidCluster { path: PathBuf, cluster: u32, value: u32 },
+    /// A chain loops back onto itself, `cluster` is the last cluster before it does
+    ChainLoop { path: PathBuf, cluster: u32 },
+    /// The `.` entry of a directory doesn't point to the directory itself
+    BadDotEntry { path: PathBuf, expected: u32, found: Option<u32> },
+    /// The `..` entry of a directory doesn't point to its parent
+    BadDotDotEntry { path: PathBuf, expected: u32, found: Option<u32> },
+    /// Long name entries whose checksum doesn't match the short name that follows them
+    LfnChecksumMismatch { path: PathBuf },
+    /// Long name entries that can't be read, so the short name is used
+    DamagedLongName { path: PathBuf },
+    /// Two entries of the same directory whose names only differ in case
+    DuplicateName { path: PathBuf },
+    /// A directory whose entries couldn't be read
+    UnreadableDirectory { path: PathBuf, error: String },
+    /// A backup copy of the FAT differs from the primary one
+    FatMismatch { fat: usize, mismatched_entries: usize, first_cluster: u32 },
+    /// FSI_Free_Count doesn't match the number of free clusters in the FAT
+    FsInfoMismatch { free_count: u32, free_clusters: usize },
+}
+
+impl Display for CheckIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CheckIssue::LostChain { start, length } => {
+                write!(f, "Lost chain of {} cluster(s) starting at cluster {}", length, start)
+            },
+            CheckIssue::CrossLinked { cluster, path, other } => {
+                write!(f, "{} and {} are cross-linked on cluster {}", path.display(), other.display(), cluster)
+            },
+            CheckIssue::SizeMismatch { path, file_size, clusters, expected_clusters } => {
+                let relation = if clusters < expected_clusters { "shorter" } else { "longer" };
+                write!(f, "{}: cluster chain is {} than the file size ({} cluster(s) for {} bytes, expected {})",
+                    path.display(), relation, clusters, file_size, expected_clusters)
+            },
+            CheckIssue::InvalidCluster { path, cluster, value } => {
+                write!(f, "{}: cluster {} has invalid FAT entry {:#010x}", path.display(), cluster, value)
+            },
+            CheckIssue::ChainLoop { path, cluster } => {
+                write!(f, "{}: cluster chain loops back after cluster {}", path.display(), cluster)
+            },
+            CheckIssue::BadDotEntry { path, expected, found } => {
+                match found {
+                    Some(found) => write!(f, "{}: '.' points to cluster {} instead of {}", path.display(), found, expected),
+                    None => write!(f, "{}: missing '.' entry", path.display())
+                }
+            },
+            CheckIssue::BadDotDotEntry { path, expected, found } => {
+                match found {
+                    Some(found) => write!(f, "{}: '..' points to cluster {} instead of {}", path.display(), found, expected),
+                    None => write!(f, "{}: missing '..' entry", path.display())
+                }
+            },
+            CheckIssue::LfnChecksumMismatch { path } => {
+                write!(f, "{}: long name checksum doesn't match the short name", path.display())
+            },
+            CheckIssue::DamagedLongName { path } => {
+                write!(f, "{}: damaged long name entries, the short name is used", path.display())
+            },
+            CheckIssue::DuplicateName { path } => {
+                write!(f, "{}: duplicate name", path.display())
+            },
+            CheckIssue::UnreadableDirectory { path, error } => {
+                write!(f, "{}: couldn't read directory: {}", path.display(), error)
+            },
+            CheckIssue::FatMismatch { fat, mismatched_entries, first_cluster } => {
+                write!(f, "FAT copy {} differs from the primary FAT in {} entries, starting at cluster {}", fat, mismatched_entries, first_cluster)
+            },
+            CheckIssue::FsInfoMismatch { free_count, free_clusters } => {
+                write!(f, "FSInfo claims {} free cluster(s), the FAT has {}", free_count, free_clusters)
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CheckStats {
+    pub files: usize,
+    pub directories: usize,
+    pub total_clusters: usize,
+    pub used_clusters: usize,
+    pub free_clusters: usize,
+    pub bad_clusters: usize,
+    pub lost_clusters: usize,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct CheckReport {
+    pub issues: Vec<CheckIssue>,
+    pub stats: CheckStats,
+    /// Where the entry of every checked path lives, used for repairs
+    #[serde(skip)]
+    pub(crate) locations: HashMap<PathBuf, EntryLocation>,
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl Display for CheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for issue in &self.issues {
+            writeln!(f, "{}", issue)?;
+        }
+
+        let stats = &self.stats;
+        writeln!(f, "{} files, {} directories", stats.files, stats.directories)?;
+        writeln!(f, "{}/{} clusters used, {} free, {} bad, {} lost",
+            stats.used_clusters, stats.total_clusters, stats.free_clusters, stats.bad_clusters, stats.lost_clusters)?;
+
+        if self.is_clean() {
+            write!(f, "No problems found")
+        } else {
+            write!(f, "{} problem(s) found", self.issues.len())
+        }
+    }
+}
+
+/// A directory entry found while walking the tree, along with where it was found
+struct CheckedEntry {
+    path: PathBuf,
+    directory: FatDirectory,
+}
+
+struct Checker<'d> {
+    driver: &'d Driver,
+    fat: Vec<u32>,
+    /// Index into `paths` of the entry owning each cluster
+    owners: Vec<u32>,
+    paths: Vec<PathBuf>,
+    report: CheckReport,
+}
+
+impl<'d> Checker<'d> {
+    fn new(driver: &'d Driver) -> Fat32Result<Self> {
+        let fat = driver.read_fat_table(0)?;
+        let owners = vec![NO_OWNER; fat.len()];
+
+        Ok(Self {
+            driver,
+            fat,
+            owners,
+            paths: vec![],
+            report: CheckReport::default(),
+        })
+    }
+    fn issue(&mut self, issue: CheckIssue) {
+        log::debug!("{}", issue);
+        self.report.issues.push(issue);
+    }
+    /// Claims the cluster chain starting at `start` for the entry at `path`
+    /// Returns the number of clusters in the chain and whether the chain was claimed for the first time
+    fn claim_chain(&mut self, path: &Path, start: usize) -> (usize, bool) {
+        let owner = self.paths.len() as u32;
+        self.paths.push(path.to_owned());
+
+        let mut cluster = start;
+        let mut previous = start;
+        let mut n_clusters = 0;
+
+        if !self.driver.is_valid_cluster(cluster) {
+            self.issue(CheckIssue::InvalidCluster { path: path.to_owned(), cluster: 0, value: cluster as u32 });
+            return (0, false);
+        }
+
+        loop {
+            let current_owner = self.owners[cluster];
+
+            if current_owner == owner {
+                self.issue(CheckIssue::ChainLoop { path: path.to_owned(), cluster: previous as u32 });
+                break;
+            } else if current_owner != NO_OWNER {
+                let other = self.paths[current_owner as usize].clone();
+                self.issue(CheckIssue::CrossLinked { cluster: cluster as u32, path: path.to_owned(), other });
+
+                let claimed = n_clusters != 0;
+                return (n_clusters + self.shared_length(cluster), claimed);
+            }
+
+            self.owners[cluster] = owner;
+            n_clusters += 1;
+
+            let value = self.fat[cluster];
+
+            if fat_is_eoc(value) {
+                break;
+            }
+
+            if !self.driver.is_valid_cluster(value as usize) || fat_is_bad(value) {
+                self.issue(CheckIssue::InvalidCluster { path: path.to_owned(), cluster: cluster as u32, value });
+                break;
+            }
+
+            previous = cluster;
+            cluster = value as usize;
+        }
+
+        (n_clusters, true)
+    }
+    /// Length of the rest of a chain that is already owned by another entry
+    fn shared_length(&self, start: usize) -> usize {
+        let mut cluster = start;
+        let mut length = 1;
+
+        while !fat_is_eoc(self.fat[cluster]) && length < self.fat.len() {
+            let next = self.fat[cluster] as usize;
+            if !self.driver.is_valid_cluster(next) {
+                break;
+            }
+
+            cluster = next;
+            length += 1;
+        }
+
+        length
+    }
+    fn check_file(&mut self, path: &Path, file: &FatDirectory) {
+        let file_size = file.file_size();
+        let bytes_per_cluster = self.driver.bytes_per_cluster();
+        let expected_clusters = file_size.div_ceil(bytes_per_cluster);
+
+        let clusters = if file.cluster_num() == 0 {
+            0
+        } else {
+            self.claim_chain(path, file.cluster_num()).0
+        };
+
+        if clusters != expected_clusters {
+            self.issue(CheckIssue::SizeMismatch { path: path.to_owned(), file_size, clusters, expected_clusters });
+        }
+    }
+    /// Reads all entries of `directory`, reporting the directory as unreadable on failure
+    fn read_entries(&mut self, path: &Path, directory: &FatDirectory) -> Vec<FatDirectory> {
+        let mut entries = vec![];
+        let mut files = self.driver.files(directory);
+
+        loop {
+            match files.next() {
+                Ok(Some(entry)) => entries.push(entry),
+                Ok(None) => break,
+                Err(err) => {
+                    self.issue(CheckIssue::UnreadableDirectory { path: path.to_owned(), error: err.to_string() });
+                    break;
+                }
+            }
+        }
+
+        entries
+    }
+    fn check_dot_entries(&mut self, path: &Path, directory: &FatDirectory, parent_cluster: usize, entries: &[FatDirectory]) {
+        let expected = directory.cluster_num() as u32;
+        let found = entries.first().filter(|entry| entry.is_current_dir()).map(|entry| entry.cluster_num() as u32);
+
+        if found != Some(expected) {
+            self.issue(CheckIssue::BadDotEntry { path: path.to_owned(), expected, found });
+        }
+
+        // `..` refers to the root directory with cluster 0, some implementations store the root cluster instead
+        let root_cluster = self.driver.bpb.bpb_root_clus as usize;
+        let expected = if parent_cluster == root_cluster { 0 } else { parent_cluster as u32 };
+        let found = entries.get(1).filter(|entry| entry.is_parent_dir()).map(|entry| entry.cluster_num() as u32);
+
+        let valid = match found {
+            Some(found) => found == expected || (expected == 0 && found as usize == root_cluster),
+            None => false,
+        };
+
+        if !valid {
+            self.issue(CheckIssue::BadDotDotEntry { path: path.to_owned(), expected, found });
+        }
+    }
+    fn walk(&mut self) {
+        let root = FatDirectory::root(self.driver);
+        let root_path = PathBuf::from("/");
+
+        self.claim_chain(&root_path, root.cluster_num());
+        self.report.stats.directories += 1;
+
+        let mut queue = VecDeque::new();
+        queue.push_back((CheckedEntry { path: root_path, directory: root }, 0));
+
+        while let Some((current, parent_cluster)) = queue.pop_front() {
+            let entries = self.read_entries(&current.path, &current.directory);
+
+            if current.path != Path::new("/") {
+                self.check_dot_entries(&current.path, &current.directory, parent_cluster, &entries);
+            }
+
+            let mut names = HashSet::new();
+
+            for entry in entries {
+                let special_dir = entry.is_current_dir() || entry.is_parent_dir();
+                if special_dir || entry.is_deleted() || entry.is_volume_id() {
+                    continue;
+                }
+
+                let path = current.path.join(entry.name());
+
+                if let Some(location) = entry.location() {
+                    self.report.locations.insert(path.clone(), location);
+                }
+
+                if entry.has_lfn_mismatch() {
+                    self.issue(CheckIssue::LfnChecksumMismatch { path: path.clone() });
+                }
+                if entry.has_damaged_lfn() {
+                    self.issue(CheckIssue::DamagedLongName { path: path.clone() });
+                }
+
+                // Names are case insensitive on FAT
+                if !names.insert(entry.name().to_string_lossy().to_lowercase()) {
+                    self.issue(CheckIssue::DuplicateName { path: path.clone() });
+                }
+
+                if entry.is_dir() {
+                    self.report.stats.directories += 1;
+
+                    if entry.cluster_num() == 0 {
+                        self.issue(CheckIssue::InvalidCluster { path, cluster: 0, value: 0 });
+                        continue;
+                    }
+
+                    // Only descend into directories whose chain wasn't already claimed to avoid cycles
+                    let (_, claimed) = self.claim_chain(&path, entry.cluster_num());
+                    if claimed {
+                        let parent_cluster = current.directory.cluster_num();
+                        queue.push_back((CheckedEntry { path, directory: entry }, parent_cluster));
+                    }
+                } else {
+                    self.report.stats.files += 1;
+                    self.check_file(&path, &entry);
+                }
+            }
+        }
+    }
+    fn find_lost_chains(&mut self) {
+        let n_clusters = self.fat.len();
+        let is_lost = |checker: &Self, cluster: usize| {
+            let value = checker.fat[cluster];
+            checker.owners[cluster] == NO_OWNER && !fat_is_free(value) && !fat_is_bad(value)
+        };
+
+        // Chains start at lost clusters which no other lost cluster points to
+        let mut pointed_to = vec![false; n_clusters];
+        for cluster in 2..n_clusters {
+            let next = self.fat[cluster] as usize;
+            if is_lost(self, cluster) && next < n_clusters {
+                pointed_to[next] = true;
+            }
+        }
+
+        let mut visited = vec![false; n_clusters];
+        let mut lost_chains = vec![];
+
+        // Heads first, then whatever is left over must be part of a cycle
+        let heads = (2..n_clusters).filter(|cluster| !pointed_to[*cluster]);
+        let rest = 2..n_clusters;
+
+        for start in heads.chain(rest) {
+            if visited[start] || !is_lost(self, start) {
+                continue;
+            }
+
+            let mut cluster = start;
+            let mut length = 0;
+
+            while cluster < n_clusters && !visited[cluster] && is_lost(self, cluster) {
+                visited[cluster] = true;
+                length += 1;
+                cluster = self.fat[cluster] as usize;
+            }
+
+            lost_chains.push((start as u32, length));
+        }
+
+        for (start, length) in lost_chains {
+            self.report.stats.lost_clusters += length;
+            self.issue(CheckIssue::LostChain { start, length });
+        }
+    }
+    fn compare_fat_copies(&mut self) -> Fat32Result<()> {
+        for fat in 1..self.driver.bpb.bpb_num_fats as usize {
+            let copy = self.driver.read_fat_table(fat)?;
+
+            let mut mismatches = self.fat.iter().zip(copy.iter())
+            .enumerate()
+            .skip(2)
+            .filter(|(_, (primary, copy))| primary != copy)
+            .map(|(cluster, _)| cluster as u32);
+
+            if let Some(first_cluster) = mismatches.next() {
+                let mismatched_entries = mismatches.count() + 1;
+                self.issue(CheckIssue::FatMismatch { fat, mismatched_entries, first_cluster });
+            }
+        }
+
+        Ok(())
+    }
+    fn check_fs_info(&mut self) -> Fat32Result<()> {
+        let fs_info = self.driver.fs_info()?;
+        let free_clusters = self.report.stats.free_clusters;
+
+        if let Some(free_count) = fs_info.free_count() {
+            if free_count as usize != free_clusters {
+                self.issue(CheckIssue::FsInfoMismatch { free_count, free_clusters });
+            }
+        }
+
+        Ok(())
+    }
+    fn collect_stats(&mut self) {
+        let stats = &mut self.report.stats;
+        stats.total_clusters = self.fat.len() - 2;
+
+        for value in &self.fat[2..] {
+            if fat_is_free(*value) {
+                stats.free_clusters += 1;
+            } else if fat_is_bad(*value) {
+                stats.bad_clusters += 1;
+            } else {
+                stats.used_clusters += 1;
+            }
+        }
+    }
+}
+
+/// Checks the consistency of the volume, walking every directory and the whole FAT.
+/// The volume is only read, never modified.
+pub fn check(driver: &Driver) -> Fat32Result<CheckReport> {
+    let mut checker = Checker::new(driver)?;
+
+    checker.walk();
+    checker.find_lost_chains();
+    checker.compare_fat_copies()?;
+    checker.collect_stats();
+    checker.check_fs_info()?;
+
+    Ok(checker.report)
+}
@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+
+use crate::{EntryLocation, FatDirectory};
+
+/// Entries kept before the cache is emptied and starts over
+const DEFAULT_CAPACITY: usize = 16384;
+
+/// Caches the results of looking up a name in a directory, keyed by the first cluster of the directory.
+/// Names that weren't found are cached too, as None.
+pub(crate) struct DentryCache {
+    entries: HashMap<(usize, OsString), Option<FatDirectory>>,
+    capacity: usize,
+    /// Bumped on every invalidation, so lookups that raced with one don't get cached
+    generation: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl DentryCache {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity: DEFAULT_CAPACITY,
+            generation: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+    /// Returns None on a miss, Some(None) if the name is known not to exist
+    pub fn get(&mut self, directory_cluster: usize, name: &OsStr) -> Option<Option<FatDirectory>> {
+        let found = self.entries.get(&(directory_cluster, name.to_owned())).cloned();
+
+        if found.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+
+        found
+    }
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+    /// Caches the result of a lookup that started at `generation`, unless something was invalidated since
+    pub fn insert(&mut self, generation: u64, directory_cluster: usize, name: &OsStr, directory: Option<FatDirectory>) {
+        if generation != self.generation {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity {
+            log::debug!("Dentry cache full after {} hits, {} misses, emptying it", self.hits, self.misses);
+            self.entries.clear();
+        }
+
+        self.entries.insert((directory_cluster, name.to_owned()), directory);
+    }
+    /// Drops everything cached for the directory, including names that weren't found
+    pub fn invalidate_dir(&mut self, directory_cluster: usize) {
+        self.generation += 1;
+        self.entries.retain(|(cluster, _), _| *cluster != directory_cluster);
+    }
+    /// Drops the entries read from `location`
+    pub fn invalidate_entry(&mut self, location: EntryLocation) {
+        self.generation += 1;
+        self.entries.retain(|_, directory| {
+            directory.as_ref().and_then(|directory| directory.location()) != Some(location)
+        });
+    }
+    pub fn clear(&mut self) {
+        self.generation += 1;
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,441 @@
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::alloc::extents_of;
+use crate::check::CheckIssue;
+use crate::{fat_is_bad, fat_is_free, Driver, EntryLocation, Extent, Fat32Error, Fat32Result, FatDirectory, FAT32_DIR_SIZE, FAT_FREE};
+
+const NO_OWNER: (u32, u32) = (u32::MAX, u32::MAX);
+const ROOT_CHAIN: usize = 0;
+
+#[derive(Clone, Debug, Default)]
+pub struct DefragOptions {
+    /// Move all data to the beginning of the volume, leaving the free space in one run at the end
+    pub compact: bool,
+    /// Only defragment these files and directories, everything if empty
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FragmentedChain {
+    pub path: PathBuf,
+    pub extents: Vec<Extent>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Fragmentation {
+    pub files: usize,
+    pub directories: usize,
+    /// Total number of extents over all chains
+    pub extents: usize,
+    pub fragmented: Vec<FragmentedChain>,
+    pub free_runs: usize,
+    pub largest_free_run: usize,
+}
+
+impl Fragmentation {
+    /// Percentage of chains made up of more than one extent
+    pub fn fragmented_percent(&self) -> f64 {
+        let chains = self.files + self.directories;
+        if chains == 0 {
+            return 0.0;
+        }
+
+        self.fragmented.len() as f64 * 100.0 / chains as f64
+    }
+}
+
+impl Display for Fragmentation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for chain in &self.fragmented {
+            writeln!(f, "{}: {} extents", chain.path.display(), chain.extents.len())?;
+        }
+
+        writeln!(f, "{} files, {} directories in {} extents", self.files, self.directories, self.extents)?;
+        writeln!(f, "{} fragmented ({:.1}%)", self.fragmented.len(), self.fragmented_percent())?;
+        write!(f, "Free space in {} run(s), the largest is {} cluster(s)", self.free_runs, self.largest_free_run)
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct MovedChain {
+    pub path: PathBuf,
+    pub from: Vec<Extent>,
+    pub to: Vec<Extent>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DefragReport {
+    pub before: Fragmentation,
+    pub after: Fragmentation,
+    pub moved: Vec<MovedChain>,
+    /// Chains that couldn't be made contiguous for lack of a large enough free run
+    pub skipped: Vec<PathBuf>,
+}
+
+impl Display for DefragReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Before:\n{}\n", self.before)?;
+
+        for moved in &self.moved {
+            writeln!(f, "Moved {}: {} -> {} extent(s)", moved.path.display(), moved.from.len(), moved.to.len())?;
+        }
+        for skipped in &self.skipped {
+            writeln!(f, "Skipped {}: no contiguous free space", skipped.display())?;
+        }
+
+        write!(f, "\nAfter:\n{}", self.after)
+    }
+}
+
+struct Chain {
+    path: PathBuf,
+    clusters: Vec<usize>,
+    is_dir: bool,
+    /// Parent directory chain, index of the cluster within it and slot index of the entry, None for the root
+    entry: Option<(usize, usize, usize)>,
+    /// Chains of the subdirectories, whose `..` entries point at this chain
+    subdirectories: Vec<usize>,
+}
+
+struct Defragmenter<'d> {
+    driver: &'d Driver,
+    fat: Vec<u32>,
+    /// Chain and index within it of every used cluster
+    owners: Vec<(u32, u32)>,
+    chains: Vec<Chain>,
+    buffer: Vec<u8>,
+    /// Where to continue looking for free clusters from the end of the volume
+    high_free: usize,
+}
+
+impl<'d> Defragmenter<'d> {
+    fn new(driver: &'d Driver) -> Fat32Result<Self> {
+        let fat = driver.read_fat_table(0)?;
+        let owners = vec![NO_OWNER; fat.len()];
+        let high_free = fat.len() - 1;
+
+        let mut defragmenter = Self {
+            driver,
+            fat,
+            owners,
+            chains: vec![],
+            buffer: vec![0; driver.bytes_per_cluster()],
+            high_free,
+        };
+        defragmenter.walk()?;
+
+        Ok(defragmenter)
+    }
+    fn chain_from(&self, start: usize) -> Vec<usize> {
+        let mut clusters = vec![];
+        let mut cluster = start;
+
+        while self.driver.is_valid_cluster(cluster) && clusters.len() < self.fat.len() {
+            clusters.push(cluster);
+            cluster = self.fat[cluster] as usize;
+        }
+
+        clusters
+    }
+    fn add_chain(&mut self, chain: Chain) -> usize {
+        let id = self.chains.len();
+
+        for (ix, cluster) in chain.clusters.iter().enumerate() {
+            self.owners[*cluster] = (id as u32, ix as u32);
+        }
+        self.chains.push(chain);
+
+        id
+    }
+    fn walk(&mut self) -> Fat32Result<()> {
+        let root = FatDirectory::root(self.driver);
+        let root_chain = Chain {
+            path: PathBuf::from("/"),
+            clusters: self.chain_from(root.cluster_num()),
+            is_dir: true,
+            entry: None,
+            subdirectories: vec![],
+        };
+        self.add_chain(root_chain);
+
+        let mut queue = VecDeque::new();
+        queue.push_back((ROOT_CHAIN, root));
+
+        while let Some((parent, directory)) = queue.pop_front() {
+            let mut files = self.driver.files(&directory);
+
+            while let Some(file) = files.next()? {
+                let special_dir = file.is_current_dir() || file.is_parent_dir();
+                if special_dir || file.is_deleted() || file.is_volume_id() || file.cluster_num() == 0 {
+                    continue;
+                }
+
+                let location = file.location().ok_or(Fat32Error::FileCorrupt)?;
+                let cluster_ix = self.chains[parent].clusters.iter()
+                .position(|cluster| *cluster == location.cluster)
+                .ok_or(Fat32Error::FileCorrupt)?;
+
+                let chain = Chain {
+                    path: self.chains[parent].path.join(file.name()),
+                    clusters: self.chain_from(file.cluster_num()),
+                    is_dir: file.is_dir(),
+                    entry: Some((parent, cluster_ix, location.index)),
+                    subdirectories: vec![],
+                };
+                let id = self.add_chain(chain);
+
+                if file.is_dir() {
+                    self.chains[parent].subdirectories.push(id);
+                    queue.push_back((id, file));
+                }
+            }
+        }
+
+        Ok(())
+    }
+    fn free_runs(&self) -> Vec<Extent> {
+        let free = (2..self.fat.len())
+        .filter(|cluster| fat_is_free(self.fat[*cluster]))
+        .collect::<Vec<_>>();
+
+        extents_of(&free)
+    }
+    fn fragmentation(&self) -> Fragmentation {
+        let mut fragmentation = Fragmentation::default();
+
+        for chain in &self.chains {
+            if chain.is_dir {
+                fragmentation.directories += 1;
+            } else {
+                fragmentation.files += 1;
+            }
+
+            let extents = extents_of(&chain.clusters);
+            fragmentation.extents += extents.len();
+
+            if extents.len() > 1 {
+                fragmentation.fragmented.push(FragmentedChain { path: chain.path.clone(), extents });
+            }
+        }
+
+        let free_runs = self.free_runs();
+        fragmentation.free_runs = free_runs.len();
+        fragmentation.largest_free_run = free_runs.iter().map(|run| run.length).max().unwrap_or(0);
+
+        fragmentation
+    }
+    fn entry_location(&self, id: usize) -> Option<EntryLocation> {
+        let (parent, cluster_ix, index) = self.chains[id].entry?;
+
+        Some(EntryLocation { cluster: self.chains[parent].clusters[cluster_ix], index })
+    }
+    fn set_fat(&mut self, cluster: usize, value: u32) -> Fat32Result<()> {
+        self.driver.write_fat(cluster, value)?;
+        self.fat[cluster] = value;
+
+        Ok(())
+    }
+    /// Points the `.` or `..` entry in the first cluster of a directory to `value`
+    fn patch_dot_entry(&self, directory_cluster: usize, name: &[u8; 11], value: usize) -> Fat32Result<()> {
+        let entries_per_cluster = self.driver.bytes_per_cluster() / FAT32_DIR_SIZE;
+
+        for index in 0..entries_per_cluster {
+            let location = EntryLocation { cluster: directory_cluster, index };
+
+            let mut raw = [0; 11];
+            self.driver.read_cluster(directory_cluster, location.byte_offset(), &mut raw)?;
+
+            if &raw == name {
+                let mut dot = self.driver.read_entry(location)?.ok_or(Fat32Error::FileCorrupt)?;
+                dot.set_cluster_num(value);
+
+                return self.driver.write_entry(&dot);
+            }
+        }
+
+        Ok(())
+    }
+    /// Moves the `ix`th cluster of chain `id` to the free cluster `to`.
+    /// The data is copied and linked in before the old cluster is freed.
+    fn move_cluster(&mut self, id: usize, ix: usize, to: usize) -> Fat32Result<()> {
+        let from = self.chains[id].clusters[ix];
+
+        self.driver.read_cluster(from, 0, &mut self.buffer)?;
+        self.driver.write_cluster(to, 0, &self.buffer)?;
+
+        self.set_fat(to, self.fat[from])?;
+
+        if ix > 0 {
+            self.set_fat(self.chains[id].clusters[ix - 1], to as u32)?;
+        } else if let Some(location) = self.entry_location(id) {
+            let mut entry = self.driver.read_entry(location)?.ok_or(Fat32Error::FileCorrupt)?;
+            entry.set_cluster_num(to);
+            self.driver.write_entry(&entry)?;
+        }
+
+        self.set_fat(from, FAT_FREE)?;
+
+        self.chains[id].clusters[ix] = to;
+        self.owners[to] = (id as u32, ix as u32);
+        self.owners[from] = NO_OWNER;
+
+        if self.chains[id].is_dir && ix == 0 {
+            self.patch_dot_entry(to, b".          ", to)?;
+
+            for child in self.chains[id].subdirectories.clone() {
+                let child_cluster = self.chains[child].clusters[0];
+                self.patch_dot_entry(child_cluster, b"..         ", to)?;
+            }
+        }
+
+        Ok(())
+    }
+    fn is_pinned(&self, cluster: usize) -> bool {
+        // The first cluster of the root directory is recorded in the BPB
+        fat_is_bad(self.fat[cluster]) || self.owners[cluster] == (ROOT_CHAIN as u32, 0)
+    }
+    /// Finds a free cluster from the end of the volume, above `min` if possible
+    fn free_cluster_above(&mut self, min: usize) -> Fat32Result<usize> {
+        for cluster in (min..=self.high_free).rev() {
+            if fat_is_free(self.fat[cluster]) {
+                self.high_free = cluster;
+                return Ok(cluster);
+            }
+        }
+
+        // Free space may have opened up again at the end of the volume
+        self.high_free = self.fat.len() - 1;
+        (min..self.fat.len()).rev()
+        .find(|cluster| fat_is_free(self.fat[*cluster]))
+        .ok_or(Fat32Error::NoSpace)
+    }
+    /// Moves a fragmented chain into the smallest free run that holds it
+    fn make_contiguous(&mut self, id: usize) -> Fat32Result<bool> {
+        let length = self.chains[id].clusters.len();
+
+        let run = self.free_runs().into_iter()
+        .filter(|run| run.length >= length)
+        .min_by_key(|run| run.length);
+
+        let Some(run) = run else {
+            return Ok(false);
+        };
+
+        for ix in 0..length {
+            self.move_cluster(id, ix, run.start + ix)?;
+        }
+
+        Ok(true)
+    }
+    /// Packs every chain, in walk order, from the start of the data region
+    fn compact(&mut self, selected: &[usize]) -> Fat32Result<()> {
+        let mut cursor = 2;
+
+        for id in selected.iter().copied() {
+            for ix in 0..self.chains[id].clusters.len() {
+                if id == ROOT_CHAIN && ix == 0 {
+                    continue;
+                }
+
+                while cursor < self.fat.len() && self.is_pinned(cursor) {
+                    cursor += 1;
+                }
+
+                let target = cursor;
+                if target >= self.fat.len() {
+                    return Err(Fat32Error::NoSpace);
+                }
+
+                if self.chains[id].clusters[ix] != target {
+                    if !fat_is_free(self.fat[target]) {
+                        // Evict whatever lives there out of the way, it gets its turn later
+                        let (owner, owner_ix) = self.owners[target];
+                        let remaining = self.chains[id].clusters.len() - ix;
+                        let free = self.free_cluster_above(target + remaining)?;
+
+                        self.move_cluster(owner as usize, owner_ix as usize, free)?;
+                    }
+
+                    self.move_cluster(id, ix, target)?;
+                }
+
+                cursor += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Reports how fragmented the files and directories of the volume are
+pub fn fragmentation(driver: &Driver) -> Fat32Result<Fragmentation> {
+    Ok(Defragmenter::new(driver)?.fragmentation())
+}
+
+fn is_selected(path: &Path, options: &DefragOptions) -> bool {
+    options.paths.is_empty() || options.paths.iter().any(|selected| selected == path)
+}
+
+/// Relocates cluster chains into contiguous runs. The volume must be consistent and not mounted.
+pub fn defrag(driver: &Driver, options: &DefragOptions) -> Fat32Result<DefragReport> {
+    let check = crate::check::check(driver)?;
+
+    // A wrong free count in FSInfo is only a stale hint, it's counted again rather than refused over
+    if !check.issues.iter().all(|issue| matches!(issue, CheckIssue::FsInfoMismatch { .. })) {
+        return Err(Fat32Error::Inconsistent);
+    }
+    if !check.is_clean() {
+        driver.recompute_free_space()?;
+    }
+
+    let mut defragmenter = Defragmenter::new(driver)?;
+    let mut report = DefragReport {
+        before: defragmenter.fragmentation(),
+        ..Default::default()
+    };
+
+    let selected = (0..defragmenter.chains.len())
+    .filter(|id| is_selected(&defragmenter.chains[*id].path, options))
+    .collect::<Vec<_>>();
+
+    let before = defragmenter.chains.iter()
+    .map(|chain| extents_of(&chain.clusters))
+    .collect::<Vec<_>>();
+
+    if options.compact {
+        defragmenter.compact(&selected)?;
+    } else {
+        for id in selected.iter().copied() {
+            let fragmented = before[id].len() > 1;
+            // The root directory can't be moved as a whole
+            if !fragmented || id == ROOT_CHAIN {
+                continue;
+            }
+
+            if !defragmenter.make_contiguous(id)? {
+                report.skipped.push(defragmenter.chains[id].path.clone());
+            }
+        }
+    }
+
+    for (chain, before) in defragmenter.chains.iter().zip(before) {
+        let after = extents_of(&chain.clusters);
+        if after != before {
+            report.moved.push(MovedChain { path: chain.path.clone(), from: before, to: after });
+        }
+    }
+    report.moved.sort_by(|a, b| a.path.cmp(&b.path));
+
+    driver.recompute_free_space()?;
+    // Clusters of directories may have moved
+    driver.invalidate_dentries();
+    driver.sync()?;
+
+    report.after = defragmenter.fragmentation();
+
+    Ok(report)
+}
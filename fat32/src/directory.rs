@@ -1,6 +1,7 @@
 use std::{ffi::{OsStr, OsString}, io::{Cursor, Read}, num::Wrapping, ops::Add, time::SystemTime};
 
-use chrono::{Local, TimeZone};
+use chrono::{Datelike, Local, TimeZone, Timelike};
+use serde::Serialize;
 
 use crate::{util::read_bytes, Driver, Fat32Error, Fat32Result};
 
@@ -11,9 +12,26 @@ pub const DIR_ATTR_VOLUME_ID: u8 = 0x08;
 pub const DIR_ATTR_DIRECTORY: u8 = 0x10;
 pub const DIR_ATTR_ARCHIVE: u8 = 0x20;
 pub const DIR_ATTR_LONG_FILE_NAME: u8 = 0x0F;
+/// Attributes that can be changed on an existing entry, the rest describe what the entry is
+pub const DIR_ATTR_CHANGEABLE: u8 = DIR_ATTR_READ_ONLY | DIR_ATTR_HIDDEN | DIR_ATTR_SYSTEM | DIR_ATTR_ARCHIVE;
 
 pub const FAT32_DIR_SIZE: usize = 32;
 
+/// Where a short name entry lives on disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+pub struct EntryLocation {
+    /// Cluster of the directory containing the entry
+    pub cluster: usize,
+    /// Index of the 32 byte entry within the cluster
+    pub index: usize,
+}
+
+impl EntryLocation {
+    pub fn byte_offset(&self) -> usize {
+        self.index * FAT32_DIR_SIZE
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct FatEntry {
     /// DIR_Name
@@ -85,8 +103,47 @@ impl FatEntry {
             file_size,
         }))
     }
+    /// Creates a short name entry stamped with the current local time
+    pub fn new(name: [u8; 11], attr: u8, cluster: usize, file_size: u32) -> Self {
+        let now = Local::now();
+        let date = fat32_encode_date(now.year(), now.month(), now.day());
+        let time = fat32_encode_time(now.hour(), now.minute(), now.second());
+
+        Self {
+            name,
+            attr,
+            nt_res: 0,
+            crt_time_tenth: ((now.second() % 2) * 100 + now.timestamp_subsec_millis() / 10) as u8,
+            crt_time: time,
+            crt_date: date,
+            lst_acc_date: date,
+            fst_clus_hi: (cluster >> 16) as u16,
+            wrt_time: time,
+            wrt_date: date,
+            fst_clus_lo: (cluster & 0xFFFF) as u16,
+            file_size,
+        }
+    }
+    pub fn to_bytes(&self) -> [u8; FAT32_DIR_SIZE] {
+        let mut buf = [0; FAT32_DIR_SIZE];
+
+        buf[0..11].copy_from_slice(&self.name);
+        buf[11] = self.attr;
+        buf[12] = self.nt_res;
+        buf[13] = self.crt_time_tenth;
+        buf[14..16].copy_from_slice(&self.crt_time.to_le_bytes());
+        buf[16..18].copy_from_slice(&self.crt_date.to_le_bytes());
+        buf[18..20].copy_from_slice(&self.lst_acc_date.to_le_bytes());
+        buf[20..22].copy_from_slice(&self.fst_clus_hi.to_le_bytes());
+        buf[22..24].copy_from_slice(&self.wrt_time.to_le_bytes());
+        buf[24..26].copy_from_slice(&self.wrt_date.to_le_bytes());
+        buf[26..28].copy_from_slice(&self.fst_clus_lo.to_le_bytes());
+        buf[28..32].copy_from_slice(&self.file_size.to_le_bytes());
+
+        buf
+    }
     pub fn root(driver: &Driver) -> Self {
-        let cluster = driver.bpb.data_start_sector();
+        let cluster = driver.bpb.bpb_root_clus as usize;
 
         let fst_clus_hi = (cluster >> 16) as u16;
         let fst_clus_lo = (cluster & 0xFFFF) as u16;
@@ -110,8 +167,11 @@ impl FatEntry {
         let file_name = &self.name[0..8];
         let extension = &self.name[8..];
 
-        let file_name = std::str::from_utf8(file_name).unwrap().trim();
-        let extension = std::str::from_utf8(extension).unwrap().trim();
+        let file_name = String::from_utf8_lossy(file_name);
+        let extension = String::from_utf8_lossy(extension);
+
+        let file_name = file_name.trim();
+        let extension = extension.trim();
 
         let file_name = if self.nt_res >> 3 & 1 == 1 {
            file_name.to_lowercase()
@@ -133,16 +193,39 @@ impl FatEntry {
 
         OsString::from(name)
     }
-    pub fn name_checksum(&self) -> u8 {
-        let mut sum: Wrapping<u8> = Wrapping(0);
-        for i in 0..11 {
-            sum = (sum >> 1).add(sum << 7).add(Wrapping(self.name[i]));       
+    /// DIR_Name as stored, the 8.3 name padded with spaces
+    pub fn raw_name(&self) -> &[u8; 11] {
+        &self.name
+    }
+    /// A copy of the entry under another short name
+    pub(crate) fn renamed(&self, name: [u8; 11]) -> Self {
+        Self {
+            name,
+            // The case flags were for the old name
+            nt_res: 0,
+            ..self.clone()
         }
+    }
+    pub fn name_checksum(&self) -> u8 {
+        name_checksum(&self.name)
+    }
+}
 
-        sum.0
+/// The checksum long name entries keep of the raw short name they belong to
+pub(crate) fn name_checksum(name: &[u8; 11]) -> u8 {
+    let mut sum: Wrapping<u8> = Wrapping(0);
+    for byte in name {
+        sum = (sum >> 1).add(sum << 7).add(Wrapping(*byte));
     }
+
+    sum.0
 }
 
+/// Set in LDIR_Ord on the last (first on disk) entry of a long name
+pub const LAST_LONG_ENTRY: u8 = 0x40;
+/// Marks a free or deleted directory entry in DIR_Name[0] or LDIR_Ord
+pub const DIR_ENTRY_FREE: u8 = 0xE5;
+
 #[allow(unused)]
 pub struct LFN {
     /// LDIR_Ord
@@ -184,10 +267,11 @@ impl LFN {
         let mut name3 = [0; 4];
         reader.read_exact(&mut name3).map_err(|err| Fat32Error::IOError(err))?;
 
+        // Not a long name entry any more, whatever the attributes say
+        if type_ != 0 || fst_clus_lo != 0 {
+            return Err(Fat32Error::FileCorrupt);
+        }
 
-        assert!(type_ == 0);
-        assert!(fst_clus_lo == 0);
-        
         Ok(Self {
             ord,
             name1,
@@ -199,69 +283,150 @@ impl LFN {
             name3,
         })
     }
-    pub fn construct_name(&self, buffer: &mut OsString) {
+    /// Splits a UTF-16 long name into the entries that store it, in the order they go on disk
+    pub(crate) fn entries_for(name: &[u16], checksum: u8) -> Vec<Self> {
+        let n_entries = name.len().div_ceil(13);
+
+        (1..=n_entries).rev().map(|ord| {
+            // The name is terminated by a 0 if it doesn't fill the last entry, and padded with 0xFFFF after that
+            let mut chars = [0xFFFF; 13];
+            let part = &name[(ord - 1) * 13..usize::min(ord * 13, name.len())];
+            chars[..part.len()].copy_from_slice(part);
+            if part.len() < 13 {
+                chars[part.len()] = 0;
+            }
+
+            let mut bytes = [0; 26];
+            for (ix, char_u16) in chars.iter().enumerate() {
+                bytes[ix * 2..ix * 2 + 2].copy_from_slice(&char_u16.to_le_bytes());
+            }
+
+            Self {
+                ord: ord as u8 | if ord == n_entries { LAST_LONG_ENTRY } else { 0 },
+                name1: bytes[0..10].try_into().unwrap(),
+                attr: DIR_ATTR_LONG_FILE_NAME,
+                type_: 0,
+                chksum: checksum,
+                name2: bytes[10..22].try_into().unwrap(),
+                fst_clus_lo: 0,
+                name3: bytes[22..26].try_into().unwrap(),
+            }
+        })
+        .collect()
+    }
+    pub fn to_bytes(&self) -> [u8; FAT32_DIR_SIZE] {
+        let mut buf = [0; FAT32_DIR_SIZE];
+
+        buf[0] = self.ord;
+        buf[1..11].copy_from_slice(&self.name1);
+        buf[11] = self.attr;
+        buf[12] = self.type_;
+        buf[13] = self.chksum;
+        buf[14..26].copy_from_slice(&self.name2);
+        buf[26..28].copy_from_slice(&self.fst_clus_lo.to_le_bytes());
+        buf[28..32].copy_from_slice(&self.name3);
+
+        buf
+    }
+    /// Appends the UTF-16 characters of this part of the name to `units`, up to the terminating 0
+    fn push_name_units(&self, units: &mut Vec<u16>) {
         let mut name = [0; 26];
         name[0..10].copy_from_slice(&self.name1);
         name[10..22].copy_from_slice(&self.name2);
         name[22..].copy_from_slice(&self.name3);
 
-        let mut name_utf16: [u16; 13] = [0; 13];
-
-        let mut len = name_utf16.len();
-        for (ix, chunks) in name.chunks(2).enumerate() {
-            let char_u16 = u16::from_le_bytes([chunks[0], chunks[1]]);
-
-            if char_u16 == 0 {
-                len = ix;
-                break;
-            }
-
-            name_utf16[ix] = char_u16;
-        }
+        units.extend(name.chunks(2)
+        .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+        .take_while(|char_u16| *char_u16 != 0));
+    }
+    pub fn construct_name(&self, buffer: &mut OsString) {
+        let mut units = vec![];
+        self.push_name_units(&mut units);
 
-        let part = String::from_utf16(&name_utf16[0..len]).expect("Invalid UTF-16");
-        let name_string = part;
-        
-        buffer.push(name_string)
+        buffer.push(String::from_utf16_lossy(&units))
     }
 }
 
 #[derive(Clone)]
 pub struct FatDirectory {
     name: OsString,
-    entry: FatEntry
+    entry: FatEntry,
+    /// Long name entries were present but their checksum didn't match the short name
+    lfn_mismatch: bool,
+    /// Some of the long name entries preceding this entry couldn't be read
+    lfn_damaged: bool,
+    /// None for the root directory, which has no entry of its own
+    location: Option<EntryLocation>,
 }
 
 impl FatDirectory {
     pub fn new(entry: FatEntry, lfn_parts: &[LFN]) -> Self {
         let sfn_checksum = entry.name_checksum();
 
-        let name = if lfn_parts.is_empty() {
+        // Orphaned long name entries are ignored and the short name is used instead
+        let lfn_mismatch = lfn_parts.iter().any(|part| part.chksum != sfn_checksum);
+
+        let name = if lfn_parts.is_empty() || lfn_mismatch {
             entry.short_name()
         } else {
-            let mut full_name = OsString::new();
-
+            // Decoded as a whole, surrogate pairs can straddle two parts
+            let mut units = vec![];
             for part in lfn_parts.iter().rev() {
-                if part.chksum != sfn_checksum {
-                    todo!("Invalid name checksum")
-                }
-                part.construct_name(&mut full_name);
+                part.push_name_units(&mut units);
             }
 
-            full_name
+            OsString::from(String::from_utf16_lossy(&units))
         };
 
         Self {
             name,
-            entry
+            entry,
+            lfn_mismatch,
+            lfn_damaged: false,
+            location: None,
         }
     }
     pub fn root(driver: &Driver) -> Self {
         Self {
             name: OsString::from("/"),
-            entry: FatEntry::root(driver)
+            entry: FatEntry::root(driver),
+            lfn_mismatch: false,
+            lfn_damaged: false,
+            location: None,
         }
     }
+    pub fn entry(&self) -> &FatEntry {
+        &self.entry
+    }
+    pub fn location(&self) -> Option<EntryLocation> {
+        self.location
+    }
+    pub(crate) fn set_location(&mut self, location: EntryLocation) {
+        self.location = Some(location);
+    }
+    pub(crate) fn set_cluster_num(&mut self, cluster: usize) {
+        self.entry.fst_clus_hi = (cluster >> 16) as u16;
+        self.entry.fst_clus_lo = (cluster & 0xFFFF) as u16;
+    }
+    /// Points the entry at new contents, stamping it as written now and setting the archive bit
+    pub(crate) fn set_contents(&mut self, cluster: usize, file_size: u32) {
+        let now = FatEntry::new(self.entry.name, self.entry.attr | DIR_ATTR_ARCHIVE, cluster, file_size);
+
+        self.entry = FatEntry {
+            crt_time_tenth: self.entry.crt_time_tenth,
+            crt_time: self.entry.crt_time,
+            crt_date: self.entry.crt_date,
+            nt_res: self.entry.nt_res,
+            ..now
+        };
+    }
+    /// The DIR_Attr byte
+    pub fn attributes(&self) -> u8 {
+        self.entry.attr
+    }
+    pub(crate) fn set_attributes(&mut self, attr: u8) {
+        self.entry.attr = attr;
+    }
     pub fn name(&self) -> &OsStr {
         &self.name
     }
@@ -278,7 +443,18 @@ impl FatDirectory {
         self.matches_attr(DIR_ATTR_DIRECTORY)
     }
     pub fn is_deleted(&self) -> bool {
-        self.entry.name[0] == 0xE5
+        self.entry.name[0] == DIR_ENTRY_FREE
+    }
+    pub fn is_volume_id(&self) -> bool {
+        self.matches_attr(DIR_ATTR_VOLUME_ID) && !self.is_dir()
+    }
+    /// Checks if the long name entries preceding this entry were orphaned
+    pub fn has_lfn_mismatch(&self) -> bool {
+        self.lfn_mismatch
+    }
+    /// Checks if long name entries preceding this entry were damaged and left out
+    pub fn has_damaged_lfn(&self) -> bool {
+        self.lfn_damaged
     }
     /// Checks if directory is .
     pub fn is_current_dir(&self) -> bool {
@@ -304,15 +480,15 @@ impl FatDirectory {
 
         Ok(n_clusters)
     }
-    fn fat32_get_time(time: u16) -> (u32, u32, u32) {
+    pub(crate) fn fat32_get_time(time: u16) -> (u32, u32, u32) {
         let two_second_count = time & 0b11111;
         let seconds = two_second_count * 2;
         let minutes = time >> 5 & 0b111111;
-        let hours = time >> 15;
+        let hours = time >> 11;
 
         (hours as u32, minutes as u32, seconds as u32)
     }
-    fn fat32_get_date(date: u16) -> (i32, u32, u32) {
+    pub(crate) fn fat32_get_date(date: u16) -> (i32, u32, u32) {
         let day = date & 0b11111;
         let month = date >> 5 & 0b1111; 
         let year_offset = date >> 9;
@@ -320,57 +496,104 @@ impl FatDirectory {
         
         (year as i32, month as u32, day as u32)
     }
+    /// Unset or invalid timestamps, like the all zero ones of volume labels, fall back to the FAT epoch
+    fn fat32_system_time(year: i32, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> SystemTime {
+        let datetime = Local.with_ymd_and_hms(year, month, day, hour, minute, second).earliest()
+        .or_else(|| Local.with_ymd_and_hms(1980, 1, 1, 0, 0, 0).earliest())
+        .unwrap();
+
+        SystemTime::from(datetime)
+    }
     pub fn create_time(&self) -> SystemTime {
         let (hour, minute, second) = Self::fat32_get_time(self.entry.crt_time);
         let (year, month, day) = Self::fat32_get_date(self.entry.crt_date);
-        let datetime = Local.with_ymd_and_hms(year, month, day, hour, minute, second).unwrap();
-        
-        SystemTime::from(datetime)
+
+        Self::fat32_system_time(year, month, day, hour, minute, second)
     }
     pub fn write_time(&self) -> SystemTime {
         let (hour, minute, second) = Self::fat32_get_time(self.entry.wrt_time);
         let (year, month, day) = Self::fat32_get_date(self.entry.wrt_date);
-        
-        let datetime = Local.with_ymd_and_hms(year, month, day, hour, minute, second).unwrap();
-        
-        SystemTime::from(datetime)
+
+        Self::fat32_system_time(year, month, day, hour, minute, second)
     }
     pub fn access_time(&self) -> SystemTime {
         let (year, month, day) = Self::fat32_get_date(self.entry.lst_acc_date);
 
-        let datetime = Local.with_ymd_and_hms(year, month, day, 0, 0, 0).unwrap();
-
-        SystemTime::from(datetime)
+        Self::fat32_system_time(year, month, day, 0, 0, 0)
     }
 }
 
 pub struct Files<'d> {
     driver: &'d Driver,
-    start_sector: usize,
-    byte_offset: usize
+    cluster: Option<usize>,
+    byte_offset: usize,
+    n_clusters: usize,
+    /// Location of the last entry read
+    location: EntryLocation,
 }
 
 impl<'d> Files<'d> {
     pub fn new(driver: &'d Driver, directory: &FatDirectory) -> Self {
-        let start_sector = if directory.cluster_num() == driver.bpb.data_start_sector() {
-            directory.cluster_num()
-        } else {
-            driver.bpb.cluster_start_sector(directory.cluster_num())
+        // `..` entries of first level directories store 0 to refer to the root
+        let cluster = match directory.cluster_num() {
+            0 => driver.bpb.bpb_root_clus as usize,
+            cluster => cluster
         };
 
         Self {
             driver,
-            start_sector,
-            byte_offset: 0
+            cluster: Some(cluster),
+            byte_offset: 0,
+            n_clusters: 1,
+            location: EntryLocation { cluster, index: 0 },
+        }
+    }
+    /// Reads the next raw 32 byte entry, following the cluster chain of the directory
+    fn read_entry(&mut self, buf: &mut [u8; FAT32_DIR_SIZE]) -> Fat32Result<bool> {
+        let Some(mut cluster) = self.cluster else {
+            return Ok(false);
+        };
+
+        if self.byte_offset == self.driver.bytes_per_cluster() {
+            let Some(next_cluster) = self.driver.read_fat(cluster)? else {
+                self.cluster = None;
+                return Ok(false);
+            };
+
+            if !self.driver.is_valid_cluster(next_cluster) {
+                return Err(Fat32Error::BadCluster(next_cluster as u32));
+            }
+
+            // A chain longer than the volume must contain a loop
+            self.n_clusters += 1;
+            if self.n_clusters > self.driver.bpb.cluster_count() {
+                return Err(Fat32Error::FileCorrupt);
+            }
+
+            cluster = next_cluster;
+            self.cluster = Some(cluster);
+            self.byte_offset = 0;
+        }
+
+        if !self.driver.is_valid_cluster(cluster) {
+            return Err(Fat32Error::BadCluster(cluster as u32));
         }
+
+        self.driver.read_cluster(cluster, self.byte_offset, buf)?;
+        self.location = EntryLocation { cluster, index: self.byte_offset / FAT32_DIR_SIZE };
+        self.byte_offset += FAT32_DIR_SIZE;
+
+        Ok(true)
     }
     fn fetch_directory(&mut self) -> Fat32Result<Option<FatDirectory>> {
         let mut buf = [0; FAT32_DIR_SIZE];
 
-        let mut lfn_parts = vec![];
+        let mut lfn_parts: Vec<LFN> = vec![];
+        let mut damaged = false;
         loop {
-            self.driver.read_sector(self.start_sector, self.byte_offset, &mut buf)?;
-            self.byte_offset += FAT32_DIR_SIZE;
+            if !self.read_entry(&mut buf)? {
+                return Ok(None);
+            }
     
             let attrs = buf[11];
     
@@ -378,16 +601,49 @@ impl<'d> Files<'d> {
                 (attrs & DIR_ATTR_LONG_FILE_NAME) == DIR_ATTR_LONG_FILE_NAME
             }
 
-            if is_lfn_entry(attrs) {
-                lfn_parts.push(LFN::read(&buf)?);
+            if buf[0] != 0 && is_lfn_entry(attrs) {
+                // A damaged part breaks the name it belongs to, the short name is used instead
+                let Ok(part) = LFN::read(&buf) else {
+                    lfn_parts.clear();
+                    damaged = buf[0] != DIR_ENTRY_FREE;
+                    continue;
+                };
+
+                // A new long name starts, or deleted parts give way to live ones
+                let starts_new_name = part.ord & LAST_LONG_ENTRY != 0;
+                let deletion_changed = lfn_parts.last()
+                .is_some_and(|last| (last.ord == DIR_ENTRY_FREE) != (part.ord == DIR_ENTRY_FREE));
+
+                if starts_new_name || deletion_changed {
+                    lfn_parts.clear();
+                    damaged = false;
+                }
+
+                // The rest of a damaged name would only give part of it
+                if !damaged {
+                    lfn_parts.push(part);
+                }
 
                 continue;
             } else {
                 let entry = FatEntry::read(&buf)?;
 
                 let result = match entry {
-                    Some(entry) => Ok(Some(FatDirectory::new(entry, &lfn_parts))),
-                    None => Ok(None)
+                    Some(entry) => {
+                        // Leftover parts of a deleted long name don't belong to a live entry
+                        let deleted = entry.name[0] == DIR_ENTRY_FREE;
+                        lfn_parts.retain(|part| (part.ord == DIR_ENTRY_FREE) == deleted);
+
+                        let mut directory = FatDirectory::new(entry, &lfn_parts);
+                        directory.lfn_damaged = damaged && !deleted;
+                        directory.set_location(self.location);
+
+                        Ok(Some(directory))
+                    },
+                    None => {
+                        self.cluster = None;
+                        Ok(None)
+                    }
                 };
 
                 return result;
@@ -407,4 +663,13 @@ impl<'d> Files<'d> {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+pub fn fat32_encode_date(year: i32, month: u32, day: u32) -> u16 {
+    let year_offset = (year - 1980).clamp(0, 127) as u16;
+
+    year_offset << 9 | (month as u16) << 5 | day as u16
+}
+pub fn fat32_encode_time(hours: u32, minutes: u32, seconds: u32) -> u16 {
+    (hours as u16) << 11 | (minutes as u16) << 5 | (seconds / 2) as u16
+}
@@ -1,9 +1,12 @@
 use std::ffi::OsStr;
+use std::io::Read;
 use std::path::{Component, Path};
 
-use parking_lot::{Mutex, RwLock};
+use parking_lot::Mutex;
 
-use crate::{Fat32Error, FatDirectory, FileHandle, FileState, Files};
+use crate::alloc::{AllocationStrategy, AllocatorState};
+use crate::dcache::DentryCache;
+use crate::{EntryLocation, FSInfo, Fat32Error, FatDirectory, FatEntry, FatFile, FileHandle, FileState, Files, OpenHandle, DIR_ATTR_CHANGEABLE, DIR_ATTR_DIRECTORY, DIR_ATTR_READ_ONLY, DIR_ENTRY_FREE, FAT32_DIR_SIZE};
 
 use super::io::Drive;
 use super::{boot::BPB, Fat32Result};
@@ -11,24 +14,34 @@ use super::{boot::BPB, Fat32Result};
 pub struct Driver {
     pub(crate) drive: Drive,
     pub(crate) bpb: BPB,
-    file_state: RwLock<FileState>,
+    file_state: FileState,
+    pub(crate) allocator: Mutex<AllocatorState>,
+    pub(crate) dentries: Mutex<DentryCache>,
 }
 
 impl Driver {
     pub fn new(drive: Drive) -> Fat32Result<Self> {
         let bpb = BPB::read_from(&drive)?;
 
-        println!("{:#?}", bpb);
-
         Ok(Self {
             drive,
             bpb,
-            file_state: RwLock::new(FileState::new()),
+            file_state: FileState::new(),
+            allocator: Mutex::new(AllocatorState::default()),
+            dentries: Mutex::new(DentryCache::new()),
         })
     }
     pub fn bytes_per_cluster(&self) -> usize {
         self.bpb.bytes_per_cluster()
     }
+    /// Number of clusters in the data region
+    pub fn cluster_count(&self) -> usize {
+        self.bpb.cluster_count()
+    }
+    /// Volume serial number from the boot sector
+    pub fn volume_id(&self) -> u32 {
+        self.bpb.bs_vol_id
+    }
     pub(crate) fn read_sector(&self, n: usize, byte_offset: usize, buffer: &mut [u8]) -> Fat32Result<()>{
         let sector_byte_offset = self.bpb.bytes_per_sector() * n;
         let offset = sector_byte_offset + byte_offset;
@@ -43,18 +56,46 @@ impl Driver {
     
         Ok(())
     }
-    /// Returns the next cluster number according to the FAT table
-    pub(crate) fn read_fat(&self, cluster_num: usize) -> Fat32Result<Option<usize>> {
+    pub(crate) fn write_sector(&self, n: usize, byte_offset: usize, buffer: &[u8]) -> Fat32Result<()> {
+        let sector_byte_offset = self.bpb.bytes_per_sector() * n;
+        let offset = sector_byte_offset + byte_offset;
+
+        self.drive.write(buffer, offset as i64)?;
+
+        Ok(())
+    }
+    pub(crate) fn write_cluster(&self, n: usize, byte_offset: usize, buffer: &[u8]) -> Fat32Result<()> {
+        let start_sector = self.bpb.cluster_start_sector(n);
+        self.write_sector(start_sector, byte_offset, buffer)
+    }
+    pub(crate) fn zero_cluster(&self, n: usize) -> Fat32Result<()> {
+        let zeroes = vec![0; self.bytes_per_cluster()];
+        self.write_cluster(n, 0, &zeroes)
+    }
+    /// Writes back the FSInfo hints and flushes all writes to the underlying drive
+    pub fn sync(&self) -> Fat32Result<()> {
+        self.flush_fs_info()?;
+        self.drive.sync()
+    }
+    pub fn fs_info(&self) -> Fat32Result<FSInfo> {
+        FSInfo::read_from(self)
+    }
+    /// Returns the raw value of a FAT entry, with the reserved upper 4 bits masked off
+    pub(crate) fn read_fat_entry(&self, cluster_num: usize) -> Fat32Result<u32> {
         let bpb = &self.bpb;
 
-        let sector = bpb.bpb_rsvd_sec_cnt as usize + (cluster_num * 4 / bpb.bytes_per_sector());
+        let sector = bpb.fat_start_sector() + (cluster_num * 4 / bpb.bytes_per_sector());
         let entry_offset = (cluster_num * 4) % bpb.bytes_per_sector();
 
         let mut bytes = [0; 4];
 
         self.read_sector(sector, entry_offset, &mut bytes)?;
 
-        let cluster_val = u32::from_le_bytes(bytes) & 0x0FFFFFFF;
+        Ok(u32::from_le_bytes(bytes) & 0x0FFFFFFF)
+    }
+    /// Returns the next cluster number according to the FAT table
+    pub(crate) fn read_fat(&self, cluster_num: usize) -> Fat32Result<Option<usize>> {
+        let cluster_val = self.read_fat_entry(cluster_num)?;
 
         let cluster_num = if fat_is_eoc(cluster_val) {
             None
@@ -64,23 +105,238 @@ impl Driver {
 
         Ok(cluster_num)
     }
+    /// Reads every entry of the `fat_index`th copy of the FAT in one go
+    pub(crate) fn read_fat_table(&self, fat_index: usize) -> Fat32Result<Vec<u32>> {
+        let bpb = &self.bpb;
+
+        let n_entries = bpb.cluster_count() + 2;
+        let start_sector = bpb.fat_start_sector() + fat_index * bpb.bpb_fat_sz32 as usize;
+
+        let mut bytes = vec![0; n_entries * 4];
+        self.read_sector(start_sector, 0, &mut bytes)?;
+
+        let table = bytes.chunks_exact(4)
+        .map(|entry| u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) & 0x0FFFFFFF)
+        .collect();
+
+        Ok(table)
+    }
+    /// Writes a FAT entry to every copy of the FAT, preserving the reserved upper 4 bits
+    pub(crate) fn write_fat(&self, cluster_num: usize, value: u32) -> Fat32Result<()> {
+        let bpb = &self.bpb;
+
+        let entry_offset = (cluster_num * 4) % bpb.bytes_per_sector();
+
+        for fat_index in 0..bpb.bpb_num_fats as usize {
+            let sector = bpb.fat_start_sector() + fat_index * bpb.bpb_fat_sz32 as usize + (cluster_num * 4 / bpb.bytes_per_sector());
+
+            let mut bytes = [0; 4];
+            self.read_sector(sector, entry_offset, &mut bytes)?;
+
+            let reserved = u32::from_le_bytes(bytes) & 0xF0000000;
+            let bytes = (reserved | (value & 0x0FFFFFFF)).to_le_bytes();
+
+            self.write_sector(sector, entry_offset, &bytes)?;
+        }
+
+        self.allocator.lock().fat_written(cluster_num, &[value]);
+
+        Ok(())
+    }
+    /// Overwrites the `fat_index`th copy of the FAT with `table`
+    pub(crate) fn write_fat_table(&self, fat_index: usize, table: &[u32]) -> Fat32Result<()> {
+        let bpb = &self.bpb;
+        let start_sector = bpb.fat_start_sector() + fat_index * bpb.bpb_fat_sz32 as usize;
+
+        let mut bytes = vec![0; table.len() * 4];
+        self.read_sector(start_sector, 0, &mut bytes)?;
+
+        for (entry, value) in bytes.chunks_exact_mut(4).zip(table) {
+            let reserved = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) & 0xF0000000;
+            entry.copy_from_slice(&(reserved | (value & 0x0FFFFFFF)).to_le_bytes());
+        }
+
+        self.write_sector(start_sector, 0, &bytes)?;
+
+        if fat_index == 0 {
+            self.allocator.lock().fat_written(0, table);
+        }
+
+        Ok(())
+    }
+    /// Checks if `cluster_num` refers to a cluster in the data region
+    pub fn is_valid_cluster(&self, cluster_num: usize) -> bool {
+        cluster_num >= 2 && cluster_num < self.bpb.cluster_count() + 2
+    }
     pub fn files(&self, directory: &FatDirectory) -> Files {
         Files::new(self, &directory)
     }
+    /// Writes the short name entry of `directory` back to where it was read from
+    pub(crate) fn write_entry(&self, directory: &FatDirectory) -> Fat32Result<()> {
+        let Some(location) = directory.location() else {
+            // The root directory has no entry of its own
+            return Ok(());
+        };
+
+        self.write_cluster(location.cluster, location.byte_offset(), &directory.entry().to_bytes())?;
+        // Only once it's written, or a lookup in between could cache the old entry again
+        self.dentries.lock().invalidate_entry(location);
+
+        Ok(())
+    }
+    /// Re-reads the entry at `location`, returning None if the slot is free
+    pub(crate) fn read_entry(&self, location: EntryLocation) -> Fat32Result<Option<FatDirectory>> {
+        let mut buf = [0; FAT32_DIR_SIZE];
+        self.read_cluster(location.cluster, location.byte_offset(), &mut buf)?;
+
+        let Some(entry) = FatEntry::read(&buf)? else {
+            return Ok(None);
+        };
+
+        let mut directory = FatDirectory::new(entry, &[]);
+        directory.set_location(location);
+
+        Ok((!directory.is_deleted()).then_some(directory))
+    }
+    /// The 32 bytes at `location` as they are on disk
+    pub fn raw_entry(&self, location: EntryLocation) -> Fat32Result<[u8; FAT32_DIR_SIZE]> {
+        let mut buf = [0; FAT32_DIR_SIZE];
+        self.read_cluster(location.cluster, location.byte_offset(), &mut buf)?;
+
+        Ok(buf)
+    }
+    /// Finds `count` consecutive free slots in `parent`, extending the directory with new clusters if there's no such run
+    pub(crate) fn free_entry_locations(&self, parent: &FatDirectory, count: usize) -> Fat32Result<Vec<EntryLocation>> {
+        let entries_per_cluster = self.bytes_per_cluster() / FAT32_DIR_SIZE;
+        let mut cluster = self.directory_cluster(parent);
+        let mut run = vec![];
+
+        let mut buf = [0; FAT32_DIR_SIZE];
+        loop {
+            for index in 0..entries_per_cluster {
+                let location = EntryLocation { cluster, index };
+                self.read_cluster(cluster, location.byte_offset(), &mut buf)?;
+
+                if buf[0] == 0 || buf[0] == DIR_ENTRY_FREE {
+                    run.push(location);
+                    if run.len() == count {
+                        return Ok(run);
+                    }
+                } else {
+                    run.clear();
+                }
+            }
+
+            match self.read_fat(cluster)? {
+                Some(next_cluster) if self.is_valid_cluster(next_cluster) => cluster = next_cluster,
+                Some(next_cluster) => return Err(Fat32Error::BadCluster(next_cluster as u32)),
+                None => {
+                    // A run of free slots at the end carries on into the new clusters
+                    let n_clusters = (count - run.len()).div_ceil(entries_per_cluster);
+
+                    for extent in self.extend(cluster, n_clusters, AllocationStrategy::NextFit)? {
+                        for new_cluster in extent.clusters() {
+                            self.zero_cluster(new_cluster)?;
+                            run.extend((0..entries_per_cluster).map(|index| EntryLocation { cluster: new_cluster, index }));
+                        }
+                    }
+                    run.truncate(count);
+
+                    return Ok(run);
+                }
+            }
+        }
+    }
+    /// Adds a short name only entry to `parent`
+    pub(crate) fn create_entry(&self, parent: &FatDirectory, entry: FatEntry) -> Fat32Result<FatDirectory> {
+        let location = self.free_entry_locations(parent, 1)?[0];
+
+        let mut directory = FatDirectory::new(entry, &[]);
+        directory.set_location(location);
+
+        self.write_entry(&directory)?;
+        self.dentries.lock().invalidate_dir(self.directory_cluster(parent));
+
+        Ok(directory)
+    }
+    /// Allocates the first cluster of a new directory in `parent` and writes its `.` and `..` entries
+    pub(crate) fn new_dir_cluster(&self, parent: &FatDirectory) -> Fat32Result<usize> {
+        let cluster = self.allocate(1, AllocationStrategy::NextFit)?[0].start;
+        self.zero_cluster(cluster)?;
+
+        let current = FatEntry::new(*b".          ", DIR_ATTR_DIRECTORY, cluster, 0);
+        let parent_entry = FatEntry::new(*b"..         ", DIR_ATTR_DIRECTORY, Self::dotdot_cluster(parent), 0);
+
+        self.write_cluster(cluster, 0, &current.to_bytes())?;
+        self.write_cluster(cluster, FAT32_DIR_SIZE, &parent_entry.to_bytes())?;
+
+        Ok(cluster)
+    }
+    /// What `..` entries store to point at `parent`
+    pub(crate) fn dotdot_cluster(parent: &FatDirectory) -> usize {
+        if parent.location().is_none() { 0 } else { parent.cluster_num() }
+    }
+    /// Creates an empty directory in `parent`, including its `.` and `..` entries
+    pub(crate) fn create_dir(&self, parent: &FatDirectory, name: [u8; 11]) -> Fat32Result<FatDirectory> {
+        let cluster = self.new_dir_cluster(parent)?;
+
+        self.create_entry(parent, FatEntry::new(name, DIR_ATTR_DIRECTORY, cluster, 0))
+    }
+    /// First cluster of a directory, where `..` entries pointing to the root store 0
+    pub(crate) fn directory_cluster(&self, directory: &FatDirectory) -> usize {
+        match directory.cluster_num() {
+            0 => self.bpb.bpb_root_clus as usize,
+            cluster => cluster
+        }
+    }
+    /// Looks `name` up in `directory`, ignoring case and matching the long or the 8.3 name, like Windows does.
+    /// An exact match wins over one that only differs in case.
     pub fn search(&self, directory: &FatDirectory, name: &OsStr) -> Fat32Result<FatDirectory> {
+        let directory_cluster = self.directory_cluster(directory);
+
+        let generation = {
+            let mut dentries = self.dentries.lock();
+            if let Some(cached) = dentries.get(directory_cluster, name) {
+                return cached.ok_or(Fat32Error::NotFound);
+            }
+
+            dentries.generation()
+        };
+
+        let wanted = name.to_string_lossy().to_lowercase();
+
+        let mut found = None;
         let mut files = self.files(directory);
         while let Some(file) = files.next()? {
+            if file.is_deleted() || file.is_volume_id() {
+                continue;
+            }
+
             if file.name() == name {
-                return Ok(file);
+                found = Some(file);
+                break;
+            }
+
+            let matches = file.name().to_string_lossy().to_lowercase() == wanted
+            || file.entry().short_name().to_string_lossy().to_lowercase() == wanted;
+
+            if matches && found.is_none() {
+                found = Some(file);
             }
         }
 
-        Err(Fat32Error::NotFound)
+        self.dentries.lock().insert(generation, directory_cluster, name, found.clone());
+
+        found.ok_or(Fat32Error::NotFound)
+    }
+    /// Forgets every cached lookup, for when directories were changed behind the cache's back
+    pub fn invalidate_dentries(&self) {
+        self.dentries.lock().clear();
     }
     pub fn search_by_path(&self, path: &Path) -> Fat32Result<FatDirectory> {
         let mut components = path.components();
 
-        let mut current_directory = FatDirectory::root(self);
+        let mut directories = vec![FatDirectory::root(self)];
 
         // First component should always be root (/)
         components.next().unwrap();
@@ -89,53 +345,101 @@ impl Driver {
         for component in components {
             match component {
                 Component::Normal(name) => {
-                    let result = self.search(&current_directory, name);
-                    current_directory = result?;
+                    let result = self.search(directories.last().unwrap(), name);
+                    directories.push(result?);
                 }
-                _=> unreachable!()
+                Component::ParentDir if directories.len() > 1 => {
+                    directories.pop();
+                }
+                _=> {}
             }
         }
 
-        Ok(current_directory)
+        Ok(directories.pop().unwrap())
     }
-    pub fn open_dir(&self, path: &Path) -> Fat32Result<FileHandle> {
-        let directory = self.search_by_path(path)?;
-        let mut file_state = self.file_state.write();
+    /// Changes the read-only, hidden, system and archive attributes of the entry at `path`
+    pub fn set_attributes(&self, path: &Path, attr: u8) -> Fat32Result<FatDirectory> {
+        let mut file = self.search_by_path(path)?;
+
+        let fixed = file.attributes() & !DIR_ATTR_CHANGEABLE;
+        if file.location().is_none() || attr & !DIR_ATTR_CHANGEABLE != fixed {
+            return Err(Fat32Error::InvalidAttributes(attr));
+        }
 
-        let handle = file_state.open_dir(&directory)?;
+        file.set_attributes(attr);
+        self.write_entry(&file)?;
+        self.drive.sync()?;
 
-        Ok(handle)
+        Ok(file)
+    }
+    pub fn open_dir(&self, path: &Path) -> Fat32Result<FileHandle> {
+        let directory = self.search_by_path(path)?;
+        self.file_state.open_dir(path, &directory)
     }
     pub fn read_dir(&self, handle: FileHandle, offset: usize) -> Fat32Result<Option<FatDirectory>>{
-        let mut file_state = self.file_state.write();
-
-        file_state.read_dir(self, handle, offset)
+        self.file_state.read_dir(self, handle, offset)
     }
     pub fn close_dir(&self, handle: FileHandle) -> Fat32Result<()> {
-        let mut file_state = self.file_state.write();
-        file_state.close_dir(handle)
+        self.file_state.close_dir(handle)
     }
     pub fn open(&self, path: &Path) -> Fat32Result<FileHandle> {
         let file = self.search_by_path(path)?;
-        let mut file_state = self.file_state.write();
+        self.file_state.open(path, &file)
+    }
+    /// Opens `path` as a file that implements `Read` and `Seek`
+    pub fn open_file(&self, path: &Path) -> Fat32Result<FatFile<'_>> {
+        let file = self.search_by_path(path)?;
+        let handle = self.file_state.open(path, &file)?;
+
+        Ok(FatFile::new(self, handle, file.file_size() as u64))
+    }
+    /// Opens `path` as a file that implements `Write` as well. Writes go straight to the volume, growing the file
+    /// as needed, and the FSInfo hints are written back on `flush` or when the file is dropped
+    pub fn open_file_mut(&self, path: &Path) -> Fat32Result<FatFile<'_>> {
+        let file = self.search_by_path(path)?;
+        if file.attributes() & DIR_ATTR_READ_ONLY != 0 {
+            return Err(Fat32Error::ReadOnly);
+        }
 
-        let handle = file_state.open(&file)?;
+        let clusters = match file.cluster_num() {
+            0 => vec![],
+            cluster => self.chain(cluster)?,
+        };
+        let handle = self.file_state.open(path, &file)?;
 
-        Ok(handle)
+        Ok(FatFile::new(self, handle, file.file_size() as u64).writable(file, clusters))
     }
-    pub fn close(&self, handle: FileHandle) -> Fat32Result<()> {
-        let mut file_state = self.file_state.write();
+    /// Reads all of `path` into memory, like `std::fs::read`
+    pub fn read_to_end(&self, path: &Path) -> Fat32Result<Vec<u8>> {
+        let mut file = self.open_file(path)?;
+        let mut contents = Vec::with_capacity(file.len() as usize);
 
-        file_state.close(handle)
+        file.read_to_end(&mut contents).map_err(Fat32Error::IOError)?;
+
+        Ok(contents)
+    }
+    pub fn close(&self, handle: FileHandle) -> Fat32Result<()> {
+        self.file_state.close(handle)
     }
     pub fn read(&self, handle: FileHandle, buffer: &mut [u8], byte_offset: usize) -> Fat32Result<usize> {
-        let file_state = self.file_state.read();
-
-        file_state.read(self, handle, buffer, byte_offset)
+        self.file_state.read(self, handle, buffer, byte_offset)
+    }
+    /// Has reads through `handle` see the file as `file` describes it, after it was written to
+    pub(crate) fn update_open_file(&self, handle: FileHandle, file: &FatDirectory) -> Fat32Result<()> {
+        self.file_state.update(handle, file)
+    }
+    /// Lists the files and directories that are open, for tracking down leaked handles
+    pub fn open_handles(&self) -> Vec<OpenHandle> {
+        self.file_state.open_handles()
     }
 }
 
 
+/// Value written to mark the end of a cluster chain
+pub const FAT_EOC: u32 = 0x0FFFFFFF;
+/// Value of a FAT entry for a free cluster
+pub const FAT_FREE: u32 = 0;
+
 pub fn fat_is_eoc(value: u32) -> bool {
     value >= 0x0FFFFFF8 && value <= 0x0FFFFFFF
 }
@@ -19,7 +19,54 @@ pub enum Fat32Error {
     #[error("File is not a directory")]
     NotADir,
     #[error("Invalid file handle {0}")]
-    InvalidFileHandle(FileHandle)
+    InvalidFileHandle(FileHandle),
+    #[error("No space left on volume")]
+    NoSpace,
+    #[error("Volume has consistency errors, run fsck first")]
+    Inconsistent,
+    #[error("Invalid partition table: {0}")]
+    InvalidPartitionTable(&'static str),
+    #[error("Invalid attributes {0:#04x}")]
+    InvalidAttributes(u8),
+    #[error("Invalid name: {0}")]
+    InvalidName(String),
+    #[error("File/Directory already exists")]
+    AlreadyExists,
+    #[error("Directory is not empty")]
+    DirectoryNotEmpty,
+    #[error("File is larger than 4 GiB - 1")]
+    FileTooLarge,
+    #[error("Cluster {0} is already in use")]
+    ClusterInUse(u32),
+    #[error("File is read-only")]
+    ReadOnly,
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(&'static str),
 }
 
-pub type Fat32Result<T> = Result<T, Fat32Error>;
\ No newline at end of file
+pub type Fat32Result<T> = Result<T, Fat32Error>;
+
+impl From<Fat32Error> for std::io::Error {
+    fn from(error: Fat32Error) -> Self {
+        use std::io::ErrorKind;
+
+        if let Fat32Error::IOError(inner) = error {
+            return inner;
+        }
+
+        let kind = match &error {
+            Fat32Error::NotFound => ErrorKind::NotFound,
+            Fat32Error::IsDir => ErrorKind::IsADirectory,
+            Fat32Error::NotADir => ErrorKind::NotADirectory,
+            Fat32Error::NoSpace => ErrorKind::StorageFull,
+            Fat32Error::InvalidAttributes(_) | Fat32Error::InvalidName(_) | Fat32Error::InvalidArgument(_) => ErrorKind::InvalidInput,
+            Fat32Error::AlreadyExists => ErrorKind::AlreadyExists,
+            Fat32Error::DirectoryNotEmpty => ErrorKind::DirectoryNotEmpty,
+            Fat32Error::FileTooLarge => ErrorKind::FileTooLarge,
+            Fat32Error::ReadOnly => ErrorKind::PermissionDenied,
+            _=> ErrorKind::InvalidData,
+        };
+
+        std::io::Error::new(kind, error)
+    }
+}
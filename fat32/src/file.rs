@@ -1,17 +1,35 @@
 use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
 
 use crate::{Driver, Fat32Error, Fat32Result, FatDirectory};
 
+/// The slot of an open file or directory in the low 32 bits, and the generation of the slot in the high 32 bits.
+/// Slots are reused once closed, the generation tells a stale handle apart from the one now in the slot.
 pub type FileHandle = u64;
+
+fn handle_of(slot: u32, generation: u32) -> FileHandle {
+    (generation as u64) << 32 | slot as u64
+}
+fn slot_of(handle: FileHandle) -> u32 {
+    handle as u32
+}
+
 pub struct File {
+    path: PathBuf,
     directory: FatDirectory,
 }
 
 impl File {
-    pub fn new(directory: FatDirectory) -> Fat32Result<Self> {
+    pub fn new(path: &Path, directory: FatDirectory) -> Fat32Result<Self> {
         assert!(directory.is_file());
         Ok(
         Self {
+            path: path.to_owned(),
             directory,
         }
         )
@@ -74,64 +92,215 @@ impl File {
     }
 }
 
+/// What a file opened for writing keeps track of
+struct Writer {
+    file: FatDirectory,
+    /// The cluster chain of the file, so writes don't have to follow it through the FAT each time
+    clusters: Vec<usize>,
+}
+
+/// An open file, read and, if opened with `Driver::open_file_mut`, written through `std::io` like a `std::fs::File`.
+/// The handle is closed when it's dropped.
+pub struct FatFile<'d> {
+    driver: &'d Driver,
+    handle: FileHandle,
+    size: u64,
+    position: u64,
+    writer: Option<Writer>,
+}
+
+impl<'d> FatFile<'d> {
+    pub(crate) fn new(driver: &'d Driver, handle: FileHandle, size: u64) -> Self {
+        Self {
+            driver,
+            handle,
+            size,
+            position: 0,
+            writer: None,
+        }
+    }
+    pub(crate) fn writable(mut self, file: FatDirectory, clusters: Vec<usize>) -> Self {
+        self.writer = Some(Writer { file, clusters });
+        self
+    }
+    pub fn len(&self) -> u64 {
+        self.size
+    }
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+}
+
+impl Read for FatFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.size {
+            return Ok(0);
+        }
+
+        let nbytes = self.driver.read(self.handle, buf, self.position as usize)?;
+        self.position += nbytes as u64;
+
+        Ok(nbytes)
+    }
+}
+
+impl Write for FatFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(writer) = &mut self.writer else {
+            return Err(std::io::Error::new(ErrorKind::PermissionDenied, "file wasn't opened for writing"));
+        };
+
+        let nbytes = self.driver.write_file_at(&mut writer.file, &mut writer.clusters, self.position, buf)?;
+        self.driver.update_open_file(self.handle, &writer.file)?;
+
+        self.position += nbytes as u64;
+        self.size = writer.file.file_size() as u64;
+
+        Ok(nbytes)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.writer.is_some() {
+            self.driver.sync()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Seek for FatFile<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        // Like std::fs::File, seeking past the end is fine but before the start isn't
+        let Some(position) = position else {
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"));
+        };
+        self.position = position;
+
+        Ok(position)
+    }
+}
+
+impl Drop for FatFile<'_> {
+    fn drop(&mut self) {
+        if let Err(error) = self.flush() {
+            log::warn!("Flushing file handle {:#x} failed: {}", self.handle, error);
+        }
+        if let Err(error) = self.driver.close(self.handle) {
+            log::warn!("Closing file handle {:#x} failed: {}", self.handle, error);
+        }
+    }
+}
 
 struct DirectoryState {
+    path: PathBuf,
     directory: FatDirectory,
     files_cached: Vec<FatDirectory>
 }
 impl DirectoryState {
-    pub fn new(directory: FatDirectory) -> Self {
+    pub fn new(path: &Path, directory: FatDirectory) -> Self {
         Self {
+            path: path.to_owned(),
             directory,
             files_cached: vec![]
         }
     }
 }
+struct HandleAllocator {
+    /// Current generation of each slot, slot n is at n - 1 so that no handle is 0
+    generations: Vec<u32>,
+    free_list: Vec<u32>,
+}
+/// An open file or directory, for debugging
+#[derive(Clone, Debug, Serialize)]
+pub struct OpenHandle {
+    pub handle: FileHandle,
+    pub path: PathBuf,
+    pub is_dir: bool,
+}
+/// Open files and directories. Each table has its own lock, held just long enough to find a handle,
+/// so reads of different files, and of different directories, don't wait on each other.
 pub struct FileState {
-    files: HashMap<FileHandle, File>,
-    dirs: HashMap<FileHandle, DirectoryState>,
-    next_file_handle: FileHandle,
-    free_list: Vec<FileHandle>,
+    files: RwLock<HashMap<FileHandle, Arc<File>>>,
+    dirs: RwLock<HashMap<FileHandle, Arc<Mutex<DirectoryState>>>>,
+    handles: Mutex<HandleAllocator>,
 }
 
 impl FileState {
     pub fn new() -> Self {
         Self {
-            files: HashMap::new(),
-            dirs: HashMap::new(),
-            next_file_handle: 0,
-            free_list: Vec::new(),
+            files: RwLock::new(HashMap::new()),
+            dirs: RwLock::new(HashMap::new()),
+            handles: Mutex::new(HandleAllocator {
+                generations: Vec::new(),
+                free_list: Vec::new(),
+            }),
         }
     }
-    fn alloc_handle(&mut self) -> FileHandle {
-        if let Some(existing) = self.free_list.pop() {
-            return existing;
-        }
+    fn alloc_handle(&self) -> FileHandle {
+        let mut handles = self.handles.lock();
+
+        let slot = match handles.free_list.pop() {
+            Some(existing) => existing,
+            None => {
+                handles.generations.push(0);
+                handles.generations.len() as u32
+            }
+        };
 
-        self.next_file_handle += 1;
+        handle_of(slot, handles.generations[slot as usize - 1])
+    }
+    fn dealloc_handle(&self, handle: FileHandle) {
+        let mut handles = self.handles.lock();
+        let slot = slot_of(handle);
 
-        self.next_file_handle
+        // Invalidates every handle given out for the slot so far
+        let generation = &mut handles.generations[slot as usize - 1];
+        *generation = generation.wrapping_add(1);
+
+        handles.free_list.push(slot);
     }
-    fn dealloc_handle(&mut self, handle: FileHandle) {
-        self.free_list.push(handle);
+    /// Every handle that's currently open, sorted by handle
+    pub fn open_handles(&self) -> Vec<OpenHandle> {
+        let files = self.files.read().iter().map(|(handle, file)| OpenHandle {
+            handle: *handle,
+            path: file.path.clone(),
+            is_dir: false,
+        }).collect::<Vec<_>>();
+
+        let dirs = self.dirs.read().iter().map(|(handle, dir_state)| OpenHandle {
+            handle: *handle,
+            path: dir_state.lock().path.clone(),
+            is_dir: true,
+        }).collect::<Vec<_>>();
+
+        let mut handles = [files, dirs].concat();
+        handles.sort_by_key(|open| open.handle);
+
+        handles
     }
 
-    pub fn open_dir(&mut self, directory: &FatDirectory) -> Fat32Result<FileHandle> {
+    pub fn open_dir(&self, path: &Path, directory: &FatDirectory) -> Fat32Result<FileHandle> {
         if !directory.is_dir() {
             return Err(Fat32Error::NotADir);
         }
 
         let handle = self.alloc_handle();
         
-        self.dirs.insert(handle, DirectoryState::new(directory.clone()));
+        self.dirs.write().insert(handle, Arc::new(Mutex::new(DirectoryState::new(path, directory.clone()))));
 
         Ok(handle)
     }
-    fn get_dir_state(&mut self, handle: FileHandle) -> Fat32Result<&mut DirectoryState> {
-        self.dirs.get_mut(&handle).ok_or(Fat32Error::InvalidFileHandle(handle))
+    fn get_dir_state(&self, handle: FileHandle) -> Fat32Result<Arc<Mutex<DirectoryState>>> {
+        self.dirs.read().get(&handle).cloned().ok_or(Fat32Error::InvalidFileHandle(handle))
     }
-    pub fn read_dir(&mut self, driver: &Driver, handle: FileHandle, offset: usize) -> Fat32Result<Option<FatDirectory>> {
+    pub fn read_dir(&self, driver: &Driver, handle: FileHandle, offset: usize) -> Fat32Result<Option<FatDirectory>> {
         let dir_state = self.get_dir_state(handle)?;
+        let mut dir_state = dir_state.lock();
         
         if dir_state.files_cached.is_empty() {
             let mut files = driver.files(&dir_state.directory);
@@ -142,8 +311,8 @@ impl FileState {
 
         Ok(dir_state.files_cached.get(offset).cloned())
     }
-    pub fn close_dir(&mut self, handle: FileHandle) -> Fat32Result<()> {
-        if self.dirs.remove(&handle).is_none() {
+    pub fn close_dir(&self, handle: FileHandle) -> Fat32Result<()> {
+        if self.dirs.write().remove(&handle).is_none() {
             return Err(Fat32Error::InvalidFileHandle(handle));
         }
 
@@ -151,20 +320,20 @@ impl FileState {
 
         Ok(())
     }
-    pub fn open(&mut self, file: &FatDirectory) -> Fat32Result<FileHandle> {
+    pub fn open(&self, path: &Path, file: &FatDirectory) -> Fat32Result<FileHandle> {
         if file.is_dir() {
             return Err(Fat32Error::IsDir);
         }
 
         let handle = self.alloc_handle();
 
-        let opened_file = File::new(file.clone())?;
-        self.files.insert(handle, opened_file);
+        let opened_file = File::new(path, file.clone())?;
+        self.files.write().insert(handle, Arc::new(opened_file));
 
         Ok(handle)
     }
-    pub fn close(&mut self, handle: FileHandle) -> Fat32Result<()> {
-        if self.files.remove(&handle).is_none() {
+    pub fn close(&self, handle: FileHandle) -> Fat32Result<()> {
+        if self.files.write().remove(&handle).is_none() {
             return Err(Fat32Error::InvalidFileHandle(handle));
         }
 
@@ -173,7 +342,15 @@ impl FileState {
         Ok(())
     }
     pub fn read(&self, driver: &Driver, handle: FileHandle, buffer: &mut [u8], byte_offset: usize) -> Fat32Result<usize> {
-        let file = self.files.get(&handle).ok_or(Fat32Error::InvalidFileHandle(handle))?;
+        let file = self.files.read().get(&handle).cloned().ok_or(Fat32Error::InvalidFileHandle(handle))?;
         file.read(driver, byte_offset, buffer)
     }
-}
\ No newline at end of file
+    pub(crate) fn update(&self, handle: FileHandle, directory: &FatDirectory) -> Fat32Result<()> {
+        let mut files = self.files.write();
+        let file = files.get_mut(&handle).ok_or(Fat32Error::InvalidFileHandle(handle))?;
+
+        *file = Arc::new(File::new(&file.path, directory.clone())?);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::{Drive, Driver, Fat32Error, Fat32Result, FatDirectory, FatFile, Files};
+
+/// A `std::fs` like view of a volume, for when raw entries and cluster chains aren't needed
+pub struct FatFs {
+    driver: Driver,
+}
+
+impl FatFs {
+    pub fn new(driver: Driver) -> Self {
+        Self {
+            driver
+        }
+    }
+    pub fn from_drive(drive: Drive) -> Fat32Result<Self> {
+        Ok(Self::new(Driver::new(drive)?))
+    }
+    pub fn driver(&self) -> &Driver {
+        &self.driver
+    }
+    pub fn into_driver(self) -> Driver {
+        self.driver
+    }
+    /// Lists a directory, without `.`, `..`, deleted entries and the volume label
+    pub fn read_dir<P: AsRef<Path>>(&self, path: P) -> Fat32Result<ReadDir<'_>> {
+        let path = path.as_ref();
+        let directory = self.driver.search_by_path(path)?;
+
+        ReadDir::new(&self.driver, path, &directory)
+    }
+    pub fn metadata<P: AsRef<Path>>(&self, path: P) -> Fat32Result<Metadata> {
+        let directory = self.driver.search_by_path(path.as_ref())?;
+
+        Ok(Metadata::from(&directory))
+    }
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Fat32Result<FatFile<'_>> {
+        self.driver.open_file(path.as_ref())
+    }
+    pub fn read<P: AsRef<Path>>(&self, path: P) -> Fat32Result<Vec<u8>> {
+        self.driver.read_to_end(path.as_ref())
+    }
+    pub fn read_to_string<P: AsRef<Path>>(&self, path: P) -> Fat32Result<String> {
+        let contents = self.read(path)?;
+
+        String::from_utf8(contents).map_err(|error| {
+            Fat32Error::IOError(std::io::Error::new(std::io::ErrorKind::InvalidData, error))
+        })
+    }
+    /// Like `Path::exists`, errors other than the path missing count as it existing
+    pub fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        !matches!(self.driver.search_by_path(path.as_ref()), Err(Fat32Error::NotFound))
+    }
+    /// Resolves `.` and `..` and matches every name the way `Driver::search` does.
+    /// Returns the path spelled as it's stored.
+    pub fn canonicalize<P: AsRef<Path>>(&self, path: P) -> Fat32Result<PathBuf> {
+        let mut canonical = PathBuf::from("/");
+        let mut directories = vec![FatDirectory::root(&self.driver)];
+
+        for component in path.as_ref().components() {
+            let current = directories.last().unwrap();
+
+            match component {
+                Component::RootDir | Component::CurDir => {},
+                Component::ParentDir => {
+                    if directories.len() > 1 {
+                        directories.pop();
+                        canonical.pop();
+                    }
+                },
+                Component::Normal(name) => {
+                    if !current.is_dir() {
+                        return Err(Fat32Error::NotADir);
+                    }
+
+                    let found = self.driver.search(current, name)?;
+
+                    canonical.push(found.name());
+                    directories.push(found);
+                },
+                Component::Prefix(_) => return Err(Fat32Error::NotFound),
+            }
+        }
+
+        Ok(canonical)
+    }
+    /// Walks the tree under `path` depth first, parents before their children
+    pub fn walk<P: AsRef<Path>>(&self, path: P) -> Walk<'_> {
+        Walk::new(self, path.as_ref())
+    }
+}
+
+pub(crate) fn is_listed(directory: &FatDirectory) -> bool {
+    !(directory.is_deleted() || directory.is_volume_id() || directory.is_current_dir() || directory.is_parent_dir())
+}
+
+/// Whether `name` is a single path component that can be joined to a path safely. A corrupt or crafted entry
+/// can hold a `/`, a NUL or be `..`, which would point outside the directory it's in
+pub fn is_plain_name(name: &OsStr) -> bool {
+    let mut components = Path::new(name).components();
+
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(component)), None) => component == name && !name.as_encoded_bytes().contains(&0),
+        _=> false,
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Metadata {
+    pub len: u64,
+    pub is_dir: bool,
+    /// Attribute byte of the entry, see the DIR_ATTR_* constants
+    pub attributes: u8,
+    /// 0 for empty files
+    pub first_cluster: usize,
+    pub created: SystemTime,
+    pub modified: SystemTime,
+    /// FAT only stores the date of the last access
+    pub accessed: SystemTime,
+}
+
+impl Metadata {
+    pub fn is_file(&self) -> bool {
+        !self.is_dir
+    }
+}
+
+impl From<&FatDirectory> for Metadata {
+    fn from(directory: &FatDirectory) -> Self {
+        Self {
+            len: directory.file_size() as u64,
+            is_dir: directory.is_dir(),
+            attributes: directory.attributes(),
+            first_cluster: directory.cluster_num(),
+            created: directory.create_time(),
+            modified: directory.write_time(),
+            accessed: directory.access_time(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DirEntry {
+    path: PathBuf,
+    directory: FatDirectory,
+    depth: usize,
+}
+
+impl std::fmt::Debug for DirEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DirEntry").field("path", &self.path).finish()
+    }
+}
+
+impl DirEntry {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn file_name(&self) -> OsString {
+        self.directory.name().to_owned()
+    }
+    pub fn metadata(&self) -> Metadata {
+        Metadata::from(&self.directory)
+    }
+    pub fn is_dir(&self) -> bool {
+        self.directory.is_dir()
+    }
+    /// The raw entry, for whatever the facade doesn't cover
+    pub fn directory(&self) -> &FatDirectory {
+        &self.directory
+    }
+    /// How far below the start of a walk the entry is, 1 for its children. Always 1 for `read_dir`.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+}
+
+pub struct ReadDir<'d> {
+    files: Files<'d>,
+    path: PathBuf,
+    depth: usize,
+    failed: bool,
+}
+
+impl<'d> ReadDir<'d> {
+    fn new(driver: &'d Driver, path: &Path, directory: &FatDirectory) -> Fat32Result<Self> {
+        if !directory.is_dir() {
+            return Err(Fat32Error::NotADir);
+        }
+
+        Ok(Self {
+            files: driver.files(directory),
+            path: path.to_owned(),
+            depth: 1,
+            failed: false,
+        })
+    }
+}
+
+impl Iterator for ReadDir<'_> {
+    type Item = Fat32Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // A broken chain would keep failing, stop after reporting it once
+        if self.failed {
+            return None;
+        }
+
+        loop {
+            let directory = match self.files.next() {
+                Ok(Some(directory)) => directory,
+                Ok(None) => return None,
+                Err(error) => {
+                    self.failed = true;
+                    return Some(Err(error));
+                }
+            };
+
+            if is_listed(&directory) {
+                if !is_plain_name(directory.name()) {
+                    return Some(Err(Fat32Error::InvalidName(directory.name().to_string_lossy().into_owned())));
+                }
+
+                return Some(Ok(DirEntry {
+                    path: self.path.join(directory.name()),
+                    directory,
+                    depth: self.depth,
+                }));
+            }
+        }
+    }
+}
+
+type EntryFilter<'d> = Box<dyn FnMut(&DirEntry) -> bool + 'd>;
+
+/// Recursive iterator returned by `FatFs::walk`. Errors are yielded and the walk goes on with the next directory.
+pub struct Walk<'d> {
+    fs: &'d FatFs,
+    start: Option<PathBuf>,
+    stack: Vec<ReadDir<'d>>,
+    max_depth: usize,
+    filter: Option<EntryFilter<'d>>,
+}
+
+impl<'d> Walk<'d> {
+    fn new(fs: &'d FatFs, path: &Path) -> Self {
+        Self {
+            fs,
+            start: Some(path.to_owned()),
+            stack: vec![],
+            max_depth: usize::MAX,
+            filter: None,
+        }
+    }
+    /// Don't go further than `depth` levels below the start
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+    /// Skips entries `predicate` returns false for. Skipped directories aren't descended into.
+    pub fn filter_entry<F>(mut self, predicate: F) -> Self
+    where F: FnMut(&DirEntry) -> bool + 'd {
+        self.filter = Some(Box::new(predicate));
+        self
+    }
+}
+
+impl Iterator for Walk<'_> {
+    type Item = Fat32Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(start) = self.start.take() {
+            match self.fs.read_dir(&start) {
+                Ok(read_dir) => self.stack.push(read_dir),
+                Err(error) => return Some(Err(error)),
+            }
+        }
+
+        loop {
+            let read_dir = self.stack.last_mut()?;
+
+            let entry = match read_dir.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(error)) => return Some(Err(error)),
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+
+            if let Some(filter) = &mut self.filter {
+                if !filter(&entry) {
+                    continue;
+                }
+            }
+
+            if entry.is_dir() && entry.depth < self.max_depth {
+                match ReadDir::new(&self.fs.driver, &entry.path, &entry.directory) {
+                    Ok(mut children) => {
+                        children.depth = entry.depth + 1;
+                        self.stack.push(children);
+                    },
+                    Err(error) => return Some(Err(error)),
+                }
+            }
+
+            return Some(Ok(entry));
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::io::{Cursor, Read};
+
+use crate::util::read_bytes;
+use crate::{Driver, Fat32Error, Fat32Result};
+
+pub const FSI_LEAD_SIG: u32 = 0x41615252;
+pub const FSI_STRUC_SIG: u32 = 0x61417272;
+pub const FSI_TRAIL_SIG: u32 = 0xAA550000;
+/// FSI_Free_Count and FSI_Nxt_Free are set to this value when unknown
+pub const FSI_UNKNOWN: u32 = 0xFFFFFFFF;
+
+const FSI_STRUC_SIG_OFFSET: usize = 484;
+const FSI_TRAIL_SIG_OFFSET: usize = 508;
+
+#[derive(Clone, Debug)]
+pub struct FSInfo {
+    /// FSI_LeadSig
+    pub fsi_lead_sig: u32,
+    /// FSI_StrucSig
+    pub fsi_struc_sig: u32,
+    /// FSI_Free_Count
+    pub fsi_free_count: u32,
+    /// FSI_Nxt_Free
+    pub fsi_nxt_free: u32,
+    /// FSI_TrailSig
+    pub fsi_trail_sig: u32,
+}
+
+impl FSInfo {
+    pub fn read_from(driver: &Driver) -> Fat32Result<Self> {
+        let mut buf = [0u8; 512];
+        driver.read_sector(driver.bpb.bpb_fs_info as usize, 0, &mut buf)?;
+
+        let mut reader = Cursor::new(&buf[..]);
+        let fsi_lead_sig = read_bytes!(u32, reader)?;
+
+        reader.set_position(FSI_STRUC_SIG_OFFSET as u64);
+        let fsi_struc_sig = read_bytes!(u32, reader)?;
+        let fsi_free_count = read_bytes!(u32, reader)?;
+        let fsi_nxt_free = read_bytes!(u32, reader)?;
+
+        reader.set_position(FSI_TRAIL_SIG_OFFSET as u64);
+        let fsi_trail_sig = read_bytes!(u32, reader)?;
+
+        Ok(Self {
+            fsi_lead_sig,
+            fsi_struc_sig,
+            fsi_free_count,
+            fsi_nxt_free,
+            fsi_trail_sig,
+        })
+    }
+    /// Writes the free count and next free hint back, leaving the rest of the sector alone
+    pub(crate) fn write_to(&self, driver: &Driver) -> Fat32Result<()> {
+        let mut buf = [0u8; 8];
+        buf[0..4].copy_from_slice(&self.fsi_free_count.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.fsi_nxt_free.to_le_bytes());
+
+        driver.write_sector(driver.bpb.bpb_fs_info as usize, FSI_STRUC_SIG_OFFSET + 4, &buf)
+    }
+    pub fn is_valid(&self) -> bool {
+        self.fsi_lead_sig == FSI_LEAD_SIG && self.fsi_struc_sig == FSI_STRUC_SIG && self.fsi_trail_sig == FSI_TRAIL_SIG
+    }
+    /// Last known free cluster count, which is only a hint
+    pub fn free_count(&self) -> Option<u32> {
+        (self.is_valid() && self.fsi_free_count != FSI_UNKNOWN).then_some(self.fsi_free_count)
+    }
+    /// Cluster number at which to start looking for free clusters, which is only a hint
+    pub fn next_free(&self) -> Option<u32> {
+        (self.is_valid() && self.fsi_nxt_free != FSI_UNKNOWN).then_some(self.fsi_nxt_free)
+    }
+}
@@ -0,0 +1,531 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::directory::name_checksum;
+use crate::{fat_is_bad, fat_is_eoc, fat_is_free, fat_is_reserved, Driver, EntryLocation, Fat32Result, FatDirectory, FatFs, FSInfo, DIR_ATTR_LONG_FILE_NAME, DIR_ENTRY_FREE, FAT32_DIR_SIZE, LAST_LONG_ENTRY};
+
+/// FAT type by cluster count, the only thing that decides it according to the specification
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    pub fn from_cluster_count(cluster_count: usize) -> Self {
+        match cluster_count {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _=> FatType::Fat32,
+        }
+    }
+}
+
+/// The fields of the boot sector, with the byte strings decoded
+#[derive(Clone, Debug, Serialize)]
+pub struct BootSector {
+    pub jmp_boot: [u8; 3],
+    pub oem_name: String,
+    pub bytes_per_sector: u16,
+    pub sectors_per_cluster: u8,
+    pub reserved_sectors: u16,
+    pub fat_count: u8,
+    pub root_entry_count: u16,
+    pub total_sectors_16: u16,
+    pub media: u8,
+    pub fat_size_16: u16,
+    pub sectors_per_track: u16,
+    pub heads: u16,
+    pub hidden_sectors: u32,
+    pub total_sectors_32: u32,
+    pub fat_size_32: u32,
+    pub ext_flags: u16,
+    pub fs_version: u16,
+    pub root_cluster: u32,
+    pub fs_info_sector: u16,
+    pub backup_boot_sector: u16,
+    pub drive_number: u8,
+    pub boot_signature: u8,
+    pub volume_id: u32,
+    pub volume_label: String,
+    pub fs_type: String,
+    pub signature: u16,
+}
+
+/// What follows from the boot sector
+#[derive(Clone, Debug, Serialize)]
+pub struct Geometry {
+    pub fat_type: FatType,
+    pub bytes_per_cluster: usize,
+    pub fat_start_sector: usize,
+    /// Sectors taken by all copies of the FAT
+    pub fat_sectors: usize,
+    pub data_start_sector: usize,
+    pub data_sectors: usize,
+    pub cluster_count: usize,
+    /// FAT entries that map clusters beyond the data region, which must stay unused
+    pub unused_fat_entries: usize,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FsInfoReport {
+    pub sector: u16,
+    pub lead_signature: u32,
+    pub struct_signature: u32,
+    pub trail_signature: u32,
+    pub valid: bool,
+    /// None if unknown
+    pub free_count: Option<u32>,
+    /// None if unknown
+    pub next_free: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct FatUsage {
+    pub free: usize,
+    pub used: usize,
+    pub bad: usize,
+    /// Entries holding the reserved value 1 or pointing outside the data region
+    pub invalid: usize,
+    /// Number of chains that end properly, i.e. end of chain markers
+    pub chain_ends: usize,
+    /// For every backup copy of the FAT, how many entries differ from the primary one
+    pub mismatched_copies: Vec<usize>,
+}
+
+/// What a raw 32 byte directory slot holds
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RawEntryKind {
+    LongName {
+        /// Sequence number, without the last entry flag. Gone once the slot is deleted
+        ord: Option<u8>,
+        last: bool,
+        checksum: u8,
+        /// The up to 13 characters stored in this slot
+        part: String,
+        deleted: bool,
+    },
+    ShortName {
+        /// DIR_Name as stored, with the padding
+        name: String,
+        attr: u8,
+        nt_res: u8,
+        crt_time_tenth: u8,
+        crt_time: u16,
+        crt_date: u16,
+        lst_acc_date: u16,
+        wrt_time: u16,
+        wrt_date: u16,
+        first_cluster: u32,
+        file_size: u32,
+        deleted: bool,
+        /// Reassembled from the long name slots before it, if they belong to it
+        long_name: Option<String>,
+    },
+    /// The 0 byte that ends the directory
+    End,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RawEntry {
+    pub location: EntryLocation,
+    #[serde(serialize_with = "serialize_hex")]
+    pub bytes: [u8; FAT32_DIR_SIZE],
+    #[serde(flatten)]
+    pub kind: RawEntryKind,
+}
+
+fn serialize_hex<S: serde::Serializer>(bytes: &[u8; FAT32_DIR_SIZE], serializer: S) -> Result<S::Ok, S::Error> {
+    let hex = bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    serializer.serialize_str(&hex)
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DirectoryDump {
+    pub path: PathBuf,
+    pub entries: Vec<RawEntry>,
+    /// Error that stopped reading the directory early
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Inspection {
+    pub boot_sector: BootSector,
+    pub geometry: Geometry,
+    pub fs_info: FsInfoReport,
+    pub fat_usage: FatUsage,
+    pub directories: Vec<DirectoryDump>,
+}
+
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+/// The characters of a long name slot, up to the terminating 0 or the 0xFFFF padding
+pub(crate) fn long_name_part(buf: &[u8]) -> Vec<u16> {
+    [1..11, 14..26, 28..32].into_iter()
+    .flat_map(|range| (range.start..range.end).step_by(2).map(|offset| u16_at(buf, offset)))
+    .take_while(|c| *c != 0 && *c != 0xFFFF)
+    .collect()
+}
+
+impl RawEntry {
+    /// Decodes a slot on its own, the long name of short name entries is left for `raw_entries` to fill in
+    pub fn decode(location: EntryLocation, bytes: [u8; FAT32_DIR_SIZE]) -> Self {
+        let kind = if bytes[0] == 0 {
+            RawEntryKind::End
+        } else if bytes[11] & DIR_ATTR_LONG_FILE_NAME == DIR_ATTR_LONG_FILE_NAME {
+            let deleted = bytes[0] == DIR_ENTRY_FREE;
+
+            RawEntryKind::LongName {
+                ord: (!deleted).then_some(bytes[0] & !LAST_LONG_ENTRY),
+                last: !deleted && bytes[0] & LAST_LONG_ENTRY != 0,
+                checksum: bytes[13],
+                part: String::from_utf16_lossy(&long_name_part(&bytes)),
+                deleted,
+            }
+        } else {
+            RawEntryKind::ShortName {
+                name: String::from_utf8_lossy(&bytes[0..11]).into_owned(),
+                attr: bytes[11],
+                nt_res: bytes[12],
+                crt_time_tenth: bytes[13],
+                crt_time: u16_at(&bytes, 14),
+                crt_date: u16_at(&bytes, 16),
+                lst_acc_date: u16_at(&bytes, 18),
+                wrt_time: u16_at(&bytes, 22),
+                wrt_date: u16_at(&bytes, 24),
+                first_cluster: (u16_at(&bytes, 20) as u32) << 16 | u16_at(&bytes, 26) as u32,
+                file_size: u32::from_le_bytes([bytes[28], bytes[29], bytes[30], bytes[31]]),
+                deleted: bytes[0] == DIR_ENTRY_FREE,
+                long_name: None,
+            }
+        };
+
+        Self {
+            location,
+            bytes,
+            kind,
+        }
+    }
+}
+
+/// A raw date as y-m-d
+fn fat_date(date: u16) -> String {
+    let (year, month, day) = FatDirectory::fat32_get_date(date);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+/// A raw time as h:m:s
+fn fat_time(time: u16) -> String {
+    let (hour, minute, second) = FatDirectory::fat32_get_time(time);
+    format!("{:02}:{:02}:{:02}", hour, minute, second)
+}
+
+impl Display for RawEntryKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RawEntryKind::LongName { ord, last, checksum, part, .. } => {
+                match ord {
+                    Some(ord) => write!(f, "LFN ord {}{}", ord, if *last { " last" } else { "" })?,
+                    None => write!(f, "LFN (deleted)")?,
+                }
+                write!(f, " checksum {:#04x} \"{}\"", checksum, part)
+            },
+            RawEntryKind::ShortName { name, attr, nt_res, crt_time_tenth, crt_time, crt_date, lst_acc_date, wrt_time, wrt_date, first_cluster, file_size, deleted, long_name } => {
+                write!(f, "SFN {}\"{}\" attr {:#04x} nt_res {:#04x} cluster {} size {}",
+                    if *deleted { "(deleted) " } else { "" }, name, attr, nt_res, first_cluster, file_size)?;
+                if let Some(long_name) = long_name {
+                    write!(f, " long name \"{}\"", long_name)?;
+                }
+                write!(f, ", created {} {} +{}0ms, written {} {}, accessed {}",
+                    fat_date(*crt_date), fat_time(*crt_time), crt_time_tenth, fat_date(*wrt_date), fat_time(*wrt_time), fat_date(*lst_acc_date))
+            },
+            RawEntryKind::End => write!(f, "end of directory"),
+        }
+    }
+}
+
+impl Display for RawEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:>8}:{:<4} {}", self.location.cluster, self.location.index, self.kind)
+    }
+}
+
+pub fn boot_sector(driver: &Driver) -> BootSector {
+    let bpb = &driver.bpb;
+
+    BootSector {
+        jmp_boot: bpb.bs_jmp_boot,
+        oem_name: String::from_utf8_lossy(&bpb.bs_oem_name).into_owned(),
+        bytes_per_sector: bpb.bpb_bytes_per_sec,
+        sectors_per_cluster: bpb.bpb_sec_per_clus,
+        reserved_sectors: bpb.bpb_rsvd_sec_cnt,
+        fat_count: bpb.bpb_num_fats,
+        root_entry_count: bpb.bpb_root_ent_cnt,
+        total_sectors_16: bpb.bpb_tot_sec16,
+        media: bpb.bpb_media,
+        fat_size_16: bpb.bpb_fat_sz16,
+        sectors_per_track: bpb.bpb_sec_per_trk,
+        heads: bpb.bpb_num_heads,
+        hidden_sectors: bpb.bpb_hidd_sec,
+        total_sectors_32: bpb.bpb_tot_sec32,
+        fat_size_32: bpb.bpb_fat_sz32,
+        ext_flags: bpb.bpb_ext_flags,
+        fs_version: bpb.bpb_fs_ver,
+        root_cluster: bpb.bpb_root_clus,
+        fs_info_sector: bpb.bpb_fs_info,
+        backup_boot_sector: bpb.bpb_bk_boot_sec,
+        drive_number: bpb.bs_drv_num,
+        boot_signature: bpb.bs_boot_sig,
+        volume_id: bpb.bs_vol_id,
+        volume_label: String::from_utf8_lossy(&bpb.bs_vol_lab).into_owned(),
+        fs_type: String::from_utf8_lossy(&bpb.bs_fil_sys_type).into_owned(),
+        signature: bpb.bs_sign,
+    }
+}
+
+pub fn geometry(driver: &Driver) -> Geometry {
+    let bpb = &driver.bpb;
+    let entries_per_fat = bpb.bpb_fat_sz32 as usize * bpb.bytes_per_sector() / 4;
+
+    Geometry {
+        fat_type: FatType::from_cluster_count(bpb.cluster_count()),
+        bytes_per_cluster: bpb.bytes_per_cluster(),
+        fat_start_sector: bpb.fat_start_sector(),
+        fat_sectors: bpb.fat_sectors(),
+        data_start_sector: bpb.data_start_sector(),
+        data_sectors: bpb.data_sectors(),
+        cluster_count: bpb.cluster_count(),
+        unused_fat_entries: entries_per_fat.saturating_sub(bpb.cluster_count() + 2),
+    }
+}
+
+pub fn fs_info(driver: &Driver) -> Fat32Result<FsInfoReport> {
+    let fs_info = FSInfo::read_from(driver)?;
+
+    Ok(FsInfoReport {
+        sector: driver.bpb.bpb_fs_info,
+        lead_signature: fs_info.fsi_lead_sig,
+        struct_signature: fs_info.fsi_struc_sig,
+        trail_signature: fs_info.fsi_trail_sig,
+        valid: fs_info.is_valid(),
+        free_count: fs_info.free_count(),
+        next_free: fs_info.next_free(),
+    })
+}
+
+pub fn fat_usage(driver: &Driver) -> Fat32Result<FatUsage> {
+    let fat = driver.read_fat_table(0)?;
+    let mut usage = FatUsage::default();
+
+    for &value in &fat[2..driver.cluster_count() + 2] {
+        if fat_is_free(value) {
+            usage.free += 1;
+            continue;
+        }
+        if fat_is_bad(value) {
+            usage.bad += 1;
+            continue;
+        }
+
+        usage.used += 1;
+
+        if fat_is_eoc(value) {
+            usage.chain_ends += 1;
+        } else if fat_is_reserved(value) || !driver.is_valid_cluster(value as usize) {
+            usage.invalid += 1;
+        }
+    }
+
+    for fat_index in 1..driver.bpb.bpb_num_fats as usize {
+        let copy = driver.read_fat_table(fat_index)?;
+        let mismatched = (2..driver.cluster_count() + 2).filter(|cluster| copy[*cluster] != fat[*cluster]).count();
+
+        usage.mismatched_copies.push(mismatched);
+    }
+
+    Ok(usage)
+}
+
+/// Every slot of `directory` up to the end of directory marker, decoded, with long names reassembled.
+/// Deleted short names get the deleted long name slots before them, which can't be checked against the checksum
+/// since the first byte of the short name is gone.
+pub fn raw_entries(driver: &Driver, directory: &FatDirectory) -> Fat32Result<Vec<RawEntry>> {
+    let entries_per_cluster = driver.bytes_per_cluster() / FAT32_DIR_SIZE;
+    let mut entries = vec![];
+    let mut long_name: Vec<Vec<u16>> = vec![];
+
+    for cluster in driver.chain(driver.directory_cluster(directory))? {
+        for index in 0..entries_per_cluster {
+            let location = EntryLocation { cluster, index };
+            let mut entry = RawEntry::decode(location, driver.raw_entry(location)?);
+
+            let follows_deleted = matches!(entries.last(), Some(RawEntry { kind: RawEntryKind::LongName { deleted: true, .. }, .. }));
+
+            match &mut entry.kind {
+                RawEntryKind::LongName { last, deleted, .. } => {
+                    // Slots are stored last part first. Deleted ones have lost the flag, so a run of them is taken as one name
+                    if *last || *deleted && !follows_deleted {
+                        long_name.clear();
+                    }
+                    long_name.push(long_name_part(&entry.bytes));
+                },
+                RawEntryKind::ShortName { deleted, long_name: assembled, .. } => {
+                    let parts = long_name_slots(&entries, long_name.len());
+                    let checksum = name_checksum(entry.bytes[0..11].try_into().unwrap());
+
+                    let belongs = !parts.is_empty() && parts.iter().all(|part| match part {
+                        RawEntryKind::LongName { deleted: part_deleted, checksum: part_checksum, .. } => {
+                            if *deleted { *part_deleted } else { !part_deleted && *part_checksum == checksum }
+                        },
+                        _=> false,
+                    });
+
+                    if belongs {
+                        let chars = long_name.iter().rev().flatten().copied().collect::<Vec<_>>();
+                        *assembled = Some(String::from_utf16_lossy(&chars));
+                    }
+                    long_name.clear();
+                },
+                RawEntryKind::End => {
+                    entries.push(entry);
+                    return Ok(entries);
+                },
+            }
+
+            entries.push(entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// The kinds of the last `count` entries, which are the long name slots collected for the next short name
+fn long_name_slots(entries: &[RawEntry], count: usize) -> Vec<&RawEntryKind> {
+    entries[entries.len().saturating_sub(count)..].iter().map(|entry| &entry.kind).collect()
+}
+
+fn dump_directory(driver: &Driver, path: &Path, directory: &FatDirectory) -> DirectoryDump {
+    match raw_entries(driver, directory) {
+        Ok(entries) => DirectoryDump { path: path.to_owned(), entries, error: None },
+        Err(error) => DirectoryDump { path: path.to_owned(), entries: vec![], error: Some(error.to_string()) },
+    }
+}
+
+/// Dumps the raw entries of the directories at `paths`, or of every directory if `recursive`, starting with them
+pub fn dump_directories(fs: &FatFs, paths: &[PathBuf], recursive: bool) -> Fat32Result<Vec<DirectoryDump>> {
+    let driver = fs.driver();
+    let mut dumps = vec![];
+
+    for path in paths {
+        let directory = driver.search_by_path(path)?;
+        dumps.push(dump_directory(driver, path, &directory));
+
+        if !recursive {
+            continue;
+        }
+
+        for entry in fs.walk(path) {
+            match entry {
+                Ok(entry) if entry.is_dir() => dumps.push(dump_directory(driver, entry.path(), entry.directory())),
+                Ok(_) => {},
+                Err(error) => dumps.push(DirectoryDump { path: path.to_owned(), entries: vec![], error: Some(error.to_string()) }),
+            }
+        }
+    }
+
+    Ok(dumps)
+}
+
+/// Everything about the volume outside of the files themselves, plus the raw entries of `directories`
+pub fn inspect(fs: &FatFs, directories: &[PathBuf], recursive: bool) -> Fat32Result<Inspection> {
+    let driver = fs.driver();
+
+    Ok(Inspection {
+        boot_sector: boot_sector(driver),
+        geometry: geometry(driver),
+        fs_info: fs_info(driver)?,
+        fat_usage: fat_usage(driver)?,
+        directories: dump_directories(fs, directories, recursive)?,
+    })
+}
+
+impl Display for Inspection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let boot = &self.boot_sector;
+        let geometry = &self.geometry;
+
+        writeln!(f, "Boot sector")?;
+        writeln!(f, "  jmp_boot            {:02x?}", boot.jmp_boot)?;
+        writeln!(f, "  OEM name            \"{}\"", boot.oem_name)?;
+        writeln!(f, "  Bytes per sector    {}", boot.bytes_per_sector)?;
+        writeln!(f, "  Sectors per cluster {}", boot.sectors_per_cluster)?;
+        writeln!(f, "  Reserved sectors    {}", boot.reserved_sectors)?;
+        writeln!(f, "  FATs                {}", boot.fat_count)?;
+        writeln!(f, "  Root entry count    {}", boot.root_entry_count)?;
+        writeln!(f, "  Total sectors 16/32 {} / {}", boot.total_sectors_16, boot.total_sectors_32)?;
+        writeln!(f, "  Media               {:#04x}", boot.media)?;
+        writeln!(f, "  FAT size 16/32      {} / {}", boot.fat_size_16, boot.fat_size_32)?;
+        writeln!(f, "  Sectors per track   {}", boot.sectors_per_track)?;
+        writeln!(f, "  Heads               {}", boot.heads)?;
+        writeln!(f, "  Hidden sectors      {}", boot.hidden_sectors)?;
+        writeln!(f, "  Ext flags           {:#06x}", boot.ext_flags)?;
+        writeln!(f, "  FS version          {:#06x}", boot.fs_version)?;
+        writeln!(f, "  Root cluster        {}", boot.root_cluster)?;
+        writeln!(f, "  FSInfo sector       {}", boot.fs_info_sector)?;
+        writeln!(f, "  Backup boot sector  {}", boot.backup_boot_sector)?;
+        writeln!(f, "  Drive number        {:#04x}", boot.drive_number)?;
+        writeln!(f, "  Boot signature      {:#04x}", boot.boot_signature)?;
+        writeln!(f, "  Volume ID           {:04X}-{:04X}", boot.volume_id >> 16, boot.volume_id & 0xFFFF)?;
+        writeln!(f, "  Volume label        \"{}\"", boot.volume_label)?;
+        writeln!(f, "  FS type             \"{}\"", boot.fs_type)?;
+        writeln!(f, "  Signature           {:#06x}", boot.signature)?;
+
+        writeln!(f, "Geometry")?;
+        writeln!(f, "  FAT type            {:?}", geometry.fat_type)?;
+        writeln!(f, "  Bytes per cluster   {}", geometry.bytes_per_cluster)?;
+        writeln!(f, "  FAT start sector    {}", geometry.fat_start_sector)?;
+        writeln!(f, "  FAT sectors         {}", geometry.fat_sectors)?;
+        writeln!(f, "  Data start sector   {}", geometry.data_start_sector)?;
+        writeln!(f, "  Data sectors        {}", geometry.data_sectors)?;
+        writeln!(f, "  Clusters            {}", geometry.cluster_count)?;
+        writeln!(f, "  Unused FAT entries  {}", geometry.unused_fat_entries)?;
+
+        let hint = |value: Option<u32>| value.map_or(String::from("unknown"), |value| value.to_string());
+        let fs_info = &self.fs_info;
+
+        writeln!(f, "FSInfo (sector {})", fs_info.sector)?;
+        writeln!(f, "  Signatures          {:#010x} {:#010x} {:#010x} ({})",
+            fs_info.lead_signature, fs_info.struct_signature, fs_info.trail_signature, if fs_info.valid { "valid" } else { "invalid" })?;
+        writeln!(f, "  Free count          {}", hint(fs_info.free_count))?;
+        writeln!(f, "  Next free           {}", hint(fs_info.next_free))?;
+
+        let usage = &self.fat_usage;
+
+        writeln!(f, "FAT usage")?;
+        writeln!(f, "  Free                {}", usage.free)?;
+        writeln!(f, "  Used                {}", usage.used)?;
+        writeln!(f, "  Bad                 {}", usage.bad)?;
+        writeln!(f, "  Invalid             {}", usage.invalid)?;
+        writeln!(f, "  Chain ends          {}", usage.chain_ends)?;
+        for (ix, mismatched) in usage.mismatched_copies.iter().enumerate() {
+            writeln!(f, "  FAT {} mismatches    {}", ix + 1, mismatched)?;
+        }
+
+        for directory in &self.directories {
+            writeln!(f, "Directory {}", directory.path.display())?;
+            for entry in &directory.entries {
+                writeln!(f, "  {}", entry)?;
+            }
+            if let Some(error) = &directory.error {
+                writeln!(f, "  error: {}", error)?;
+            }
+        }
+
+        Ok(())
+    }
+}
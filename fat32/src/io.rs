@@ -5,15 +5,23 @@ use crate::{Fat32Error, Fat32Result};
 
 
 pub struct Drive {
-    fd: OwnedFd
+    fd: OwnedFd,
+    /// Where the volume starts, for volumes inside a partitioned image or disk
+    offset: i64,
 }
 
 impl Drive {
     pub fn from_file(file: File) -> Result<Self>  {
         Ok(Self {
-            fd: file.as_fd().try_clone_to_owned()?
+            fd: file.as_fd().try_clone_to_owned()?,
+            offset: 0,
         })
     }
+    /// Makes all reads and writes relative to `offset` bytes into the file
+    pub fn with_offset(mut self, offset: u64) -> Self {
+        self.offset = offset as i64;
+        self
+    }
     #[allow(unused)]
     fn seek_and_read(&self, buf: &mut [u8], offset: i64) -> nix::Result<usize> {
         nix::unistd::lseek(self.fd.as_raw_fd(), offset, nix::unistd::Whence::SeekSet)?;
@@ -22,8 +30,21 @@ impl Drive {
     fn pread(&self, buf: &mut [u8], offset: i64) -> nix::Result<usize> {
         nix::sys::uio::pread(&self.fd, buf, offset)
     }
+    fn pwrite(&self, buf: &[u8], offset: i64) -> nix::Result<usize> {
+        nix::sys::uio::pwrite(&self.fd, buf, offset)
+    }
     pub fn read(&self, buf: &mut [u8], offset: i64) -> Fat32Result<usize> {
-        self.pread(buf, offset).map_err(|errno| {
+        self.pread(buf, self.offset + offset).map_err(|errno| {
+            Fat32Error::IOError(std::io::Error::from(errno))
+        })
+    }
+    pub fn write(&self, buf: &[u8], offset: i64) -> Fat32Result<usize> {
+        self.pwrite(buf, self.offset + offset).map_err(|errno| {
+            Fat32Error::IOError(std::io::Error::from(errno))
+        })
+    }
+    pub fn sync(&self) -> Fat32Result<()> {
+        nix::unistd::fsync(self.fd.as_raw_fd()).map_err(|errno| {
             Fat32Error::IOError(std::io::Error::from(errno))
         })
     }
@@ -1,14 +1,30 @@
+pub mod alloc;
 pub mod boot;
 pub mod driver;
 pub mod io;
 pub mod directory;
 pub mod file;
+pub mod fs;
+pub mod check;
+pub mod repair;
+pub mod defrag;
+pub mod fsinfo;
+pub mod partition;
+pub mod write;
+pub mod inspect;
+pub mod recover;
+pub mod carve;
+pub mod timeline;
+mod dcache;
 
 pub mod error;
 mod util;
 
+pub use alloc::*;
 pub use error::*;
 pub use driver::*;
 pub use io::*;
 pub use directory::*;
-pub use file::*;
\ No newline at end of file
+pub use file::*;
+pub use fs::*;
+pub use fsinfo::*;
\ No newline at end of file
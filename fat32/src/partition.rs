@@ -0,0 +1,112 @@
+use serde::Serialize;
+
+use crate::{Drive, Fat32Error, Fat32Result};
+
+/// Partition tables always address 512 byte sectors here, like the images they're found in
+const SECTOR_SIZE: u64 = 512;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_PARTITION_TABLE: usize = 446;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub enum PartitionTable {
+    Mbr,
+    Gpt,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Partition {
+    /// 1-based, in table order
+    pub number: usize,
+    /// Byte offset of the partition on the drive
+    pub start: u64,
+    /// Size in bytes
+    pub size: u64,
+    pub table: PartitionTable,
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    read_u32(buf, offset) as u64 | (read_u32(buf, offset + 4) as u64) << 32
+}
+
+fn gpt_partitions(drive: &Drive) -> Fat32Result<Vec<Partition>> {
+    let mut header = [0; SECTOR_SIZE as usize];
+    drive.read(&mut header, SECTOR_SIZE as i64)?;
+
+    if &header[0..8] != GPT_SIGNATURE {
+        return Err(Fat32Error::InvalidPartitionTable("GPT header signature is missing"));
+    }
+
+    let entries_lba = read_u64(&header, 72);
+    let n_entries = read_u32(&header, 80) as usize;
+    let entry_size = read_u32(&header, 84) as usize;
+
+    if entry_size < 128 || n_entries > 1024 {
+        return Err(Fat32Error::InvalidPartitionTable("GPT partition entry array is invalid"));
+    }
+
+    let mut entries = vec![0; n_entries * entry_size];
+    drive.read(&mut entries, (entries_lba * SECTOR_SIZE) as i64)?;
+
+    let partitions = entries.chunks_exact(entry_size).enumerate()
+    // Unused entries have an all zero type GUID
+    .filter(|(_, entry)| entry[0..16].iter().any(|byte| *byte != 0))
+    .map(|(ix, entry)| {
+        let first_lba = read_u64(entry, 32);
+        let last_lba = read_u64(entry, 40);
+
+        Partition {
+            number: ix + 1,
+            start: first_lba * SECTOR_SIZE,
+            size: (last_lba + 1).saturating_sub(first_lba) * SECTOR_SIZE,
+            table: PartitionTable::Gpt,
+        }
+    })
+    .collect();
+
+    Ok(partitions)
+}
+
+/// Reads the primary MBR partitions, or the GPT ones if the MBR is protective
+pub fn partitions(drive: &Drive) -> Fat32Result<Vec<Partition>> {
+    let mut mbr = [0; SECTOR_SIZE as usize];
+    drive.read(&mut mbr, 0)?;
+
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Err(Fat32Error::InvalidPartitionTable("MBR signature is missing"));
+    }
+
+    let mut partitions = vec![];
+
+    for ix in 0..4 {
+        let entry = &mbr[MBR_PARTITION_TABLE + ix * 16..MBR_PARTITION_TABLE + (ix + 1) * 16];
+        let partition_type = entry[4];
+
+        if partition_type == MBR_TYPE_GPT_PROTECTIVE {
+            return gpt_partitions(drive);
+        }
+        if partition_type == 0 {
+            continue;
+        }
+
+        partitions.push(Partition {
+            number: ix + 1,
+            start: read_u32(entry, 8) as u64 * SECTOR_SIZE,
+            size: read_u32(entry, 12) as u64 * SECTOR_SIZE,
+            table: PartitionTable::Mbr,
+        });
+    }
+
+    Ok(partitions)
+}
+
+/// Finds partition `number`, counting from 1
+pub fn partition(drive: &Drive, number: usize) -> Fat32Result<Partition> {
+    partitions(drive)?.into_iter()
+    .find(|partition| partition.number == number)
+    .ok_or(Fat32Error::NotFound)
+}
@@ -0,0 +1,346 @@
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::directory::name_checksum;
+use crate::inspect::{long_name_part, raw_entries, RawEntry, RawEntryKind};
+use crate::write::is_short_name_char;
+use crate::{fat_is_free, Driver, EntryLocation, Fat32Error, Fat32Result, FatDirectory, FatEntry, FatFs, FAT32_DIR_SIZE, LAST_LONG_ENTRY};
+
+/// How much of a deleted file is likely still there, assuming it was allocated contiguously
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Recoverability {
+    /// The file had no clusters
+    Empty,
+    /// Every cluster it would have used is still free
+    Recoverable,
+    /// Some of the clusters were allocated again
+    Partial { free: usize, total: usize },
+    /// None of the clusters are free, or they lie outside the volume
+    Overwritten,
+}
+
+impl Display for Recoverability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Recoverability::Empty => write!(f, "empty"),
+            Recoverability::Recoverable => write!(f, "recoverable"),
+            Recoverability::Partial { free, total } => write!(f, "partial ({}/{} clusters free)", free, total),
+            Recoverability::Overwritten => write!(f, "overwritten"),
+        }
+    }
+}
+
+/// A deleted short name entry along with the long name slots that were deleted with it
+#[derive(Clone, Debug, Serialize)]
+pub struct DeletedEntry {
+    /// Path the entry had, as far as it can be reconstructed
+    pub path: PathBuf,
+    /// Directory the entry is in
+    pub directory: PathBuf,
+    pub location: EntryLocation,
+    /// Long name slots in disk order
+    pub long_name_slots: Vec<EntryLocation>,
+    pub long_name: Option<String>,
+    /// Whether the first slot in disk order ends the name. Otherwise the slots before it may have been reused and
+    /// the long name cut short, or it's exactly a multiple of 13 characters long; either way it isn't restored
+    pub long_name_complete: bool,
+    /// The short name with the first character put back
+    pub short_name: String,
+    /// The first character of the short name, which deleting replaced with 0xE5
+    pub first_char: char,
+    /// Whether the long name checksum confirms `first_char`, otherwise it's a guess
+    pub first_char_verified: bool,
+    pub is_dir: bool,
+    pub size: u32,
+    pub first_cluster: usize,
+    /// The clusters the file would take if it was contiguous. Directories are assumed to take one
+    pub clusters: Vec<usize>,
+    pub recoverability: Recoverability,
+    #[serde(skip)]
+    bytes: [u8; FAT32_DIR_SIZE],
+}
+
+impl Display for DeletedEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:>8}:{:<4} {} {:>10} {:<12} {}{} ({}{}{})",
+            self.location.cluster, self.location.index,
+            if self.is_dir { "d" } else { "-" },
+            self.size,
+            self.recoverability.to_string(),
+            self.path.display(),
+            if self.is_dir { "/" } else { "" },
+            self.short_name,
+            if self.first_char_verified { "" } else { ", first character guessed" },
+            if self.long_name.is_some() && !self.long_name_complete { ", long name may be cut short" } else { "" },
+        )
+    }
+}
+
+/// A directory that couldn't be searched for deleted entries
+#[derive(Clone, Debug, Serialize)]
+pub struct UnreadableDirectory {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct DeletedEntries {
+    pub entries: Vec<DeletedEntry>,
+    /// Directories the search had to leave out
+    pub unreadable: Vec<UnreadableDirectory>,
+}
+
+/// Restores the 8.3 form of a raw name, for display
+fn short_name_string(name: &[u8; 11]) -> String {
+    let base = String::from_utf8_lossy(&name[0..8]).trim_end().to_owned();
+    let extension = String::from_utf8_lossy(&name[8..11]).trim_end().to_owned();
+
+    if extension.is_empty() { base } else { format!("{}.{}", base, extension) }
+}
+
+/// Works out the first character of a deleted short name. The long name checksum covers it, so every character that
+/// gives the right checksum is a candidate, preferring the first letter of the long name
+fn original_first_char(name: &[u8; 11], checksum: Option<u8>, long_name: Option<&str>) -> (u8, bool) {
+    let hint = long_name
+    .and_then(|long_name| long_name.trim_start_matches('.').chars().next())
+    .map(|c| c.to_ascii_uppercase())
+    .filter(|c| is_short_name_char(*c))
+    .map(|c| c as u8);
+
+    let Some(checksum) = checksum else {
+        return (hint.unwrap_or(b'_'), false);
+    };
+
+    let matches = |c: u8| {
+        let mut candidate = *name;
+        candidate[0] = c;
+        name_checksum(&candidate) == checksum
+    };
+
+    if let Some(hint) = hint.filter(|c| matches(*c)) {
+        return (hint, true);
+    }
+
+    match (0x21..0x7F).find(|c| is_short_name_char(*c as char) && matches(*c)) {
+        Some(c) => (c, true),
+        None => (hint.unwrap_or(b'_'), false),
+    }
+}
+
+fn recoverability(driver: &Driver, clusters: &[usize], fat: &[u32]) -> Recoverability {
+    if clusters.is_empty() {
+        return Recoverability::Empty;
+    }
+
+    let free = clusters.iter()
+    .filter(|cluster| driver.is_valid_cluster(**cluster) && fat_is_free(fat[**cluster]))
+    .count();
+
+    match free {
+        0 => Recoverability::Overwritten,
+        free if free == clusters.len() => Recoverability::Recoverable,
+        free => Recoverability::Partial { free, total: clusters.len() },
+    }
+}
+
+fn deleted_in(driver: &Driver, path: &Path, directory: &FatDirectory, fat: &[u32]) -> Fat32Result<Vec<DeletedEntry>> {
+    let entries = raw_entries(driver, directory)?;
+    let mut deleted = vec![];
+
+    for (ix, raw) in entries.iter().enumerate() {
+        let RawEntryKind::ShortName { deleted: true, long_name, .. } = &raw.kind else {
+            continue;
+        };
+        // Read through the regular decoder as well, for the attributes and such
+        let entry = FatEntry::read(&raw.bytes)?.ok_or(Fat32Error::FileCorrupt)?;
+        let file = FatDirectory::new(entry, &[]);
+        if !file.is_deleted() || file.is_volume_id() {
+            continue;
+        }
+
+        let slots = entries[..ix].iter().rev()
+        .take_while(|slot| matches!(slot.kind, RawEntryKind::LongName { deleted: true, .. }))
+        .collect::<Vec<&RawEntry>>();
+
+        // Only a long name whose slots agree on the checksum can be trusted
+        let checksum = slots.first().map(|slot| slot.bytes[13]);
+        let long_name = long_name.as_ref().filter(|_| slots.iter().all(|slot| Some(slot.bytes[13]) == checksum));
+        let checksum = checksum.filter(|_| long_name.is_some());
+        // A first slot with all 13 characters in use has neither the terminator nor padding that end a name
+        let long_name_complete = slots.last().is_some_and(|slot| long_name_part(&slot.bytes).len() < 13);
+
+        let mut name = *file.entry().raw_name();
+        let (first_char, first_char_verified) = original_first_char(&name, checksum, long_name.map(String::as_str));
+        name[0] = first_char;
+
+        let short_name = short_name_string(&name);
+        let n_clusters = match (file.cluster_num(), file.is_dir()) {
+            (0, _) => 0,
+            (_, true) => 1,
+            (_, false) => (file.file_size()).div_ceil(driver.bytes_per_cluster()),
+        };
+        let clusters = (file.cluster_num()..file.cluster_num() + n_clusters).collect::<Vec<_>>();
+
+        deleted.push(DeletedEntry {
+            path: path.join(long_name.unwrap_or(&short_name)),
+            directory: path.to_owned(),
+            location: raw.location,
+            long_name_slots: if long_name.is_some() { slots.iter().rev().map(|slot| slot.location).collect() } else { vec![] },
+            long_name: long_name.cloned(),
+            long_name_complete,
+            short_name,
+            first_char: first_char as char,
+            first_char_verified,
+            is_dir: file.is_dir(),
+            size: file.file_size() as u32,
+            first_cluster: file.cluster_num(),
+            recoverability: recoverability(driver, &clusters, fat),
+            clusters,
+            bytes: raw.bytes,
+        });
+    }
+
+    Ok(deleted)
+}
+
+/// Lists the deleted entries in the directories at `paths`, and if `recursive` in every directory below them, live
+/// or deleted. Deleted directories are only looked into while their first cluster is still free. Directories that
+/// can't be read are reported and left out
+pub fn deleted_entries(fs: &FatFs, paths: &[PathBuf], recursive: bool) -> Fat32Result<DeletedEntries> {
+    let driver = fs.driver();
+    let fat = driver.read_fat_table(0)?;
+    let mut deleted = DeletedEntries::default();
+
+    let mut pending = vec![];
+    for path in paths.iter().rev() {
+        let directory = driver.search_by_path(path)?;
+        if !directory.is_dir() {
+            return Err(Fat32Error::NotADir);
+        }
+        pending.push((path.clone(), directory));
+    }
+
+    // Stale directory clusters can point at each other
+    let mut visited = HashSet::new();
+
+    while let Some((path, directory)) = pending.pop() {
+        if !visited.insert(driver.directory_cluster(&directory)) {
+            continue;
+        }
+
+        let mut errors = vec![];
+        let mut children = vec![];
+
+        match deleted_in(driver, &path, &directory, &fat) {
+            Ok(entries) => {
+                for entry in entries.iter().filter(|entry| entry.is_dir && entry.recoverability == Recoverability::Recoverable) {
+                    let file = FatEntry::read(&entry.bytes)?.ok_or(Fat32Error::FileCorrupt)?;
+                    children.push((entry.path.clone(), FatDirectory::new(file, &[])));
+                }
+                deleted.entries.extend(entries);
+            },
+            Err(error) => errors.push(error),
+        }
+
+        // Deleted directories only have deleted entries worth listing
+        if recursive && !directory.is_deleted() {
+            match fs.read_dir(&path) {
+                Ok(read_dir) => for child in read_dir {
+                    match child {
+                        Ok(child) if child.is_dir() => children.push((child.path().to_owned(), child.directory().clone())),
+                        Ok(_) => {},
+                        Err(error) => errors.push(error),
+                    }
+                },
+                Err(error) => errors.push(error),
+            }
+        }
+
+        deleted.unreadable.extend(errors.into_iter().map(|error| UnreadableDirectory { path: path.clone(), error: error.to_string() }));
+
+        if recursive {
+            pending.extend(children.into_iter().rev());
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Undeletes `entry` where it is: its clusters are linked back into a contiguous chain and its entry is restored,
+/// with `first_char` as the first character of the short name if given. The long name is only restored if it's complete
+/// and its checksum still matches, and directories only get their first cluster back
+pub fn restore(fs: &FatFs, entry: &DeletedEntry, first_char: Option<u8>) -> Fat32Result<FatDirectory> {
+    let driver = fs.driver();
+
+    if driver.raw_entry(entry.location)? != entry.bytes {
+        return Err(Fat32Error::NotFound);
+    }
+    // Entries found in a deleted directory need it restored first
+    if !driver.search_by_path(&entry.directory)?.is_dir() {
+        return Err(Fat32Error::NotADir);
+    }
+    if let Some(c) = first_char.filter(|c| !is_short_name_char(*c as char)) {
+        return Err(Fat32Error::InvalidName(String::from(c as char)));
+    }
+
+    let mut name = *FatEntry::read(&entry.bytes)?.ok_or(Fat32Error::FileCorrupt)?.raw_name();
+    name[0] = first_char.unwrap_or(entry.first_char as u8);
+
+    let checksum = entry.long_name_slots.first().map(|slot| driver.raw_entry(*slot)).transpose()?.map(|slot| slot[13]);
+    let keep_long_name = entry.long_name.is_some() && entry.long_name_complete && checksum == Some(name_checksum(&name));
+
+    let short_name = short_name_string(&name);
+    let mut names = vec![short_name.as_str()];
+    if let (Some(long_name), true) = (&entry.long_name, keep_long_name) {
+        names.push(long_name);
+    }
+    if names.iter().any(|name| fs.exists(entry.directory.join(name))) {
+        return Err(Fat32Error::AlreadyExists);
+    }
+
+    if !entry.clusters.is_empty() {
+        driver.claim(&entry.clusters)?;
+    }
+
+    if keep_long_name {
+        let n_slots = entry.long_name_slots.len() as u8;
+        for (ix, slot) in entry.long_name_slots.iter().enumerate() {
+            let ord = (n_slots - ix as u8) | if ix == 0 { LAST_LONG_ENTRY } else { 0 };
+            driver.write_cluster(slot.cluster, slot.byte_offset(), &[ord])?;
+        }
+    }
+    driver.write_cluster(entry.location.cluster, entry.location.byte_offset(), &name[..1])?;
+    driver.invalidate_dentries();
+
+    driver.search_by_path(&entry.directory.join(names.last().unwrap()))
+}
+
+/// Copies what's left of `entry` out to `writer`, reading its clusters as if it was contiguous, whether they were
+/// overwritten or not. Returns the number of bytes written
+pub fn extract<W: Write>(driver: &Driver, entry: &DeletedEntry, mut writer: W) -> Fat32Result<u64> {
+    if entry.is_dir {
+        return Err(Fat32Error::IsDir);
+    }
+
+    let mut buffer = vec![0; driver.bytes_per_cluster()];
+    let mut remaining = entry.size as usize;
+
+    for cluster in &entry.clusters {
+        if !driver.is_valid_cluster(*cluster) {
+            return Err(Fat32Error::BadCluster(*cluster as u32));
+        }
+
+        let length = usize::min(remaining, buffer.len());
+        driver.read_cluster(*cluster, 0, &mut buffer[..length])?;
+        writer.write_all(&buffer[..length]).map_err(Fat32Error::IOError)?;
+
+        remaining -= length;
+    }
+
+    Ok(entry.size as u64)
+}
@@ -0,0 +1,488 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::check::{CheckIssue, CheckReport};
+use crate::{AllocationStrategy, Driver, EntryLocation, Fat32Error, Fat32Result, FatDirectory, FatEntry, DIR_ATTR_ARCHIVE, FAT_EOC, FAT_FREE};
+
+/// Directory in the root that salvaged lost chains are placed in
+const FOUND_DIR_NAME: [u8; 11] = *b"FOUND   000";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LostChainPolicy {
+    /// Mark lost clusters as free
+    #[default]
+    Free,
+    /// Turn every lost chain into a `FOUND.000/FILEnnnn.CHK` file
+    Salvage,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RepairOptions {
+    pub lost_chains: LostChainPolicy,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum RepairAction {
+    /// Marks `cluster` as the last cluster of the chain of `path`, dropping an invalid or looping link
+    EndChain { path: PathBuf, cluster: u32 },
+    /// Clears an invalid first cluster from the entry of `path`
+    ClearFirstCluster { path: PathBuf, entry: EntryLocation },
+    /// Gives `path` its own copy of its chain from the shared `cluster` on
+    CopySharedClusters { path: PathBuf, entry: EntryLocation, cluster: u32 },
+    /// Shortens the chain of `path` to `clusters` clusters and frees the rest
+    TruncateChain { path: PathBuf, entry: EntryLocation, clusters: usize },
+    /// Grows the chain of `path` to `clusters` clusters with zeroed clusters
+    ExtendChain { path: PathBuf, entry: EntryLocation, clusters: usize },
+    /// Points the `.` entry of `path` to `cluster`
+    FixDotEntry { path: PathBuf, entry: EntryLocation, cluster: u32 },
+    /// Points the `..` entry of `path` to `cluster`
+    FixDotDotEntry { path: PathBuf, entry: EntryLocation, cluster: u32 },
+    FreeLostChain { start: u32, length: usize },
+    /// Links a lost chain into `/FOUND.000/<name>`
+    SalvageLostChain { start: u32, length: usize, name: String },
+    /// Copies the primary FAT over the `fat`th copy
+    SyncFatCopy { fat: usize },
+    /// Recounts free clusters and the next free cluster into FSInfo
+    RecomputeFsInfo,
+}
+
+impl Display for RepairAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepairAction::EndChain { path, cluster } => {
+                write!(f, "{}: end the cluster chain at cluster {}", path.display(), cluster)
+            },
+            RepairAction::ClearFirstCluster { path, .. } => {
+                write!(f, "{}: clear the invalid first cluster", path.display())
+            },
+            RepairAction::CopySharedClusters { path, cluster, .. } => {
+                write!(f, "{}: copy the clusters shared from cluster {} on", path.display(), cluster)
+            },
+            RepairAction::TruncateChain { path, clusters, .. } => {
+                write!(f, "{}: truncate the cluster chain to {} cluster(s)", path.display(), clusters)
+            },
+            RepairAction::ExtendChain { path, clusters, .. } => {
+                write!(f, "{}: extend the cluster chain to {} cluster(s)", path.display(), clusters)
+            },
+            RepairAction::FixDotEntry { path, cluster, .. } => {
+                write!(f, "{}: point '.' to cluster {}", path.display(), cluster)
+            },
+            RepairAction::FixDotDotEntry { path, cluster, .. } => {
+                write!(f, "{}: point '..' to cluster {}", path.display(), cluster)
+            },
+            RepairAction::FreeLostChain { start, length } => {
+                write!(f, "Free the lost chain of {} cluster(s) starting at cluster {}", length, start)
+            },
+            RepairAction::SalvageLostChain { start, length, name } => {
+                write!(f, "Salvage the lost chain of {} cluster(s) starting at cluster {} into /FOUND.000/{}", length, start, name)
+            },
+            RepairAction::SyncFatCopy { fat } => {
+                write!(f, "Copy the primary FAT over FAT copy {}", fat)
+            },
+            RepairAction::RecomputeFsInfo => {
+                write!(f, "Recompute the FSInfo free cluster count and next free cluster")
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RepairPlan {
+    pub actions: Vec<RepairAction>,
+    /// Issues that can't be repaired automatically
+    pub skipped: Vec<CheckIssue>,
+}
+
+impl RepairPlan {
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+}
+
+impl Display for RepairPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (ix, action) in self.actions.iter().enumerate() {
+            writeln!(f, "{:>4}. {}", ix + 1, action)?;
+        }
+        for issue in &self.skipped {
+            writeln!(f, "Can't repair: {}", issue)?;
+        }
+
+        write!(f, "{} repair(s) planned, {} issue(s) left as is", self.actions.len(), self.skipped.len())
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RepairLogEntry {
+    pub action: RepairAction,
+    pub detail: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RepairLog {
+    pub entries: Vec<RepairLogEntry>,
+}
+
+impl RepairLog {
+    pub fn n_failed(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.error.is_some()).count()
+    }
+}
+
+impl Display for RepairLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for entry in &self.entries {
+            match (&entry.detail, &entry.error) {
+                (_, Some(error)) => writeln!(f, "FAILED {}: {}", entry.action, error)?,
+                (Some(detail), None) => writeln!(f, "OK {} ({})", entry.action, detail)?,
+                (None, None) => writeln!(f, "OK {}", entry.action)?,
+            }
+        }
+
+        write!(f, "{} repair(s) applied, {} failed", self.entries.len() - self.n_failed(), self.n_failed())
+    }
+}
+
+fn found_dir(driver: &Driver) -> Fat32Result<Option<FatDirectory>> {
+    let root = FatDirectory::root(driver);
+
+    match driver.search(&root, OsStr::new("FOUND.000")) {
+        Ok(found) if found.is_dir() => Ok(Some(found)),
+        Ok(_) => Err(Fat32Error::NotADir),
+        Err(Fat32Error::NotFound) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Picks `FILEnnnn.CHK` names not already taken in `FOUND.000`
+fn salvage_names(driver: &Driver, count: usize) -> Fat32Result<Vec<String>> {
+    let mut taken = HashSet::new();
+
+    if let Some(found) = found_dir(driver)? {
+        let mut files = driver.files(&found);
+        while let Some(file) = files.next()? {
+            if !file.is_deleted() {
+                taken.insert(file.name().to_string_lossy().to_uppercase());
+            }
+        }
+    }
+
+    let names = (0..10000)
+    .map(|n| format!("FILE{:04}.CHK", n))
+    .filter(|name| !taken.contains(name))
+    .take(count)
+    .collect::<Vec<_>>();
+
+    if names.len() < count {
+        return Err(Fat32Error::NoSpace);
+    }
+
+    Ok(names)
+}
+
+fn entry_location(report: &CheckReport, path: &Path) -> Option<EntryLocation> {
+    report.locations.get(path).copied()
+}
+
+/// Finds the `.` or `..` entry among the first two entries of the directory at `path`
+fn dot_entry_location(driver: &Driver, report: &CheckReport, path: &Path, dot_dot: bool) -> Option<EntryLocation> {
+    let directory = driver.read_entry(entry_location(report, path)?).ok()??;
+
+    let mut files = driver.files(&directory);
+    let dot = files.next().ok()??;
+
+    if dot_dot {
+        let dot_dot = files.next().ok()??;
+        dot_dot.is_parent_dir().then_some(dot_dot.location()?)
+    } else {
+        dot.is_current_dir().then_some(dot.location()?)
+    }
+}
+
+/// Works out the repairs for the issues in `report` without touching the volume
+pub fn plan(driver: &Driver, report: &CheckReport, options: &RepairOptions) -> Fat32Result<RepairPlan> {
+    let mut ends = vec![];
+    let mut copies = vec![];
+    let mut resizes = vec![];
+    let mut dots = vec![];
+    let mut lost = vec![];
+    let mut fats = vec![];
+    let mut skipped = vec![];
+
+    let lost_chains = report.issues.iter().filter(|issue| matches!(issue, CheckIssue::LostChain { .. })).count();
+    let mut names = match options.lost_chains {
+        LostChainPolicy::Salvage => salvage_names(driver, lost_chains)?,
+        LostChainPolicy::Free => vec![],
+    }.into_iter();
+
+    for issue in &report.issues {
+        match issue.clone() {
+            CheckIssue::InvalidCluster { path, cluster: 0, .. } => {
+                // Directories can't lose their first cluster
+                let entry = entry_location(report, &path)
+                .filter(|location| matches!(driver.read_entry(*location), Ok(Some(found)) if found.is_file()));
+
+                match entry {
+                    Some(entry) => ends.push(RepairAction::ClearFirstCluster { path, entry }),
+                    None => skipped.push(issue.clone()),
+                }
+            },
+            CheckIssue::InvalidCluster { path, cluster, .. } | CheckIssue::ChainLoop { path, cluster } => {
+                ends.push(RepairAction::EndChain { path, cluster });
+            },
+            CheckIssue::CrossLinked { cluster, path, .. } => {
+                match entry_location(report, &path) {
+                    Some(entry) => copies.push(RepairAction::CopySharedClusters { path, entry, cluster }),
+                    None => skipped.push(issue.clone()),
+                }
+            },
+            CheckIssue::SizeMismatch { path, clusters, expected_clusters, .. } => {
+                let Some(entry) = entry_location(report, &path) else {
+                    skipped.push(issue.clone());
+                    continue;
+                };
+
+                if clusters > expected_clusters {
+                    resizes.push(RepairAction::TruncateChain { path, entry, clusters: expected_clusters });
+                } else {
+                    resizes.push(RepairAction::ExtendChain { path, entry, clusters: expected_clusters });
+                }
+            },
+            CheckIssue::BadDotEntry { path, expected, found: Some(_) } => {
+                match dot_entry_location(driver, report, &path, false) {
+                    Some(entry) => dots.push(RepairAction::FixDotEntry { path, entry, cluster: expected }),
+                    None => skipped.push(issue.clone()),
+                }
+            },
+            CheckIssue::BadDotDotEntry { path, expected, found: Some(_) } => {
+                match dot_entry_location(driver, report, &path, true) {
+                    Some(entry) => dots.push(RepairAction::FixDotDotEntry { path, entry, cluster: expected }),
+                    None => skipped.push(issue.clone()),
+                }
+            },
+            CheckIssue::LostChain { start, length } => {
+                match names.next() {
+                    Some(name) => lost.push(RepairAction::SalvageLostChain { start, length, name }),
+                    None => lost.push(RepairAction::FreeLostChain { start, length }),
+                }
+            },
+            CheckIssue::FatMismatch { fat, .. } => {
+                fats.push(RepairAction::SyncFatCopy { fat });
+            },
+            CheckIssue::FsInfoMismatch { .. } => {},
+            _=> skipped.push(issue.clone()),
+        }
+    }
+
+    // Shared clusters have to be copied before truncating frees them
+    let mut actions = vec![];
+    actions.extend(ends);
+    actions.extend(copies);
+    actions.extend(resizes);
+    actions.extend(dots);
+    actions.extend(lost);
+    actions.extend(fats);
+
+    let fs_info_mismatch = report.issues.iter().any(|issue| matches!(issue, CheckIssue::FsInfoMismatch { .. }));
+    if !actions.is_empty() || fs_info_mismatch {
+        actions.push(RepairAction::RecomputeFsInfo);
+    }
+
+    Ok(RepairPlan { actions, skipped })
+}
+
+fn read_entry(driver: &Driver, location: EntryLocation) -> Fat32Result<FatDirectory> {
+    driver.read_entry(location)?.ok_or(Fat32Error::NotFound)
+}
+
+fn free_clusters(driver: &Driver, clusters: &[usize]) -> Fat32Result<()> {
+    for cluster in clusters {
+        driver.write_fat(*cluster, FAT_FREE)?;
+    }
+
+    Ok(())
+}
+
+/// Allocates `count` zeroed clusters linked into a chain after `last`
+fn append_clusters(driver: &Driver, last: Option<usize>, count: usize) -> Fat32Result<Vec<usize>> {
+    let extents = match last {
+        Some(last) => driver.extend(last, count, AllocationStrategy::NextFit)?,
+        None => driver.allocate(count, AllocationStrategy::NextFit)?,
+    };
+
+    let added = extents.iter().flat_map(|extent| extent.clusters()).collect::<Vec<_>>();
+    for cluster in &added {
+        driver.zero_cluster(*cluster)?;
+    }
+
+    Ok(added)
+}
+
+fn apply(driver: &Driver, action: &RepairAction) -> Fat32Result<Option<String>> {
+    let bytes_per_cluster = driver.bytes_per_cluster();
+
+    match action {
+        RepairAction::EndChain { cluster, .. } => {
+            let old = driver.read_fat_entry(*cluster as usize)?;
+            driver.write_fat(*cluster as usize, FAT_EOC)?;
+
+            Ok(Some(format!("cluster {} pointed to {:#010x}", cluster, old)))
+        },
+        RepairAction::ClearFirstCluster { entry, .. } => {
+            let mut file = read_entry(driver, *entry)?;
+            let old = file.cluster_num();
+
+            file.set_cluster_num(0);
+            driver.write_entry(&file)?;
+
+            Ok(Some(format!("first cluster was {}", old)))
+        },
+        RepairAction::CopySharedClusters { entry, cluster, .. } => {
+            let mut file = read_entry(driver, *entry)?;
+            let clusters = driver.chain(file.cluster_num())?;
+
+            let Some(shared_from) = clusters.iter().position(|c| *c == *cluster as usize) else {
+                return Ok(Some("no longer shares the cluster".to_owned()));
+            };
+
+            let previous = shared_from.checked_sub(1).map(|ix| clusters[ix]);
+            let shared = &clusters[shared_from..];
+            let copies = append_clusters(driver, None, shared.len())?;
+
+            let mut buffer = vec![0; bytes_per_cluster];
+            for (from, to) in shared.iter().zip(&copies) {
+                driver.read_cluster(*from, 0, &mut buffer)?;
+                driver.write_cluster(*to, 0, &buffer)?;
+            }
+
+            match previous {
+                Some(previous) => driver.write_fat(previous, copies[0] as u32)?,
+                None => {
+                    file.set_cluster_num(copies[0]);
+                    driver.write_entry(&file)?;
+                }
+            }
+
+            Ok(Some(format!("copied {} cluster(s) to cluster {}", copies.len(), copies[0])))
+        },
+        RepairAction::TruncateChain { entry, clusters, .. } => {
+            let mut file = read_entry(driver, *entry)?;
+            let chain = driver.chain(file.cluster_num())?;
+            let keep = usize::min(*clusters, chain.len());
+
+            if keep == 0 {
+                file.set_cluster_num(0);
+                driver.write_entry(&file)?;
+            } else {
+                driver.write_fat(chain[keep - 1], FAT_EOC)?;
+            }
+            free_clusters(driver, &chain[keep..])?;
+
+            Ok(Some(format!("freed {} cluster(s)", chain.len() - keep)))
+        },
+        RepairAction::ExtendChain { entry, clusters, .. } => {
+            let mut file = read_entry(driver, *entry)?;
+            let chain = if file.cluster_num() == 0 { vec![] } else { driver.chain(file.cluster_num())? };
+
+            let missing = clusters.saturating_sub(chain.len());
+            let added = append_clusters(driver, chain.last().copied(), missing)?;
+
+            if chain.is_empty() {
+                if let Some(first) = added.first() {
+                    file.set_cluster_num(*first);
+                    driver.write_entry(&file)?;
+                }
+            }
+
+            Ok(Some(format!("added {} zeroed cluster(s)", added.len())))
+        },
+        RepairAction::FixDotEntry { entry, cluster, .. } | RepairAction::FixDotDotEntry { entry, cluster, .. } => {
+            let mut dot = read_entry(driver, *entry)?;
+            let old = dot.cluster_num();
+
+            dot.set_cluster_num(*cluster as usize);
+            driver.write_entry(&dot)?;
+
+            Ok(Some(format!("pointed to cluster {}", old)))
+        },
+        RepairAction::FreeLostChain { start, length } => {
+            let mut lost = driver.chain(*start as usize)?;
+            lost.truncate(*length);
+
+            free_clusters(driver, &lost)?;
+
+            Ok(Some(format!("freed {} cluster(s)", lost.len())))
+        },
+        RepairAction::SalvageLostChain { start, length, name } => {
+            let lost = driver.chain(*start as usize)?;
+            let length = usize::min(*length, lost.len());
+
+            // The chain may have ended in an invalid link or a loop
+            driver.write_fat(lost[length - 1], FAT_EOC)?;
+
+            let found = match found_dir(driver)? {
+                Some(found) => found,
+                None => driver.create_dir(&FatDirectory::root(driver), FOUND_DIR_NAME)?,
+            };
+
+            let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+            let mut short_name = [b' '; 11];
+            short_name[..base.len()].copy_from_slice(base.as_bytes());
+            short_name[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+
+            let file_size = u32::try_from(length * bytes_per_cluster).unwrap_or(u32::MAX);
+            driver.create_entry(&found, FatEntry::new(short_name, DIR_ATTR_ARCHIVE, *start as usize, file_size))?;
+
+            Ok(Some(format!("{} bytes", file_size)))
+        },
+        RepairAction::SyncFatCopy { fat } => {
+            let table = driver.read_fat_table(0)?;
+            driver.write_fat_table(*fat, &table)?;
+
+            Ok(None)
+        },
+        RepairAction::RecomputeFsInfo => {
+            if !driver.fs_info()?.is_valid() {
+                return Err(Fat32Error::InvalidBPB("FSInfo sector has invalid signatures"));
+            }
+
+            let free_count = driver.recompute_free_space()?;
+
+            Ok(Some(format!("{} free cluster(s)", free_count)))
+        },
+    }
+}
+
+/// Applies every action of `plan` in order, carrying on past failed ones
+pub fn repair(driver: &Driver, plan: &RepairPlan) -> Fat32Result<RepairLog> {
+    let mut log = RepairLog::default();
+
+    for action in &plan.actions {
+        let result = apply(driver, action);
+
+        match &result {
+            Ok(_) => log::info!("Repaired: {}", action),
+            Err(err) => log::warn!("Repair failed: {}: {}", action, err),
+        }
+
+        let (detail, error) = match result {
+            Ok(detail) => (detail, None),
+            Err(err) => (None, Some(err.to_string())),
+        };
+
+        log.entries.push(RepairLogEntry { action: action.clone(), detail, error });
+    }
+
+    // Clusters of directories may have moved
+    driver.invalidate_dentries();
+    driver.sync()?;
+
+    Ok(log)
+}
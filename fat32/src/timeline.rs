@@ -0,0 +1,154 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use chrono::{FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use serde::Serialize;
+
+use crate::inspect::{RawEntry, RawEntryKind};
+use crate::recover::deleted_entries;
+use crate::{Driver, EntryLocation, Fat32Error, Fat32Result, FatDirectory, FatFs, DIR_ATTR_DIRECTORY, DIR_ATTR_READ_ONLY, FAT32_DIR_SIZE};
+
+/// FAT timestamps are local time without a zone, this says which zone to read them in
+#[derive(Clone, Copy, Debug)]
+pub enum TimestampZone {
+    /// The zone of this machine
+    Local,
+    Utc,
+    Offset(FixedOffset),
+}
+
+impl TimestampZone {
+    fn to_unix(self, datetime: NaiveDateTime) -> Option<i64> {
+        let datetime = match self {
+            TimestampZone::Local => Local.from_local_datetime(&datetime).earliest()?.fixed_offset(),
+            TimestampZone::Utc => Utc.from_utc_datetime(&datetime).fixed_offset(),
+            TimestampZone::Offset(offset) => offset.from_local_datetime(&datetime).single()?,
+        };
+
+        Some(datetime.timestamp())
+    }
+}
+
+/// MAC times of a live or deleted entry
+#[derive(Clone, Debug, Serialize)]
+pub struct TimelineEntry {
+    pub path: PathBuf,
+    pub deleted: bool,
+    pub location: EntryLocation,
+    /// Address of the entry on the volume, in 32 byte slots, which stands in for an inode number
+    pub address: usize,
+    pub attributes: u8,
+    pub is_dir: bool,
+    pub size: u32,
+    /// Unix times, None where the field is unset or isn't a valid date. FAT only stores the date of the last access
+    pub accessed: Option<i64>,
+    pub modified: Option<i64>,
+    pub created: Option<i64>,
+    /// DIR_CrtTimeTenth beyond the whole seconds already in `created`, in milliseconds
+    pub created_millis: u16,
+}
+
+impl TimelineEntry {
+    fn mode(&self) -> String {
+        let kind = if self.is_dir { 'd' } else { 'r' };
+        let permissions = if self.attributes & DIR_ATTR_READ_ONLY != 0 { "r-xr-xr-x" } else { "rwxrwxrwx" };
+
+        format!("{}/{}{}", kind, kind, permissions)
+    }
+}
+
+/// A line of a Sleuth Kit body file: MD5|name|inode|mode|UID|GID|size|atime|mtime|ctime|crtime. FAT has no change
+/// time, so ctime is always 0, as are unset times
+impl Display for TimelineEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let time = |time: Option<i64>| time.unwrap_or(0);
+
+        write!(f, "0|{}{}|{}|{}|0|0|{}|{}|{}|0|{}",
+            self.path.display(),
+            if self.deleted { " (deleted)" } else { "" },
+            self.address,
+            self.mode(),
+            self.size,
+            time(self.accessed),
+            time(self.modified),
+            time(self.created),
+        )
+    }
+}
+
+fn decode(date: u16, time: u16) -> Option<NaiveDateTime> {
+    let (year, month, day) = FatDirectory::fat32_get_date(date);
+    let (hour, minute, second) = FatDirectory::fat32_get_time(time);
+
+    NaiveDate::from_ymd_opt(year, month, day)?.and_hms_opt(hour, minute, second)
+}
+
+fn timeline_entry(driver: &Driver, path: &Path, location: EntryLocation, zone: TimestampZone) -> Fat32Result<TimelineEntry> {
+    let raw = RawEntry::decode(location, driver.raw_entry(location)?);
+
+    let RawEntryKind::ShortName { attr, crt_time_tenth, crt_time, crt_date, lst_acc_date, wrt_time, wrt_date, file_size, deleted, .. } = raw.kind else {
+        return Err(Fat32Error::FileCorrupt);
+    };
+
+    // The tenth counts 10ms units up to 1.99s, making up for the 2 second resolution of DIR_CrtTime
+    let created = decode(crt_date, crt_time)
+    .map(|created| created + chrono::Duration::seconds(crt_time_tenth as i64 / 100));
+
+    let sector = driver.bpb.cluster_start_sector(location.cluster);
+
+    Ok(TimelineEntry {
+        path: path.to_owned(),
+        deleted,
+        location,
+        address: (sector * driver.bpb.bytes_per_sector() + location.byte_offset()) / FAT32_DIR_SIZE,
+        attributes: attr,
+        is_dir: attr & DIR_ATTR_DIRECTORY != 0,
+        size: file_size,
+        accessed: decode(lst_acc_date, 0).and_then(|accessed| zone.to_unix(accessed)),
+        modified: decode(wrt_date, wrt_time).and_then(|modified| zone.to_unix(modified)),
+        created: created.and_then(|created| zone.to_unix(created)),
+        created_millis: (crt_time_tenth % 100) as u16 * 10,
+    })
+}
+
+/// Every entry on the volume with its timestamps, live ones first in walk order and then the deleted ones
+/// if `include_deleted`. Deleted entries get the names worked out by `recover::deleted_entries`. Directories that
+/// can't be read are left out with a warning
+pub fn timeline(fs: &FatFs, zone: TimestampZone, include_deleted: bool) -> Fat32Result<Vec<TimelineEntry>> {
+    let driver = fs.driver();
+    let mut timeline = vec![];
+
+    for entry in fs.walk("/") {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                log::warn!("Skipped while walking the volume: {}", error);
+                continue;
+            }
+        };
+        let Some(location) = entry.directory().location() else {
+            continue;
+        };
+
+        match timeline_entry(driver, entry.path(), location, zone) {
+            Ok(entry) => timeline.push(entry),
+            Err(error) => log::warn!("{}: {}", entry.path().display(), error),
+        }
+    }
+
+    if include_deleted {
+        let deleted = deleted_entries(fs, &[PathBuf::from("/")], true)?;
+
+        for directory in deleted.unreadable {
+            log::warn!("{}: {}", directory.path.display(), directory.error);
+        }
+        for entry in deleted.entries {
+            match timeline_entry(driver, &entry.path, entry.location, zone) {
+                Ok(entry) => timeline.push(entry),
+                Err(error) => log::warn!("{}: {}", entry.path.display(), error),
+            }
+        }
+    }
+
+    Ok(timeline)
+}
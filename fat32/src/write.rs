@@ -0,0 +1,633 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+
+use crate::alloc::AllocationStrategy;
+use crate::boot::BPB;
+use crate::fs::is_listed;
+use crate::{Driver, EntryLocation, Fat32Error, Fat32Result, FatDirectory, FatEntry, DIR_ATTR_ARCHIVE, DIR_ATTR_DIRECTORY, DIR_ATTR_LONG_FILE_NAME, DIR_ATTR_VOLUME_ID, DIR_ENTRY_FREE, FAT32_DIR_SIZE, LAST_LONG_ENTRY, LFN};
+
+/// Characters no name may contain, besides control characters
+const INVALID_CHARS: &str = "\"*/:<>?\\|";
+/// Characters allowed in long names but not in short names or labels
+const LABEL_INVALID_CHARS: &str = "+,;=[]";
+/// Characters allowed in short names besides letters and digits
+const SHORT_NAME_SPECIAL_CHARS: &str = "!#$%&'()-@^_`{}~";
+const MAX_LONG_NAME: usize = 255;
+/// What BS_VolLab holds when the volume has no label
+const NO_LABEL: [u8; 11] = *b"NO NAME    ";
+/// Offset of BS_VolLab in the boot sector
+const BS_VOL_LAB_OFFSET: usize = 71;
+/// Most clusters allocated at once while writing a file
+const MAX_ALLOCATION_BATCH: usize = 4096;
+
+/// Checks that `name` can be stored as a long name, returning it as UTF-16
+fn validate_name(name: &OsStr) -> Fat32Result<Vec<u16>> {
+    let invalid = || Fat32Error::InvalidName(name.to_string_lossy().into_owned());
+
+    let name = name.to_str().ok_or_else(invalid)?;
+
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(invalid());
+    }
+    if name.chars().any(|c| c.is_control() || INVALID_CHARS.contains(c)) {
+        return Err(invalid());
+    }
+    // Windows silently drops these, so such a name couldn't be opened again
+    if name.ends_with('.') || name.ends_with(' ') {
+        return Err(invalid());
+    }
+
+    let utf16 = name.encode_utf16().collect::<Vec<_>>();
+    if utf16.len() > MAX_LONG_NAME {
+        return Err(invalid());
+    }
+
+    Ok(utf16)
+}
+
+pub(crate) fn is_short_name_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_NAME_SPECIAL_CHARS.contains(c)
+}
+
+fn pad_short_name(base: &str, extension: &str) -> [u8; 11] {
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.as_bytes());
+    short_name[8..8 + extension.len()].copy_from_slice(extension.as_bytes());
+
+    // A leading 0xE5 would mark the entry as deleted, 0x05 stands in for it
+    if short_name[0] == DIR_ENTRY_FREE {
+        short_name[0] = 0x05;
+    }
+
+    short_name
+}
+
+/// The 8.3 name `name` is stored as if it doesn't need a long name, i.e. it's already a valid upper case 8.3 name
+fn as_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+
+    let valid = |part: &str, max_len: usize| part.len() <= max_len && part.chars().all(is_short_name_char);
+
+    (!base.is_empty() && valid(base, 8) && valid(extension, 3)).then(|| pad_short_name(base, extension))
+}
+
+/// Generates the short name alias of a long name, the way Windows does: the upper cased name is used if it's
+/// a valid 8.3 name, otherwise invalid characters become _ and a ~N tail is added until the name is unused.
+fn short_name_alias(name: &str, used: &HashSet<[u8; 11]>) -> Fat32Result<[u8; 11]> {
+    // Only the case was lost, the long name keeps it
+    if let Some(short_name) = as_short_name(&name.to_ascii_uppercase()).filter(|short_name| !used.contains(short_name)) {
+        return Ok(short_name);
+    }
+
+    let convert = |part: &str| part.chars()
+    .filter(|c| *c != ' ' && *c != '.')
+    .map(|c| {
+        let upper = c.to_ascii_uppercase();
+        if is_short_name_char(upper) { upper } else { '_' }
+    })
+    .collect::<String>();
+
+    let trimmed = name.trim_start_matches('.');
+    let (base, extension) = match trimmed.rsplit_once('.') {
+        Some((base, extension)) => (convert(base), convert(extension)),
+        None => (convert(trimmed), String::new()),
+    };
+
+    let base = if base.is_empty() { String::from("_") } else { base };
+    let extension = &extension[..usize::min(extension.len(), 3)];
+
+    for n in 1..1_000_000 {
+        let tail = format!("~{}", n);
+        let base = &base[..usize::min(base.len(), 8 - tail.len())];
+
+        let short_name = pad_short_name(&format!("{}{}", base, tail), extension);
+        if !used.contains(&short_name) {
+            return Ok(short_name);
+        }
+    }
+
+    Err(Fat32Error::AlreadyExists)
+}
+
+/// Reads until `buffer` is full or the reader runs out, returning how much was read
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut n_read = 0;
+
+    while n_read < buffer.len() {
+        match reader.read(&mut buffer[n_read..]) {
+            Ok(0) => break,
+            Ok(n) => n_read += n,
+            Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+            Err(error) => return Err(error),
+        }
+    }
+
+    Ok(n_read)
+}
+
+/// Splits `path` into its parent directory and last component
+fn split_path(path: &Path) -> Fat32Result<(&Path, &OsStr)> {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => Ok((parent, name)),
+        _=> Err(Fat32Error::InvalidName(path.display().to_string())),
+    }
+}
+
+impl Driver {
+    pub fn bpb(&self) -> &BPB {
+        &self.bpb
+    }
+    /// Adds an entry for `name` to `parent`, with long name entries if it isn't a plain 8.3 name.
+    /// Everything but the name is taken from `template`. `replacing` is an entry that's about to be removed,
+    /// which doesn't count as a conflict, so names can change case.
+    pub(crate) fn create_named_entry(&self, parent: &FatDirectory, name: &OsStr, template: &FatEntry, replacing: Option<EntryLocation>) -> Fat32Result<FatDirectory> {
+        let long_name = validate_name(name)?;
+        let name = name.to_str().unwrap();
+        let lowercase = name.to_lowercase();
+
+        let mut used = HashSet::new();
+
+        let mut files = self.files(parent);
+        while let Some(file) = files.next()? {
+            if file.is_deleted() || file.location() == replacing {
+                continue;
+            }
+
+            let conflicts = file.name().to_string_lossy().to_lowercase() == lowercase
+            || file.entry().short_name().to_string_lossy().to_lowercase() == lowercase;
+
+            if conflicts && !file.is_volume_id() {
+                return Err(Fat32Error::AlreadyExists);
+            }
+
+            used.insert(*file.entry().raw_name());
+        }
+
+        let (short_name, lfn_entries) = match as_short_name(name).filter(|short_name| !used.contains(short_name)) {
+            Some(short_name) => (short_name, vec![]),
+            None => {
+                let short_name = short_name_alias(name, &used)?;
+                let checksum = template.renamed(short_name).name_checksum();
+
+                (short_name, LFN::entries_for(&long_name, checksum))
+            }
+        };
+
+        let locations = self.free_entry_locations(parent, lfn_entries.len() + 1)?;
+
+        for (lfn, location) in lfn_entries.iter().zip(&locations) {
+            self.write_cluster(location.cluster, location.byte_offset(), &lfn.to_bytes())?;
+        }
+
+        let mut directory = FatDirectory::new(template.renamed(short_name), &lfn_entries);
+        directory.set_location(*locations.last().unwrap());
+
+        self.write_entry(&directory)?;
+        self.dentries.lock().invalidate_dir(self.directory_cluster(parent));
+
+        Ok(directory)
+    }
+    /// Every slot `directory` takes up in `parent`, its long name entries followed by the short name entry
+    pub fn entry_slots(&self, parent: &FatDirectory, directory: &FatDirectory) -> Fat32Result<Vec<EntryLocation>> {
+        let location = directory.location().ok_or(Fat32Error::InvalidName(String::from("/")))?;
+        let entries_per_cluster = self.bytes_per_cluster() / FAT32_DIR_SIZE;
+
+        let mut preceding = vec![];
+        for cluster in self.chain(self.directory_cluster(parent))? {
+            if cluster == location.cluster {
+                preceding.extend((0..location.index).map(|index| EntryLocation { cluster, index }));
+                break;
+            }
+            preceding.extend((0..entries_per_cluster).map(|index| EntryLocation { cluster, index }));
+        }
+
+        let checksum = directory.name_checksum();
+        let mut slots = vec![location];
+
+        let mut buf = [0; FAT32_DIR_SIZE];
+        for slot in preceding.iter().rev() {
+            self.read_cluster(slot.cluster, slot.byte_offset(), &mut buf)?;
+
+            let is_part = buf[11] & DIR_ATTR_LONG_FILE_NAME == DIR_ATTR_LONG_FILE_NAME
+            && buf[0] != DIR_ENTRY_FREE
+            && buf[13] == checksum;
+
+            if !is_part {
+                break;
+            }
+
+            slots.push(*slot);
+            if buf[0] & LAST_LONG_ENTRY != 0 {
+                break;
+            }
+        }
+
+        slots.reverse();
+
+        Ok(slots)
+    }
+    /// Marks the entry of `directory` and its long name entries as deleted, without freeing its clusters
+    pub(crate) fn delete_entry(&self, parent: &FatDirectory, directory: &FatDirectory) -> Fat32Result<()> {
+        for slot in self.entry_slots(parent, directory)? {
+            self.write_cluster(slot.cluster, slot.byte_offset(), &[DIR_ENTRY_FREE])?;
+            self.dentries.lock().invalidate_entry(slot);
+        }
+        self.dentries.lock().invalidate_dir(self.directory_cluster(parent));
+
+        Ok(())
+    }
+    /// Looks up the parent of `path`, which has to be a directory
+    fn parent_of<'p>(&self, path: &'p Path) -> Fat32Result<(FatDirectory, &'p OsStr)> {
+        let (parent_path, name) = split_path(path)?;
+        let parent = self.search_by_path(parent_path)?;
+
+        if !parent.is_dir() {
+            return Err(Fat32Error::NotADir);
+        }
+
+        Ok((parent, name))
+    }
+    pub fn remove_file(&self, path: &Path) -> Fat32Result<()> {
+        let (parent, name) = self.parent_of(path)?;
+        let file = self.search(&parent, name)?;
+
+        if file.is_dir() {
+            return Err(Fat32Error::IsDir);
+        }
+
+        self.delete_entry(&parent, &file)?;
+        if file.cluster_num() != 0 {
+            self.free_chain(file.cluster_num())?;
+        }
+
+        self.sync()
+    }
+    /// Removes an empty directory
+    pub fn remove_dir(&self, path: &Path) -> Fat32Result<()> {
+        let (parent, name) = self.parent_of(path)?;
+        let directory = self.search(&parent, name)?;
+
+        if !directory.is_dir() {
+            return Err(Fat32Error::NotADir);
+        }
+
+        let mut files = self.files(&directory);
+        while let Some(file) = files.next()? {
+            if is_listed(&file) {
+                return Err(Fat32Error::DirectoryNotEmpty);
+            }
+        }
+
+        self.delete_entry(&parent, &directory)?;
+        self.free_chain(directory.cluster_num())?;
+
+        self.sync()
+    }
+    /// Removes a directory and everything in it
+    pub fn remove_dir_all(&self, path: &Path) -> Fat32Result<()> {
+        let directory = self.search_by_path(path)?;
+        if !directory.is_dir() {
+            return Err(Fat32Error::NotADir);
+        }
+
+        let mut children = vec![];
+
+        let mut files = self.files(&directory);
+        while let Some(file) = files.next()? {
+            if is_listed(&file) {
+                children.push(file);
+            }
+        }
+
+        for child in children {
+            let child_path = path.join(child.name());
+
+            if child.is_dir() {
+                self.remove_dir_all(&child_path)?;
+            } else {
+                self.remove_file(&child_path)?;
+            }
+        }
+
+        self.remove_dir(path)
+    }
+    /// Creates an empty directory at `path`
+    pub fn mkdir(&self, path: &Path) -> Fat32Result<FatDirectory> {
+        let (parent, name) = self.parent_of(path)?;
+        validate_name(name)?;
+
+        let cluster = self.new_dir_cluster(&parent)?;
+        let template = FatEntry::new([b' '; 11], DIR_ATTR_DIRECTORY, cluster, 0);
+
+        let directory = match self.create_named_entry(&parent, name, &template, None) {
+            Ok(directory) => directory,
+            Err(error) => {
+                self.free_chain(cluster)?;
+                return Err(error);
+            }
+        };
+
+        self.sync()?;
+
+        Ok(directory)
+    }
+    /// Writes everything `reader` yields into a new chain, returning its first cluster (0 if empty) and the size.
+    /// `len` is how much `reader` is expected to yield, if known
+    fn write_chain<R: Read>(&self, reader: &mut R, len: Option<u64>) -> Fat32Result<(usize, u64)> {
+        let mut clusters = vec![];
+
+        match self.fill_chain(reader, len, &mut clusters) {
+            Ok(written) => Ok(written),
+            Err(error) => {
+                if let Some(first) = clusters.first() {
+                    self.free_chain(*first)?;
+                }
+                Err(error)
+            }
+        }
+    }
+    /// Does the work of `write_chain`, leaving what it allocated in `clusters` so it can be freed on failure
+    fn fill_chain<R: Read>(&self, reader: &mut R, len: Option<u64>, clusters: &mut Vec<usize>) -> Fat32Result<(usize, u64)> {
+        let mut buffer = vec![0; self.bytes_per_cluster()];
+        let mut n_used = 0;
+        let mut size: u64 = 0;
+
+        loop {
+            let n_read = read_full(reader, &mut buffer).map_err(Fat32Error::IOError)?;
+            if n_read == 0 {
+                break;
+            }
+
+            size += n_read as u64;
+            if size > u32::MAX as u64 {
+                return Err(Fat32Error::FileTooLarge);
+            }
+
+            if n_used == clusters.len() {
+                // A file of known length gets exactly what it needs in one go. Otherwise clusters are taken in
+                // growing batches, which keeps down the number of FAT writes
+                let batch = match len {
+                    Some(len) if clusters.is_empty() => usize::max(len.div_ceil(buffer.len() as u64) as usize, 1),
+                    _=> usize::clamp(clusters.len(), 16, MAX_ALLOCATION_BATCH),
+                };
+
+                let extents = match clusters.last() {
+                    Some(last) => self.extend(*last, batch, AllocationStrategy::NextFit)?,
+                    None => self.allocate(batch, AllocationStrategy::NextFit)?,
+                };
+                clusters.extend(extents.iter().flat_map(|extent| extent.clusters()));
+            }
+
+            buffer[n_read..].fill(0);
+            self.write_cluster(clusters[n_used], 0, &buffer)?;
+            n_used += 1;
+        }
+
+        // Give back what the last batch didn't need
+        if n_used < clusters.len() {
+            self.truncate_new_chain(clusters, n_used)?;
+            clusters.truncate(n_used);
+        }
+
+        Ok((clusters.first().copied().unwrap_or(0), size))
+    }
+    /// Writes `buffer` into `file` at `offset`, growing its chain as needed, and updates its entry. `clusters` is the
+    /// chain of the file, which is kept up to date too. Returns how much was written
+    pub(crate) fn write_file_at(&self, file: &mut FatDirectory, clusters: &mut Vec<usize>, offset: u64, buffer: &[u8]) -> Fat32Result<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+
+        let end = offset + buffer.len() as u64;
+        if end > u32::MAX as u64 {
+            return Err(Fat32Error::FileTooLarge);
+        }
+
+        let (offset, end) = (offset as usize, end as usize);
+        let bytes_per_cluster = self.bytes_per_cluster();
+        let size = file.file_size();
+
+        let needed = end.div_ceil(bytes_per_cluster);
+        if needed > clusters.len() {
+            let extents = match clusters.last() {
+                Some(last) => self.extend(*last, needed - clusters.len(), AllocationStrategy::NextFit)?,
+                None => self.allocate(needed, AllocationStrategy::NextFit)?,
+            };
+
+            // New clusters hold whatever was there before, what this write doesn't cover has to read as zeroes
+            for (ix, cluster) in (clusters.len()..).zip(extents.iter().flat_map(|extent| extent.clusters())) {
+                let covered = offset <= ix * bytes_per_cluster && end >= (ix + 1) * bytes_per_cluster;
+                if !covered {
+                    self.zero_cluster(cluster)?;
+                }
+                clusters.push(cluster);
+            }
+        }
+
+        // So does the gap a write past the end leaves in what was the last cluster
+        if offset > size && !size.is_multiple_of(bytes_per_cluster) {
+            let gap_end = usize::min(offset, size.next_multiple_of(bytes_per_cluster));
+            self.write_cluster(clusters[size / bytes_per_cluster], size % bytes_per_cluster, &vec![0; gap_end - size])?;
+        }
+
+        let mut written = 0;
+        while written < buffer.len() {
+            let position = offset + written;
+            let within = position % bytes_per_cluster;
+            let length = usize::min(bytes_per_cluster - within, buffer.len() - written);
+
+            self.write_cluster(clusters[position / bytes_per_cluster], within, &buffer[written..written + length])?;
+            written += length;
+        }
+
+        file.set_contents(clusters.first().copied().unwrap_or(0), usize::max(size, end) as u32);
+        self.write_entry(file)?;
+
+        Ok(written)
+    }
+    /// Creates a file at `path` with the contents of `reader`. `len` is how long the contents are, if known up front,
+    /// so the file is allocated exactly the clusters it needs
+    pub fn create_file<R: Read>(&self, path: &Path, mut reader: R, len: Option<u64>) -> Fat32Result<FatDirectory> {
+        let (parent, name) = self.parent_of(path)?;
+        validate_name(name)?;
+
+        if !matches!(self.search(&parent, name), Err(Fat32Error::NotFound)) {
+            return Err(Fat32Error::AlreadyExists);
+        }
+
+        let (cluster, size) = self.write_chain(&mut reader, len)?;
+
+        let template = FatEntry::new([b' '; 11], DIR_ATTR_ARCHIVE, cluster, size as u32);
+
+        let file = match self.create_named_entry(&parent, name, &template, None) {
+            Ok(file) => file,
+            Err(error) => {
+                if cluster != 0 {
+                    self.free_chain(cluster)?;
+                }
+                return Err(error);
+            }
+        };
+
+        self.sync()?;
+
+        Ok(file)
+    }
+    /// Replaces the contents of the file at `path` with those of `reader`, or creates it if it doesn't exist. The new
+    /// contents go into a chain of their own and the entry is switched over to it before the old chain is freed, so
+    /// running out of space or failing to read leaves the old file as it was
+    pub fn replace_file<R: Read>(&self, path: &Path, mut reader: R, len: Option<u64>) -> Fat32Result<FatDirectory> {
+        let (parent, name) = self.parent_of(path)?;
+
+        let mut file = match self.search(&parent, name) {
+            Err(Fat32Error::NotFound) => return self.create_file(path, reader, len),
+            found => found?,
+        };
+        if file.is_dir() {
+            return Err(Fat32Error::IsDir);
+        }
+
+        let (cluster, size) = self.write_chain(&mut reader, len)?;
+        let old_cluster = file.cluster_num();
+
+        file.set_contents(cluster, size as u32);
+        if let Err(error) = self.write_entry(&file) {
+            if cluster != 0 {
+                self.free_chain(cluster)?;
+            }
+            return Err(error);
+        }
+
+        if old_cluster != 0 {
+            self.free_chain(old_cluster)?;
+        }
+
+        self.sync()?;
+
+        Ok(file)
+    }
+    /// Moves or renames `from` to `to`, which must not exist yet, except as `from` under a different case
+    pub fn rename(&self, from: &Path, to: &Path) -> Fat32Result<FatDirectory> {
+        let (from_parent, from_name) = self.parent_of(from)?;
+        let (to_parent, to_name) = self.parent_of(to)?;
+
+        let source = self.search(&from_parent, from_name)?;
+        let moved = self.directory_cluster(&from_parent) != self.directory_cluster(&to_parent);
+
+        if source.is_dir() && moved && self.is_ancestor(&source, &to_parent)? {
+            return Err(Fat32Error::InvalidName(to.display().to_string()));
+        }
+
+        let replacing = (!moved).then(|| source.location()).flatten();
+        let renamed = self.create_named_entry(&to_parent, to_name, source.entry(), replacing)?;
+
+        self.delete_entry(&from_parent, &source)?;
+
+        if source.is_dir() && moved {
+            let dotdot_location = EntryLocation { cluster: source.cluster_num(), index: 1 };
+
+            if let Some(mut dotdot) = self.read_entry(dotdot_location)?.filter(|entry| entry.is_parent_dir()) {
+                dotdot.set_cluster_num(Self::dotdot_cluster(&to_parent));
+                self.write_entry(&dotdot)?;
+            }
+        }
+
+        // Lookups of `..` below a moved directory are cached too
+        self.invalidate_dentries();
+        self.sync()?;
+
+        Ok(renamed)
+    }
+    /// Checks if `directory` is `descendant` or one of its parents, following `..` entries up to the root
+    fn is_ancestor(&self, directory: &FatDirectory, descendant: &FatDirectory) -> Fat32Result<bool> {
+        let root = self.bpb.bpb_root_clus as usize;
+        let target = self.directory_cluster(directory);
+        let mut cluster = self.directory_cluster(descendant);
+
+        for _ in 0..self.cluster_count() {
+            if cluster == target {
+                return Ok(true);
+            }
+            if cluster == root {
+                return Ok(false);
+            }
+
+            let dotdot = self.read_entry(EntryLocation { cluster, index: 1 })?
+            .filter(|entry| entry.is_parent_dir())
+            .ok_or(Fat32Error::FileCorrupt)?;
+
+            cluster = match dotdot.cluster_num() {
+                0 => root,
+                parent => parent,
+            };
+        }
+
+        Err(Fat32Error::FileCorrupt)
+    }
+    fn volume_id_entry(&self) -> Fat32Result<Option<FatDirectory>> {
+        let mut files = self.files(&FatDirectory::root(self));
+        while let Some(file) = files.next()? {
+            if file.is_volume_id() && !file.is_deleted() {
+                return Ok(Some(file));
+            }
+        }
+
+        Ok(None)
+    }
+    /// The volume label, from the root directory like Windows reads it, or the boot sector if it has none there
+    pub fn label(&self) -> Fat32Result<Option<String>> {
+        let raw = match self.volume_id_entry()? {
+            Some(entry) => *entry.entry().raw_name(),
+            // The label may have changed since the boot sector was read
+            None => BPB::read_from(&self.drive)?.bs_vol_lab,
+        };
+
+        let label = String::from_utf8_lossy(&raw).trim_end().to_owned();
+
+        Ok((raw != NO_LABEL && !label.is_empty()).then_some(label))
+    }
+    /// Sets the label in the root directory and both boot sectors, None removes it
+    pub fn set_label(&self, label: Option<&str>) -> Fat32Result<()> {
+        let raw = match label {
+            Some(label) => {
+                let label = label.to_ascii_uppercase();
+
+                let valid = !label.is_empty() && label.len() <= 11 && label.chars().all(|c| {
+                    c.is_ascii() && !c.is_ascii_control() && !INVALID_CHARS.contains(c) && !LABEL_INVALID_CHARS.contains(c) && c != '.'
+                });
+                if !valid {
+                    return Err(Fat32Error::InvalidName(label));
+                }
+
+                let mut raw = [b' '; 11];
+                raw[..label.len()].copy_from_slice(label.as_bytes());
+                raw
+            },
+            None => NO_LABEL,
+        };
+
+        self.write_sector(0, BS_VOL_LAB_OFFSET, &raw)?;
+        if self.bpb.bpb_bk_boot_sec != 0 {
+            self.write_sector(self.bpb.bpb_bk_boot_sec as usize, BS_VOL_LAB_OFFSET, &raw)?;
+        }
+
+        let root = FatDirectory::root(self);
+
+        match (self.volume_id_entry()?, label) {
+            (Some(existing), Some(_)) => {
+                let mut relabeled = FatDirectory::new(existing.entry().renamed(raw), &[]);
+                relabeled.set_location(existing.location().unwrap());
+                self.write_entry(&relabeled)?;
+            },
+            (Some(existing), None) => self.delete_entry(&root, &existing)?,
+            (None, Some(_)) => {
+                self.create_entry(&root, FatEntry::new(raw, DIR_ATTR_VOLUME_ID, 0, 0))?;
+            },
+            (None, None) => {},
+        }
+
+        self.sync()
+    }
+}
@@ -0,0 +1,186 @@
+mod common;
+
+use common::{assert_clean, TestImage, CLUSTERS};
+use fat32::{AllocationStrategy, Driver, Extent, Fat32Error};
+
+fn extent(start: usize, length: usize) -> Extent {
+    Extent { start, length }
+}
+
+/// Allocates next-fit chains of the given lengths one after the other, returning their first clusters
+fn allocate_all(driver: &Driver, lengths: &[usize]) -> Vec<usize> {
+    lengths.iter()
+    .map(|length| driver.allocate(*length, AllocationStrategy::NextFit).unwrap()[0].start)
+    .collect()
+}
+
+/// Nothing points at the chains allocated here, so they have to be freed again before the volume checks clean
+fn free_all_and_check(driver: &Driver, starts: &[usize]) {
+    for start in starts {
+        driver.free_chain(*start).unwrap();
+    }
+    driver.sync().unwrap();
+
+    assert_eq!(driver.free_clusters().unwrap() as usize, CLUSTERS - 1);
+    assert_clean(driver);
+}
+
+#[test]
+fn next_fit_continues_after_the_previous_allocation() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    assert_eq!(driver.allocate(3, AllocationStrategy::NextFit).unwrap(), [extent(3, 3)]);
+    assert_eq!(driver.allocate(2, AllocationStrategy::NextFit).unwrap(), [extent(6, 2)]);
+
+    // Freed clusters behind the hint aren't handed out again until the allocator wraps around
+    driver.free_chain(3).unwrap();
+    assert_eq!(driver.allocate(2, AllocationStrategy::NextFit).unwrap(), [extent(8, 2)]);
+    assert_eq!(driver.chain(8).unwrap(), [8, 9]);
+
+    free_all_and_check(&driver, &[6, 8]);
+}
+
+#[test]
+fn next_fit_fragments_around_used_clusters() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    let starts = allocate_all(&driver, &[2, 1, 2]);
+    driver.free_chain(starts[0]).unwrap();
+    driver.free_chain(starts[2]).unwrap();
+
+    // Wrap around to the start of the volume
+    let last = driver.allocate(CLUSTERS - 1 - 5, AllocationStrategy::NextFit).unwrap();
+    assert_eq!(last, [extent(8, CLUSTERS - 1 - 5)]);
+
+    assert_eq!(driver.allocate(4, AllocationStrategy::NextFit).unwrap(), [extent(3, 2), extent(6, 2)]);
+    assert_eq!(driver.chain(3).unwrap(), [3, 4, 6, 7]);
+    assert!(matches!(driver.allocate(1, AllocationStrategy::NextFit), Err(Fat32Error::NoSpace)));
+
+    free_all_and_check(&driver, &[3, 5, 8]);
+}
+
+#[test]
+fn best_fit_takes_the_smallest_hole_that_fits() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    // Holes of 2, 5 and 3 clusters at 3, 6 and 12, with the rest of the volume free after 15
+    let starts = allocate_all(&driver, &[2, 1, 5, 1, 3, 1]);
+    for ix in [0, 2, 4] {
+        driver.free_chain(starts[ix]).unwrap();
+    }
+
+    assert_eq!(driver.allocate(3, AllocationStrategy::BestFit).unwrap(), [extent(12, 3)]);
+    assert_eq!(driver.allocate(2, AllocationStrategy::BestFit).unwrap(), [extent(3, 2)]);
+    assert_eq!(driver.allocate(4, AllocationStrategy::BestFit).unwrap(), [extent(6, 4)]);
+    assert_eq!(driver.allocate(6, AllocationStrategy::BestFit).unwrap(), [extent(16, 6)]);
+
+    free_all_and_check(&driver, &[5, 11, 15, 12, 3, 6, 16]);
+}
+
+#[test]
+fn best_fit_fills_the_largest_holes_when_nothing_fits() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    // Holes of 2, 3 and 1 clusters, with the volume full after them
+    let starts = allocate_all(&driver, &[2, 1, 3, 1, 1, 1, CLUSTERS - 1 - 9]);
+    for ix in [0, 2, 4] {
+        driver.free_chain(starts[ix]).unwrap();
+    }
+
+    assert_eq!(driver.allocate(5, AllocationStrategy::BestFit).unwrap(), [extent(6, 3), extent(3, 2)]);
+    assert!(matches!(driver.allocate(2, AllocationStrategy::BestFit), Err(Fat32Error::NoSpace)));
+
+    free_all_and_check(&driver, &[5, 9, 11, 12, 6]);
+}
+
+#[test]
+fn contiguous_never_returns_more_than_one_extent() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    let starts = allocate_all(&driver, &[2, 1, 3, 1, CLUSTERS - 1 - 7]);
+    driver.free_chain(starts[0]).unwrap();
+    driver.free_chain(starts[2]).unwrap();
+
+    assert!(matches!(driver.allocate(4, AllocationStrategy::Contiguous), Err(Fat32Error::NoSpace)));
+    assert_eq!(driver.allocate(2, AllocationStrategy::Contiguous).unwrap(), [extent(3, 2)]);
+    assert_eq!(driver.allocate(3, AllocationStrategy::Contiguous).unwrap(), [extent(6, 3)]);
+
+    free_all_and_check(&driver, &[3, 5, 6, 9, 10]);
+}
+
+#[test]
+fn reservations_are_kept_out_of_other_allocations() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    let reservation = driver.reserve(4).unwrap();
+    assert_eq!(reservation.extent, extent(3, 4));
+
+    assert_eq!(driver.allocate(2, AllocationStrategy::NextFit).unwrap(), [extent(7, 2)]);
+    assert_eq!(driver.allocate(2, AllocationStrategy::BestFit).unwrap(), [extent(9, 2)]);
+
+    let reserved = AllocationStrategy::Reserved(reservation.id);
+    assert_eq!(driver.allocate(3, reserved).unwrap(), [extent(3, 3)]);
+
+    // Once the reservation runs out, the chain goes on next-fit after it
+    let extended = driver.extend(5, 3, reserved).unwrap();
+    assert_eq!(extended, [extent(6, 1), extent(11, 2)]);
+    assert_eq!(driver.chain(3).unwrap(), [3, 4, 5, 6, 11, 12]);
+
+    driver.release(reservation.id).unwrap();
+    assert!(matches!(driver.release(reservation.id), Err(Fat32Error::NotFound)));
+    assert!(matches!(driver.allocate(1, reserved), Err(Fat32Error::NotFound)));
+
+    free_all_and_check(&driver, &[3, 7, 9]);
+}
+
+#[test]
+fn released_reservations_are_free_again() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    let reservation = driver.reserve(CLUSTERS - 1).unwrap();
+    assert!(matches!(driver.allocate(1, AllocationStrategy::NextFit), Err(Fat32Error::NoSpace)));
+    assert!(matches!(driver.reserve(1), Err(Fat32Error::NoSpace)));
+    assert!(matches!(driver.reserve(0), Err(Fat32Error::InvalidArgument(_))));
+
+    driver.release(reservation.id).unwrap();
+    assert_eq!(driver.allocate(1, AllocationStrategy::NextFit).unwrap(), [extent(3, 1)]);
+
+    free_all_and_check(&driver, &[3]);
+}
+
+#[test]
+fn extend_links_onto_the_end_of_a_chain() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    let starts = allocate_all(&driver, &[2, 1]);
+    assert_eq!(driver.extend(4, 2, AllocationStrategy::NextFit).unwrap(), [extent(6, 2)]);
+    assert_eq!(driver.chain(starts[0]).unwrap(), [3, 4, 6, 7]);
+    assert_eq!(driver.chain(starts[1]).unwrap(), [5]);
+
+    free_all_and_check(&driver, &starts);
+}
+
+#[test]
+fn free_count_survives_reopening() {
+    let image = TestImage::new();
+
+    let driver = image.driver();
+    allocate_all(&driver, &[10, 20]);
+    driver.free_chain(3).unwrap();
+    driver.sync().unwrap();
+    drop(driver);
+
+    let driver = image.driver();
+    assert_eq!(driver.free_clusters().unwrap() as usize, CLUSTERS - 1 - 20);
+    assert_eq!(driver.recompute_free_space().unwrap() as usize, CLUSTERS - 1 - 20);
+
+    free_all_and_check(&driver, &[13]);
+}
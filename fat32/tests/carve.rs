@@ -0,0 +1,37 @@
+mod common;
+
+use std::path::Path;
+
+use common::{create, TestImage, BYTES_PER_CLUSTER};
+use fat32::carve::{carve, write_carved, CarveOptions, FileKind};
+
+#[test]
+fn limits_the_size_of_complete_files() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    let mut pdf = b"%PDF-1.4\n".to_vec();
+    pdf.resize(3 * BYTES_PER_CLUSTER - 20, b' ');
+    pdf.extend_from_slice(b"%%EOF\n");
+    create(&driver, "/doc.pdf", &pdf);
+    driver.remove_file(Path::new("/doc.pdf")).unwrap();
+
+    let report = carve(&driver, &CarveOptions::default()).unwrap();
+    assert_eq!(report.files.len(), 1);
+    let file = &report.files[0];
+    assert_eq!(file.kind, FileKind::Pdf);
+    assert_eq!(file.size, pdf.len());
+    assert!(file.complete);
+
+    let mut carved = vec![];
+    write_carved(&driver, file, &mut carved).unwrap();
+    assert_eq!(carved, pdf);
+
+    // The end is found in the last cluster read, which is past the limit
+    let max_size = 2 * BYTES_PER_CLUSTER + 100;
+    let report = carve(&driver, &CarveOptions { kinds: vec![FileKind::Pdf], max_size: Some(max_size) }).unwrap();
+    let file = &report.files[0];
+    assert_eq!(file.size, max_size);
+    assert!(!file.complete);
+    assert_eq!(file.clusters.length, 3);
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use std::path::Path;
+
+use common::{assert_clean, create, TestImage};
+use fat32::check::{check, CheckIssue};
+
+#[test]
+fn reports_damaged_long_name_entries() {
+    let image = TestImage::new();
+    create(&image.driver(), "/a long name.txt", b"contents");
+
+    // The first characters of the name, then the attributes and a type byte that isn't 0
+    image.patch(b"a\0 \0l\0o\0n\0\x0F\x00", b"a\0 \0l\0o\0n\0\x0F\x07");
+
+    let report = check(&image.driver()).unwrap();
+    assert!(matches!(&report.issues[..], [CheckIssue::DamagedLongName { path }] if path == Path::new("/ALONGN~1.TXT")));
+
+    let fs = image.fs();
+    assert_eq!(fs.read("/ALONGN~1.TXT").unwrap(), b"contents");
+}
+
+#[test]
+fn decodes_invalid_utf16_in_long_names() {
+    let image = TestImage::new();
+    create(&image.driver(), "/a long name.txt", b"contents");
+
+    // A lone surrogate in place of the space
+    image.patch(b"a\0 \0l\0", b"a\0\x00\xD8l\0");
+
+    let driver = image.driver();
+    assert_clean(&driver);
+    assert_eq!(driver.read_to_end(Path::new("/a\u{FFFD}long name.txt")).unwrap(), b"contents");
+}
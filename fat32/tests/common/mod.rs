@@ -0,0 +1,144 @@
+// Not every test file uses every helper
+#![allow(dead_code)]
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use fat32::check::check;
+use fat32::{Drive, Driver, FatFs};
+
+pub const BYTES_PER_SECTOR: usize = 512;
+pub const BYTES_PER_CLUSTER: usize = BYTES_PER_SECTOR;
+pub const CLUSTERS: usize = 4096;
+pub const ROOT_CLUSTER: usize = 2;
+
+const RESERVED_SECTORS: usize = 32;
+const FAT_COUNT: usize = 2;
+const FAT_SECTORS: usize = ((CLUSTERS + 2) * 4).div_ceil(BYTES_PER_SECTOR);
+const FS_INFO_SECTOR: usize = 1;
+const BACKUP_BOOT_SECTOR: usize = 6;
+const DATA_START: usize = (RESERVED_SECTORS + FAT_COUNT * FAT_SECTORS) * BYTES_PER_SECTOR;
+
+static NEXT_IMAGE: AtomicUsize = AtomicUsize::new(0);
+
+/// A freshly formatted FAT32 image in a temporary file, removed again when dropped. There's no mkfs to make one with,
+/// so the boot sector, FSInfo and FATs are written by hand: 512 byte clusters, 2 FATs and an empty root directory
+pub struct TestImage {
+    path: PathBuf,
+}
+
+impl TestImage {
+    pub fn new() -> Self {
+        let name = format!("fat32-test-{}-{}.img", std::process::id(), NEXT_IMAGE.fetch_add(1, Ordering::Relaxed));
+        let image = Self { path: std::env::temp_dir().join(name) };
+
+        let file = File::create(&image.path).unwrap();
+        file.set_len((DATA_START + CLUSTERS * BYTES_PER_CLUSTER) as u64).unwrap();
+
+        let boot_sector = boot_sector();
+        file.write_all_at(&boot_sector, 0).unwrap();
+        file.write_all_at(&boot_sector, (BACKUP_BOOT_SECTOR * BYTES_PER_SECTOR) as u64).unwrap();
+        file.write_all_at(&fs_info(CLUSTERS as u32 - 1, 3), (FS_INFO_SECTOR * BYTES_PER_SECTOR) as u64).unwrap();
+
+        image.set_fat_entry(0, 0x0FFFFFF8);
+        image.set_fat_entry(1, 0x0FFFFFFF);
+        image.set_fat_entry(ROOT_CLUSTER, 0x0FFFFFFF);
+
+        image
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn driver(&self) -> Driver {
+        let file = OpenOptions::new().read(true).write(true).open(&self.path).unwrap();
+        Driver::new(Drive::from_file(file).unwrap()).unwrap()
+    }
+    pub fn fs(&self) -> FatFs {
+        FatFs::new(self.driver())
+    }
+    /// Writes a FAT entry to every copy of the FAT behind the driver's back, to corrupt the volume
+    pub fn set_fat_entry(&self, cluster: usize, value: u32) {
+        let file = OpenOptions::new().write(true).open(&self.path).unwrap();
+
+        for fat in 0..FAT_COUNT {
+            let offset = (RESERVED_SECTORS + fat * FAT_SECTORS) * BYTES_PER_SECTOR + cluster * 4;
+            file.write_all_at(&value.to_le_bytes(), offset as u64).unwrap();
+        }
+    }
+    pub fn set_free_count(&self, free_count: u32) {
+        let file = OpenOptions::new().write(true).open(&self.path).unwrap();
+        file.write_all_at(&free_count.to_le_bytes(), (FS_INFO_SECTOR * BYTES_PER_SECTOR + 488) as u64).unwrap();
+    }
+    /// Replaces the first occurrence of `from` in the image with `to`, which must be as long
+    pub fn patch(&self, from: &[u8], to: &[u8]) {
+        assert_eq!(from.len(), to.len());
+
+        let mut contents = std::fs::read(&self.path).unwrap();
+        let offset = contents.windows(from.len()).position(|window| window == from).expect("bytes to patch not found");
+        contents[offset..offset + to.len()].copy_from_slice(to);
+
+        std::fs::write(&self.path, contents).unwrap();
+    }
+}
+
+impl Drop for TestImage {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn boot_sector() -> Vec<u8> {
+    let mut sector = vec![0; BYTES_PER_SECTOR];
+    let total_sectors = DATA_START / BYTES_PER_SECTOR + CLUSTERS;
+
+    sector[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+    sector[3..11].copy_from_slice(b"MSWIN4.1");
+    sector[11..13].copy_from_slice(&(BYTES_PER_SECTOR as u16).to_le_bytes());
+    sector[13] = (BYTES_PER_CLUSTER / BYTES_PER_SECTOR) as u8;
+    sector[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    sector[16] = FAT_COUNT as u8;
+    sector[21] = 0xF8;
+    sector[24..26].copy_from_slice(&32u16.to_le_bytes());
+    sector[26..28].copy_from_slice(&64u16.to_le_bytes());
+    sector[32..36].copy_from_slice(&(total_sectors as u32).to_le_bytes());
+    sector[36..40].copy_from_slice(&(FAT_SECTORS as u32).to_le_bytes());
+    sector[44..48].copy_from_slice(&(ROOT_CLUSTER as u32).to_le_bytes());
+    sector[48..50].copy_from_slice(&(FS_INFO_SECTOR as u16).to_le_bytes());
+    sector[50..52].copy_from_slice(&(BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+    sector[64] = 0x80;
+    sector[66] = 0x29;
+    sector[67..71].copy_from_slice(&0x1234ABCDu32.to_le_bytes());
+    sector[71..82].copy_from_slice(b"NO NAME    ");
+    sector[82..90].copy_from_slice(b"FAT32   ");
+    sector[510..512].copy_from_slice(&[0x55, 0xAA]);
+
+    sector
+}
+
+fn fs_info(free_count: u32, next_free: u32) -> Vec<u8> {
+    let mut sector = vec![0; BYTES_PER_SECTOR];
+
+    sector[0..4].copy_from_slice(&0x41615252u32.to_le_bytes());
+    sector[484..488].copy_from_slice(&0x61417272u32.to_le_bytes());
+    sector[488..492].copy_from_slice(&free_count.to_le_bytes());
+    sector[492..496].copy_from_slice(&next_free.to_le_bytes());
+    sector[508..512].copy_from_slice(&0xAA550000u32.to_le_bytes());
+
+    sector
+}
+
+pub fn assert_clean(driver: &Driver) {
+    let report = check(driver).unwrap();
+    assert!(report.is_clean(), "{}", report);
+}
+
+/// `len` bytes that differ from file to file
+pub fn contents(seed: u8, len: usize) -> Vec<u8> {
+    (0..len).map(|ix| (ix as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+}
+
+pub fn create(driver: &Driver, path: &str, contents: &[u8]) {
+    driver.create_file(Path::new(path), contents, Some(contents.len() as u64)).unwrap();
+}
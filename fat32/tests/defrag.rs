@@ -0,0 +1,161 @@
+mod common;
+
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use common::{assert_clean, contents, create, TestImage, BYTES_PER_CLUSTER};
+use fat32::defrag::{defrag, fragmentation, DefragOptions};
+use fat32::{Driver, Fat32Error};
+
+fn append(driver: &Driver, path: &str, data: &[u8]) {
+    let mut file = driver.open_file_mut(Path::new(path)).unwrap();
+    file.seek(SeekFrom::End(0)).unwrap();
+    file.write_all(data).unwrap();
+    file.flush().unwrap();
+}
+
+fn fragmented_paths(driver: &Driver) -> Vec<PathBuf> {
+    fragmentation(driver).unwrap().fragmented.into_iter().map(|chain| chain.path).collect()
+}
+
+/// Writes `/a.bin` in two parts with `/b.bin` in between, returning the contents of both
+fn fragmented_file(driver: &Driver) -> (Vec<u8>, Vec<u8>) {
+    let a = contents(1, 4 * BYTES_PER_CLUSTER + 100);
+    let b = contents(2, BYTES_PER_CLUSTER);
+
+    create(driver, "/a.bin", &a[..2 * BYTES_PER_CLUSTER]);
+    create(driver, "/b.bin", &b);
+    append(driver, "/a.bin", &a[2 * BYTES_PER_CLUSTER..]);
+
+    (a, b)
+}
+
+#[test]
+fn makes_fragmented_files_contiguous() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    let (a, b) = fragmented_file(&driver);
+    assert_eq!(fragmented_paths(&driver), [Path::new("/a.bin")]);
+    assert_clean(&driver);
+
+    let report = defrag(&driver, &DefragOptions::default()).unwrap();
+    assert!(report.skipped.is_empty());
+    assert_eq!(report.moved.len(), 1);
+    assert_eq!(report.moved[0].path, Path::new("/a.bin"));
+    assert_eq!(report.moved[0].to.len(), 1);
+    assert!(report.after.fragmented.is_empty());
+    drop(driver);
+
+    let driver = image.driver();
+    assert_clean(&driver);
+    assert!(fragmented_paths(&driver).is_empty());
+    assert_eq!(driver.read_to_end(Path::new("/a.bin")).unwrap(), a);
+    assert_eq!(driver.read_to_end(Path::new("/b.bin")).unwrap(), b);
+}
+
+#[test]
+fn moves_directories() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    // A cluster holds 16 entries, the directory grows past its first cluster after the files in it
+    driver.mkdir(Path::new("/DIR")).unwrap();
+    let files = (0..24u8).map(|ix| contents(ix, 100)).collect::<Vec<_>>();
+    for (ix, data) in files.iter().enumerate() {
+        create(&driver, &format!("/DIR/FILE{:02}.TXT", ix), data);
+    }
+    assert_eq!(fragmented_paths(&driver), [Path::new("/DIR")]);
+
+    defrag(&driver, &DefragOptions::default()).unwrap();
+    drop(driver);
+
+    let driver = image.driver();
+    assert_clean(&driver);
+    assert!(fragmented_paths(&driver).is_empty());
+    for (ix, data) in files.iter().enumerate() {
+        assert_eq!(&driver.read_to_end(Path::new(&format!("/DIR/FILE{:02}.TXT", ix))).unwrap(), data);
+    }
+}
+
+#[test]
+fn compacts_free_space() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    let files = (0..4u8).map(|ix| contents(ix, 3 * BYTES_PER_CLUSTER)).collect::<Vec<_>>();
+    for (ix, data) in files.iter().enumerate() {
+        create(&driver, &format!("/FILE{}.BIN", ix), data);
+    }
+    driver.remove_file(Path::new("/FILE0.BIN")).unwrap();
+    driver.remove_file(Path::new("/FILE2.BIN")).unwrap();
+    assert_eq!(fragmentation(&driver).unwrap().free_runs, 3);
+
+    let report = defrag(&driver, &DefragOptions { compact: true, ..Default::default() }).unwrap();
+    assert_eq!(report.after.free_runs, 1);
+    drop(driver);
+
+    let driver = image.driver();
+    assert_clean(&driver);
+    assert_eq!(fragmentation(&driver).unwrap().free_runs, 1);
+    assert_eq!(driver.read_to_end(Path::new("/FILE1.BIN")).unwrap(), files[1]);
+    assert_eq!(driver.read_to_end(Path::new("/FILE3.BIN")).unwrap(), files[3]);
+}
+
+#[test]
+fn only_moves_the_selected_paths() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    let (a, _) = fragmented_file(&driver);
+    let c = contents(3, 2 * BYTES_PER_CLUSTER);
+    create(&driver, "/c.bin", &c[..BYTES_PER_CLUSTER]);
+    create(&driver, "/d.bin", b"d");
+    append(&driver, "/c.bin", &c[BYTES_PER_CLUSTER..]);
+    assert_eq!(fragmented_paths(&driver), [Path::new("/a.bin"), Path::new("/c.bin")]);
+
+    let options = DefragOptions { paths: vec![PathBuf::from("/c.bin")], ..Default::default() };
+    let report = defrag(&driver, &options).unwrap();
+    assert_eq!(report.moved.len(), 1);
+    assert_eq!(report.moved[0].path, Path::new("/c.bin"));
+    drop(driver);
+
+    let driver = image.driver();
+    assert_clean(&driver);
+    assert_eq!(fragmented_paths(&driver), [Path::new("/a.bin")]);
+    assert_eq!(driver.read_to_end(Path::new("/a.bin")).unwrap(), a);
+    assert_eq!(driver.read_to_end(Path::new("/c.bin")).unwrap(), c);
+}
+
+#[test]
+fn recounts_a_stale_free_count() {
+    let image = TestImage::new();
+    let driver = image.driver();
+    let (a, _) = fragmented_file(&driver);
+    drop(driver);
+
+    image.set_free_count(7);
+
+    let driver = image.driver();
+    defrag(&driver, &DefragOptions::default()).unwrap();
+    drop(driver);
+
+    let driver = image.driver();
+    assert_clean(&driver);
+    assert_eq!(driver.read_to_end(Path::new("/a.bin")).unwrap(), a);
+}
+
+#[test]
+fn refuses_inconsistent_volumes() {
+    let image = TestImage::new();
+    let driver = image.driver();
+    fragmented_file(&driver);
+    drop(driver);
+
+    image.set_fat_entry(100, 0x0FFFFFFF);
+
+    let driver = image.driver();
+    let before = driver.extents(driver.search_by_path(Path::new("/a.bin")).unwrap().cluster_num()).unwrap();
+    assert!(matches!(defrag(&driver, &DefragOptions::default()), Err(Fat32Error::Inconsistent)));
+    assert_eq!(driver.extents(driver.search_by_path(Path::new("/a.bin")).unwrap().cluster_num()).unwrap(), before);
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use common::{assert_clean, contents, create, TestImage, BYTES_PER_CLUSTER};
+use fat32::{Fat32Error, DIR_ATTR_ARCHIVE, DIR_ATTR_READ_ONLY};
+
+#[test]
+fn reads_and_seeks() {
+    let image = TestImage::new();
+    let data = contents(1, 3 * BYTES_PER_CLUSTER + 10);
+    create(&image.driver(), "/file.bin", &data);
+
+    let driver = image.driver();
+    let mut file = driver.open_file(Path::new("/file.bin")).unwrap();
+    assert_eq!(file.len(), data.len() as u64);
+
+    let mut buffer = vec![0; 20];
+    file.seek(SeekFrom::Start(BYTES_PER_CLUSTER as u64 - 10)).unwrap();
+    file.read_exact(&mut buffer).unwrap();
+    assert_eq!(buffer, data[BYTES_PER_CLUSTER - 10..BYTES_PER_CLUSTER + 10]);
+
+    file.seek(SeekFrom::End(-5)).unwrap();
+    let mut rest = vec![];
+    file.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, data[data.len() - 5..]);
+
+    assert!(file.write(b"no").is_err());
+}
+
+#[test]
+fn writes_within_and_past_the_end() {
+    let image = TestImage::new();
+    let mut data = contents(2, BYTES_PER_CLUSTER + 100);
+    create(&image.driver(), "/file.bin", &data);
+
+    let driver = image.driver();
+    let mut file = driver.open_file_mut(Path::new("/file.bin")).unwrap();
+
+    file.seek(SeekFrom::Start(50)).unwrap();
+    file.write_all(b"overwritten").unwrap();
+    data[50..61].copy_from_slice(b"overwritten");
+
+    // Past the end, the gap reads back as zeros
+    let appended = contents(3, 2 * BYTES_PER_CLUSTER);
+    file.seek(SeekFrom::Start(3 * BYTES_PER_CLUSTER as u64 + 7)).unwrap();
+    file.write_all(&appended).unwrap();
+    data.resize(3 * BYTES_PER_CLUSTER + 7, 0);
+    data.extend_from_slice(&appended);
+
+    drop(file);
+    drop(driver);
+
+    let driver = image.driver();
+    assert_clean(&driver);
+    assert_eq!(driver.read_to_end(Path::new("/file.bin")).unwrap(), data);
+}
+
+#[test]
+fn writes_to_empty_files() {
+    let image = TestImage::new();
+    create(&image.driver(), "/empty.txt", b"");
+
+    let driver = image.driver();
+    let mut file = driver.open_file_mut(Path::new("/empty.txt")).unwrap();
+    file.write_all(b"no longer empty").unwrap();
+    file.flush().unwrap();
+    drop(file);
+    drop(driver);
+
+    let driver = image.driver();
+    assert_clean(&driver);
+    assert_eq!(driver.read_to_end(Path::new("/empty.txt")).unwrap(), b"no longer empty");
+}
+
+#[test]
+fn refuses_to_write_read_only_files() {
+    let image = TestImage::new();
+    let driver = image.driver();
+    create(&driver, "/locked.txt", b"locked");
+    driver.set_attributes(Path::new("/locked.txt"), DIR_ATTR_ARCHIVE | DIR_ATTR_READ_ONLY).unwrap();
+
+    assert!(matches!(driver.open_file_mut(Path::new("/locked.txt")), Err(Fat32Error::ReadOnly)));
+
+    let error = std::io::Error::from(Fat32Error::ReadOnly);
+    assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+}
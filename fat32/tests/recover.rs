@@ -0,0 +1,170 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use common::{assert_clean, contents, create, TestImage, BYTES_PER_CLUSTER};
+use fat32::recover::{deleted_entries, extract, restore, DeletedEntry, Recoverability};
+use fat32::{AllocationStrategy, Fat32Error, FatFs};
+
+fn deleted(fs: &FatFs, recursive: bool) -> Vec<DeletedEntry> {
+    let deleted = deleted_entries(fs, &[PathBuf::from("/")], recursive).unwrap();
+    assert!(deleted.unreadable.is_empty());
+
+    deleted.entries
+}
+
+fn find<'e>(entries: &'e [DeletedEntry], path: &str) -> &'e DeletedEntry {
+    entries.iter().find(|entry| entry.path == Path::new(path)).unwrap_or_else(|| panic!("{} wasn't listed", path))
+}
+
+#[test]
+fn restores_deleted_files() {
+    let image = TestImage::new();
+    let data = contents(1, 3 * BYTES_PER_CLUSTER + 5);
+
+    let driver = image.driver();
+    create(&driver, "/notes from monday.txt", &data);
+    create(&driver, "/kept.txt", b"kept");
+    driver.remove_file(Path::new("/notes from monday.txt")).unwrap();
+    drop(driver);
+
+    let fs = image.fs();
+    let entries = deleted(&fs, false);
+    assert_eq!(entries.len(), 1);
+
+    let entry = find(&entries, "/notes from monday.txt");
+    assert_eq!(entry.long_name.as_deref(), Some("notes from monday.txt"));
+    assert!(entry.long_name_complete);
+    assert!(entry.first_char_verified);
+    assert_eq!(entry.recoverability, Recoverability::Recoverable);
+    assert_eq!(entry.clusters.len(), 4);
+
+    let mut extracted = vec![];
+    assert_eq!(extract(fs.driver(), entry, &mut extracted).unwrap(), data.len() as u64);
+    assert_eq!(extracted, data);
+
+    restore(&fs, entry, None).unwrap();
+    fs.driver().sync().unwrap();
+    drop(fs);
+
+    let fs = image.fs();
+    assert_clean(fs.driver());
+    assert_eq!(fs.read("/notes from monday.txt").unwrap(), data);
+    assert_eq!(fs.read("/kept.txt").unwrap(), b"kept");
+    assert!(deleted(&fs, false).is_empty());
+}
+
+#[test]
+fn keeps_the_short_name_when_the_long_name_was_cut_short() {
+    let image = TestImage::new();
+    let data = contents(5, BYTES_PER_CLUSTER);
+
+    let driver = image.driver();
+    create(&driver, "/Long Name File.txt", &data);
+    driver.remove_file(Path::new("/Long Name File.txt")).unwrap();
+    // The label takes the first free slot, which held the end of the long name
+    driver.set_label(Some("MYVOL")).unwrap();
+    drop(driver);
+
+    let fs = image.fs();
+    let entries = deleted(&fs, false);
+    let entry = find(&entries, "/Long Name Fil");
+    assert!(!entry.long_name_complete);
+    assert!(entry.to_string().contains("long name may be cut short"));
+
+    let restored = restore(&fs, entry, None).unwrap();
+    assert_eq!(restored.name(), "LONGNA~1.TXT");
+    fs.driver().sync().unwrap();
+    drop(fs);
+
+    let fs = image.fs();
+    assert_clean(fs.driver());
+    assert_eq!(fs.read("/LONGNA~1.TXT").unwrap(), data);
+}
+
+#[test]
+fn restores_deleted_directories_before_their_contents() {
+    let image = TestImage::new();
+    let data = contents(2, 2 * BYTES_PER_CLUSTER);
+
+    let driver = image.driver();
+    driver.mkdir(Path::new("/photos")).unwrap();
+    driver.mkdir(Path::new("/photos/summer 2024")).unwrap();
+    create(&driver, "/photos/summer 2024/beach.jpg", &data);
+    driver.remove_dir_all(Path::new("/photos")).unwrap();
+    drop(driver);
+
+    let fs = image.fs();
+    assert_eq!(deleted(&fs, false).len(), 1);
+
+    let entries = deleted(&fs, true);
+    let paths = entries.iter().map(|entry| entry.path.as_path()).collect::<Vec<_>>();
+    assert_eq!(paths, [Path::new("/photos"), Path::new("/photos/summer 2024"), Path::new("/photos/summer 2024/beach.jpg")]);
+    assert!(find(&entries, "/photos").is_dir);
+
+    // The directories it's in have to come back first
+    let file = find(&entries, "/photos/summer 2024/beach.jpg");
+    assert!(matches!(restore(&fs, file, None), Err(Fat32Error::NotFound)));
+
+    for path in ["/photos", "/photos/summer 2024", "/photos/summer 2024/beach.jpg"] {
+        restore(&fs, find(&entries, path), None).unwrap();
+    }
+    fs.driver().sync().unwrap();
+    drop(fs);
+
+    let fs = image.fs();
+    assert_clean(fs.driver());
+    assert_eq!(fs.read("/photos/summer 2024/beach.jpg").unwrap(), data);
+}
+
+#[test]
+fn restores_empty_files() {
+    let image = TestImage::new();
+
+    let driver = image.driver();
+    create(&driver, "/EMPTY.TXT", b"");
+    driver.remove_file(Path::new("/EMPTY.TXT")).unwrap();
+    drop(driver);
+
+    let fs = image.fs();
+    let entries = deleted(&fs, false);
+    let entry = find(&entries, "/_MPTY.TXT");
+    assert_eq!(entry.recoverability, Recoverability::Empty);
+    assert!(!entry.first_char_verified);
+
+    restore(&fs, entry, Some(b'E')).unwrap();
+    fs.driver().sync().unwrap();
+    drop(fs);
+
+    let fs = image.fs();
+    assert_clean(fs.driver());
+    assert_eq!(fs.read("/EMPTY.TXT").unwrap(), b"");
+}
+
+#[test]
+fn refuses_to_restore_reallocated_clusters() {
+    let image = TestImage::new();
+
+    let driver = image.driver();
+    create(&driver, "/first.bin", &contents(3, 2 * BYTES_PER_CLUSTER));
+    create(&driver, "/second.bin", &contents(4, BYTES_PER_CLUSTER));
+    driver.remove_file(Path::new("/first.bin")).unwrap();
+
+    // The hole first.bin left is the best fit
+    let taken = driver.allocate(1, AllocationStrategy::BestFit).unwrap()[0].start;
+    let fs = FatFs::new(driver);
+
+    let entries = deleted(&fs, false);
+    let entry = find(&entries, "/first.bin");
+    assert_eq!(entry.clusters[0], taken);
+    assert_eq!(entry.recoverability, Recoverability::Partial { free: 1, total: 2 });
+    assert!(matches!(restore(&fs, entry, None), Err(Fat32Error::ClusterInUse(cluster)) if cluster as usize == taken));
+
+    fs.driver().free_chain(taken).unwrap();
+    fs.driver().sync().unwrap();
+    drop(fs);
+
+    let fs = image.fs();
+    assert_clean(fs.driver());
+    assert!(!fs.exists("/first.bin"));
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use std::path::Path;
+
+use common::{assert_clean, contents, create, TestImage, BYTES_PER_CLUSTER};
+use fat32::check::{check, CheckIssue};
+use fat32::repair::{plan, repair, LostChainPolicy, RepairOptions};
+use fat32::Driver;
+
+fn check_and_repair(driver: &Driver, options: &RepairOptions) {
+    let report = check(driver).unwrap();
+    assert!(!report.is_clean());
+
+    let plan = plan(driver, &report, options).unwrap();
+    assert!(plan.skipped.is_empty(), "{:?}", plan.skipped);
+
+    let log = repair(driver, &plan).unwrap();
+    assert!(log.entries.iter().all(|entry| entry.error.is_none()), "{:?}", log.entries);
+
+    driver.sync().unwrap();
+}
+
+#[test]
+fn fresh_image_is_clean() {
+    let image = TestImage::new();
+    assert_clean(&image.driver());
+}
+
+#[test]
+fn frees_lost_chains() {
+    let image = TestImage::new();
+    image.set_fat_entry(100, 101);
+    image.set_fat_entry(101, 102);
+    image.set_fat_entry(102, 0x0FFFFFFF);
+
+    let driver = image.driver();
+    let report = check(&driver).unwrap();
+    assert!(report.issues.iter().any(|issue| matches!(issue, CheckIssue::LostChain { start: 100, length: 3 })));
+
+    check_and_repair(&driver, &RepairOptions::default());
+
+    assert_clean(&image.driver());
+}
+
+#[test]
+fn salvages_lost_chains() {
+    let image = TestImage::new();
+    let data = contents(1, 2 * BYTES_PER_CLUSTER);
+
+    let driver = image.driver();
+    create(&driver, "/LOST.BIN", &data);
+    let first_cluster = driver.search_by_path(Path::new("/LOST.BIN")).unwrap().cluster_num();
+    drop(driver);
+
+    // Losing the entry leaves the chain behind, the name needs no long name entries to lose with it
+    image.patch(b"LOST    BIN", b"\xE5OST    BIN");
+
+    let driver = image.driver();
+    check_and_repair(&driver, &RepairOptions { lost_chains: LostChainPolicy::Salvage });
+
+    let driver = image.driver();
+    assert_clean(&driver);
+
+    let salvaged = driver.search_by_path(Path::new("/FOUND.000/FILE0000.CHK")).unwrap();
+    assert_eq!(salvaged.cluster_num(), first_cluster);
+    assert_eq!(driver.read_to_end(Path::new("/FOUND.000/FILE0000.CHK")).unwrap(), data);
+}
+
+#[test]
+fn recomputes_fs_info() {
+    let image = TestImage::new();
+    image.set_free_count(7);
+
+    let driver = image.driver();
+    let report = check(&driver).unwrap();
+    assert!(matches!(report.issues[..], [CheckIssue::FsInfoMismatch { free_count: 7, .. }]));
+
+    check_and_repair(&driver, &RepairOptions::default());
+
+    assert_clean(&image.driver());
+}
+
+#[test]
+fn ends_chains_that_run_into_free_clusters() {
+    let image = TestImage::new();
+    let data = contents(2, 3 * BYTES_PER_CLUSTER);
+
+    let driver = image.driver();
+    create(&driver, "/broken.bin", &data);
+    let clusters = driver.chain(driver.search_by_path(Path::new("/broken.bin")).unwrap().cluster_num()).unwrap();
+    drop(driver);
+
+    image.set_fat_entry(clusters[1], 0);
+
+    check_and_repair(&image.driver(), &RepairOptions::default());
+
+    let driver = image.driver();
+    assert_clean(&driver);
+
+    let repaired = driver.read_to_end(Path::new("/broken.bin")).unwrap();
+    assert_eq!(repaired[..BYTES_PER_CLUSTER], data[..BYTES_PER_CLUSTER]);
+}
+
+#[test]
+fn syncs_fat_copies() {
+    let image = TestImage::new();
+    let driver = image.driver();
+    create(&driver, "/file.txt", b"contents");
+    drop(driver);
+
+    // Only the second copy
+    let mut bytes = std::fs::read(image.path()).unwrap();
+    let fat_sectors = u32::from_le_bytes(bytes[36..40].try_into().unwrap()) as usize;
+    let offset = (32 + fat_sectors) * 512 + 200 * 4;
+    bytes[offset..offset + 4].copy_from_slice(&0x0FFFFFFFu32.to_le_bytes());
+    std::fs::write(image.path(), bytes).unwrap();
+
+    let driver = image.driver();
+    let report = check(&driver).unwrap();
+    assert!(report.issues.iter().any(|issue| matches!(issue, CheckIssue::FatMismatch { fat: 1, .. })));
+
+    check_and_repair(&driver, &RepairOptions::default());
+
+    let driver = image.driver();
+    assert_clean(&driver);
+    assert_eq!(driver.read_to_end(Path::new("/file.txt")).unwrap(), b"contents");
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use std::path::Path;
+
+use chrono::FixedOffset;
+use common::{create, TestImage};
+use fat32::timeline::{timeline, TimelineEntry, TimestampZone};
+
+fn entry(image: &TestImage, path: &str, zone: TimestampZone) -> TimelineEntry {
+    let fs = image.fs();
+
+    timeline(&fs, zone, false).unwrap().into_iter()
+    .find(|entry| entry.path == Path::new(path))
+    .unwrap_or_else(|| panic!("{} isn't in the timeline", path))
+}
+
+#[test]
+fn decodes_timestamps_into_body_file_lines() {
+    let image = TestImage::new();
+    create(&image.driver(), "/STAMP.TXT", b"stamp");
+
+    let contents = std::fs::read(image.path()).unwrap();
+    let offset = contents.windows(11).position(|window| window == b"STAMP   TXT").unwrap();
+    let old = &contents[offset..offset + 32];
+
+    let mut new = old.to_vec();
+    // Created 2024-03-15 23:59:58 and 150 hundredths, so 23:59:59.5
+    new[13] = 150;
+    new[14..16].copy_from_slice(&(23 << 11 | 59 << 5 | 29u16).to_le_bytes());
+    new[16..18].copy_from_slice(&(44 << 9 | 3 << 5 | 15u16).to_le_bytes());
+    // Accessed 2024-03-16, modified 2024-03-16 13:07:42
+    new[18..20].copy_from_slice(&(44 << 9 | 3 << 5 | 16u16).to_le_bytes());
+    new[22..24].copy_from_slice(&(13 << 11 | 7 << 5 | 21u16).to_le_bytes());
+    new[24..26].copy_from_slice(&(44 << 9 | 3 << 5 | 16u16).to_le_bytes());
+    image.patch(old, &new);
+
+    let utc = entry(&image, "/STAMP.TXT", TimestampZone::Utc);
+    assert_eq!(utc.accessed, Some(1710547200));
+    assert_eq!(utc.modified, Some(1710594462));
+    assert_eq!(utc.created, Some(1710547199));
+    assert_eq!(utc.created_millis, 500);
+    // The root directory is the first cluster after the reserved sectors and FATs, and the file its first entry
+    let address = (32 + 2 * 33) * 512 / 32;
+    assert_eq!(utc.to_string(), format!("0|/STAMP.TXT|{}|r/rrwxrwxrwx|0|0|5|1710547200|1710594462|0|1710547199", address));
+
+    // The same wall clock times two hours ahead of UTC
+    let offset = entry(&image, "/STAMP.TXT", TimestampZone::Offset(FixedOffset::east_opt(2 * 3600).unwrap()));
+    assert_eq!(offset.accessed, Some(1710547200 - 7200));
+    assert_eq!(offset.modified, Some(1710594462 - 7200));
+    assert_eq!(offset.created, Some(1710547199 - 7200));
+    assert_eq!(offset.to_string(), format!("0|/STAMP.TXT|{}|r/rrwxrwxrwx|0|0|5|1710540000|1710587262|0|1710539999", address));
+}
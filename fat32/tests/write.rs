@@ -0,0 +1,224 @@
+mod common;
+
+use std::path::Path;
+
+use common::{assert_clean, contents, create, TestImage, BYTES_PER_CLUSTER, CLUSTERS};
+use fat32::{Fat32Error, FatFs};
+
+fn names(fs: &FatFs, path: &str) -> Vec<String> {
+    let mut names = fs.read_dir(path).unwrap()
+    .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+    .collect::<Vec<_>>();
+    names.sort();
+
+    names
+}
+
+#[test]
+fn creates_files_and_directories() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    let data = contents(1, 3 * BYTES_PER_CLUSTER + 17);
+    driver.mkdir(Path::new("/docs")).unwrap();
+    driver.mkdir(Path::new("/docs/nested dir")).unwrap();
+    create(&driver, "/docs/A long file name.txt", &data);
+    create(&driver, "/docs/nested dir/SHORT.TXT", b"short");
+    create(&driver, "/empty", b"");
+    drop(driver);
+
+    let fs = image.fs();
+    assert_clean(fs.driver());
+    assert_eq!(names(&fs, "/"), ["docs", "empty"]);
+    assert_eq!(names(&fs, "/docs"), ["A long file name.txt", "nested dir"]);
+    assert_eq!(fs.read("/docs/A long file name.txt").unwrap(), data);
+    assert_eq!(fs.read("/docs/nested dir/SHORT.TXT").unwrap(), b"short");
+    assert_eq!(fs.read("/empty").unwrap(), b"");
+    assert!(fs.metadata("/docs/nested dir").unwrap().is_dir);
+
+    // Known lengths are allocated exactly
+    let clusters = fs.driver().chain(fs.driver().search_by_path(Path::new("/docs/A long file name.txt")).unwrap().cluster_num()).unwrap();
+    assert_eq!(clusters.len(), 4);
+}
+
+#[test]
+fn creates_files_of_unknown_length() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    let data = contents(2, 40 * BYTES_PER_CLUSTER + 1);
+    driver.create_file(Path::new("/stream.bin"), &data[..], None).unwrap();
+    create(&driver, "/after.bin", b"after");
+    drop(driver);
+
+    let driver = image.driver();
+    assert_clean(&driver);
+    assert_eq!(driver.read_to_end(Path::new("/stream.bin")).unwrap(), data);
+    assert_eq!(driver.chain(driver.search_by_path(Path::new("/stream.bin")).unwrap().cluster_num()).unwrap().len(), 41);
+
+    // The file after it starts right where it really ended
+    let stream_end = *driver.chain(driver.search_by_path(Path::new("/stream.bin")).unwrap().cluster_num()).unwrap().last().unwrap();
+    assert_eq!(driver.search_by_path(Path::new("/after.bin")).unwrap().cluster_num(), stream_end + 1);
+}
+
+#[test]
+fn refuses_names_that_exist() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    create(&driver, "/Readme.txt", b"first");
+    driver.mkdir(Path::new("/dir")).unwrap();
+
+    assert!(matches!(driver.create_file(Path::new("/README.TXT"), &b"second"[..], Some(6)), Err(Fat32Error::AlreadyExists)));
+    assert!(matches!(driver.mkdir(Path::new("/readme.txt")), Err(Fat32Error::AlreadyExists)));
+    assert!(matches!(driver.mkdir(Path::new("/DIR")), Err(Fat32Error::AlreadyExists)));
+    assert!(matches!(driver.create_file(Path::new("/Readme.txt/x"), &b""[..], Some(0)), Err(Fat32Error::NotADir)));
+    assert!(matches!(driver.create_file(Path::new("/a:b"), &b""[..], Some(0)), Err(Fat32Error::InvalidName(_))));
+    drop(driver);
+
+    let driver = image.driver();
+    assert_clean(&driver);
+    assert_eq!(driver.read_to_end(Path::new("/Readme.txt")).unwrap(), b"first");
+}
+
+#[test]
+fn looks_names_up_ignoring_case() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    driver.mkdir(Path::new("/docs")).unwrap();
+    create(&driver, "/docs/renamed.txt", b"lower");
+    create(&driver, "/docs/A long file name.txt", b"long");
+
+    assert_eq!(driver.read_to_end(Path::new("/DOCS/RENAMED.TXT")).unwrap(), b"lower");
+    assert_eq!(driver.read_to_end(Path::new("/Docs/a LONG file NAME.txt")).unwrap(), b"long");
+    // The 8.3 alias of a long name finds it too
+    assert_eq!(driver.read_to_end(Path::new("/docs/alongf~1.txt")).unwrap(), b"long");
+    assert!(matches!(driver.create_file(Path::new("/DOCS/RENAMED.TXT"), &b"upper"[..], Some(5)), Err(Fat32Error::AlreadyExists)));
+
+    driver.remove_file(Path::new("/docs/RENAMED.txt")).unwrap();
+    assert!(matches!(driver.read_to_end(Path::new("/docs/renamed.txt")), Err(Fat32Error::NotFound)));
+    drop(driver);
+
+    let fs = image.fs();
+    assert_clean(fs.driver());
+    assert_eq!(fs.canonicalize("/DOCS/ALONGF~1.TXT").unwrap(), Path::new("/docs/A long file name.txt"));
+    assert_eq!(names(&fs, "/docs"), ["A long file name.txt"]);
+}
+
+#[test]
+fn renames_within_and_across_directories() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    driver.mkdir(Path::new("/from")).unwrap();
+    driver.mkdir(Path::new("/from/sub")).unwrap();
+    driver.mkdir(Path::new("/to")).unwrap();
+    create(&driver, "/from/file.txt", b"file");
+    create(&driver, "/from/sub/inner.txt", b"inner");
+    create(&driver, "/taken.txt", b"taken");
+
+    driver.rename(Path::new("/from/file.txt"), Path::new("/from/File.TXT")).unwrap();
+    driver.rename(Path::new("/from/File.TXT"), Path::new("/to/moved with a long name.txt")).unwrap();
+    driver.rename(Path::new("/from/sub"), Path::new("/to/sub")).unwrap();
+
+    assert!(matches!(driver.rename(Path::new("/to/sub/inner.txt"), Path::new("/taken.txt")), Err(Fat32Error::AlreadyExists)));
+    assert!(matches!(driver.rename(Path::new("/to"), Path::new("/to/sub/to")), Err(Fat32Error::InvalidName(_))));
+    drop(driver);
+
+    let fs = image.fs();
+    assert_clean(fs.driver());
+    assert!(names(&fs, "/from").is_empty());
+    assert_eq!(names(&fs, "/to"), ["moved with a long name.txt", "sub"]);
+    assert_eq!(fs.read("/to/moved with a long name.txt").unwrap(), b"file");
+    assert_eq!(fs.read("/to/sub/inner.txt").unwrap(), b"inner");
+    assert_eq!(fs.read("/to/sub/../moved with a long name.txt").unwrap(), b"file");
+    assert_eq!(fs.read("/taken.txt").unwrap(), b"taken");
+}
+
+#[test]
+fn removes_files_and_directories() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    driver.mkdir(Path::new("/tree")).unwrap();
+    driver.mkdir(Path::new("/tree/branch")).unwrap();
+    driver.mkdir(Path::new("/empty")).unwrap();
+    create(&driver, "/tree/branch/leaf.bin", &contents(3, 5 * BYTES_PER_CLUSTER));
+    create(&driver, "/tree/top.txt", b"top");
+    create(&driver, "/kept.txt", b"kept");
+
+    assert!(matches!(driver.remove_dir(Path::new("/tree")), Err(Fat32Error::DirectoryNotEmpty)));
+    assert!(matches!(driver.remove_dir(Path::new("/kept.txt")), Err(Fat32Error::NotADir)));
+    assert!(matches!(driver.remove_file(Path::new("/tree")), Err(Fat32Error::IsDir)));
+    assert!(matches!(driver.remove_file(Path::new("/missing")), Err(Fat32Error::NotFound)));
+
+    driver.remove_file(Path::new("/tree/top.txt")).unwrap();
+    driver.remove_dir(Path::new("/empty")).unwrap();
+    driver.remove_dir_all(Path::new("/tree")).unwrap();
+    drop(driver);
+
+    let fs = image.fs();
+    assert_clean(fs.driver());
+    assert_eq!(names(&fs, "/"), ["kept.txt"]);
+    assert_eq!(fs.read("/kept.txt").unwrap(), b"kept");
+    assert_eq!(fs.driver().free_clusters().unwrap() as usize, CLUSTERS - 2);
+}
+
+#[test]
+fn replaces_contents() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    let old = contents(4, 6 * BYTES_PER_CLUSTER);
+    let new = contents(5, 2 * BYTES_PER_CLUSTER + 3);
+    create(&driver, "/file.bin", &old);
+    driver.replace_file(Path::new("/file.bin"), &new[..], Some(new.len() as u64)).unwrap();
+    driver.replace_file(Path::new("/created.bin"), &b"created"[..], Some(7)).unwrap();
+
+    driver.mkdir(Path::new("/dir")).unwrap();
+    assert!(matches!(driver.replace_file(Path::new("/dir"), &b""[..], Some(0)), Err(Fat32Error::IsDir)));
+    drop(driver);
+
+    let driver = image.driver();
+    assert_clean(&driver);
+    assert_eq!(driver.read_to_end(Path::new("/file.bin")).unwrap(), new);
+    assert_eq!(driver.read_to_end(Path::new("/created.bin")).unwrap(), b"created");
+}
+
+#[test]
+fn keeps_the_old_contents_when_replacing_runs_out_of_space() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    let old = contents(6, 3 * BYTES_PER_CLUSTER);
+    create(&driver, "/file.bin", &old);
+
+    let too_big = contents(7, CLUSTERS * BYTES_PER_CLUSTER);
+    assert!(matches!(driver.replace_file(Path::new("/file.bin"), &too_big[..], Some(too_big.len() as u64)), Err(Fat32Error::NoSpace)));
+    assert!(matches!(driver.replace_file(Path::new("/file.bin"), &too_big[..], None), Err(Fat32Error::NoSpace)));
+    drop(driver);
+
+    let driver = image.driver();
+    assert_clean(&driver);
+    assert_eq!(driver.read_to_end(Path::new("/file.bin")).unwrap(), old);
+}
+
+#[test]
+fn refuses_to_list_names_with_separators() {
+    let image = TestImage::new();
+    let driver = image.driver();
+
+    create(&driver, "/aXb.txt", b"contents");
+    create(&driver, "/fine.txt", b"fine");
+    drop(driver);
+
+    // Turn the long name into a/b.txt
+    image.patch(b"a\0X\0b\0", b"a\0/\0b\0");
+
+    let fs = image.fs();
+    let entries = fs.read_dir("/").unwrap().collect::<Vec<_>>();
+    assert_eq!(entries.len(), 2);
+    assert!(entries.iter().any(|entry| matches!(entry, Err(Fat32Error::InvalidName(name)) if name == "a/b.txt")));
+    assert!(entries.iter().any(|entry| matches!(entry, Ok(entry) if entry.file_name() == "fine.txt")));
+}
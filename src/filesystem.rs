@@ -1,12 +1,14 @@
-use std::{ffi::{c_int, OsStr}, path::PathBuf, sync::Arc, time::Duration};
+use std::{ffi::{c_int, OsStr, OsString}, os::unix::ffi::OsStrExt, path::{Path, PathBuf}, sync::Arc, time::{Duration, SystemTime}};
 
-use fat32::{Driver, Fat32Result, FatDirectory};
-use fuser::{consts::FOPEN_DIRECT_IO, FileAttr, FileType, Filesystem, MountOption};
+use fat32::{Driver, Fat32Result, FatDirectory, DIR_ATTR_HIDDEN, DIR_ATTR_READ_ONLY, DIR_ATTR_SYSTEM};
+use fuser::{consts::{self, FOPEN_DIRECT_IO}, FileAttr, FileType, Filesystem, MountOption};
 use nix::libc;
 use parking_lot::Mutex;
 use rayon::{ThreadPool, ThreadPoolBuilder};
 
-use crate::inode::InodeResolver;
+use crate::inode::{inode_of, InodeResolver};
+use crate::options::{FatOptions, HiddenPolicy};
+use crate::xattr;
 
 macro_rules! try_io {
     ($fat32_result: expr, $reply: expr) => {
@@ -25,11 +27,21 @@ macro_rules! try_io {
                     $reply.error(libc::ENOTDIR);
                     return;
                 },
+                Err(fat32::Fat32Error::InvalidAttributes(_)) => {
+                    log::debug!("EINVAL");
+                    $reply.error(libc::EINVAL);
+                    return;
+                },
                 Err(fat32::Fat32Error::IsDir) => {
                     log::debug!("EISDIR");
                     $reply.error(libc::EISDIR);
                     return;
                 },
+                Err(fat32::Fat32Error::InvalidFileHandle(handle)) => {
+                    log::warn!("Stale or unknown file handle {:#x}", handle);
+                    $reply.error(libc::EBADF);
+                    return;
+                },
                 Err(_) => {
                     log::debug!("EIO");
                     $reply.error(libc::EIO);
@@ -39,6 +51,16 @@ macro_rules! try_io {
         }
     };
 }
+/// Replies with the size of `value` if the kernel asked for it, otherwise the value if it fits
+fn reply_xattr(reply: fuser::ReplyXattr, size: u32, value: &[u8]) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
 fn file_type_of(directory: &FatDirectory) -> FileType {
     if directory.is_file() {
         FileType::RegularFile
@@ -48,46 +70,74 @@ fn file_type_of(directory: &FatDirectory) -> FileType {
 }
 
 const FMODE_EXEC: i32 = 0x20;
+/// Linux vfat ioctls, _IOR('r', 0x10, __u32), _IOW('r', 0x11, __u32) and _IOR('r', 0x13, __u32)
+const FAT_IOCTL_GET_ATTRIBUTES: u32 = 0x80047210;
+const FAT_IOCTL_SET_ATTRIBUTES: u32 = 0x40047211;
+const FAT_IOCTL_GET_VOLUME_ID: u32 = 0x80047213;
+/// Extensions that get execute permission with `showexec`
+const EXEC_EXTENSIONS: [&str; 3] = ["EXE", "COM", "BAT"];
+/// Long file names are limited to 255 UTF-16 characters
+const MAX_NAME_LEN: u32 = 255;
+
+/// How long the kernel may cache what lookup and getattr return
+#[derive(Clone, Copy, Debug)]
+pub struct CacheTimeouts {
+    /// Applies to both the name and the attributes returned by lookup, FUSE has only one TTL for lookups
+    pub entry: Duration,
+    pub attr: Duration,
+}
+
+impl Default for CacheTimeouts {
+    fn default() -> Self {
+        Self {
+            entry: Duration::from_secs(1),
+            attr: Duration::from_secs(1),
+        }
+    }
+}
 
 pub struct Fat32 {
-    driver: Arc<Driver>,
-    inode_resolver: Mutex<InodeResolver>,
-    mount_permissions_mask: u16,
-    mount_uid: u32,
-    mount_gid: u32, 
+    volume: Arc<Volume>,
     tp: ThreadPool,
+}
+/// What the operations share, they run on the threads of `Fat32::tp`
+struct Volume {
+    driver: Driver,
+    inode_resolver: Mutex<InodeResolver>,
+    options: FatOptions,
     direct_io: bool,
+    timeouts: CacheTimeouts,
+    read_only: bool,
 }
 impl Fat32 {
-    pub fn new(driver: Driver, uid: u32, gid: u32, mount_options: &Vec<MountOption>, direct_io: bool) -> Self {
-        let mut mount_permissions_mask = 0;
-
-        let mut rwx = 0b000;
-        for option in mount_options {
-            match option {
-                MountOption::RO => {
-                    rwx |= 0b100;
-                },
-                MountOption::Exec => {
-                    rwx |= 0b001;
-                },
-                MountOption::RW => unimplemented!("RW mount unsupported"),
-                _=> {}
-            }
+    pub fn new(driver: Driver, options: FatOptions, mount_options: &[MountOption], direct_io: bool, timeouts: CacheTimeouts, threads: usize) -> std::io::Result<Self> {
+        if mount_options.contains(&MountOption::RW) {
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Read-write mounts aren't supported yet"));
         }
 
-        mount_permissions_mask = 0o644;
-        Self {
-            driver: Arc::new(driver),
-            mount_permissions_mask,
+        let volume = Volume {
+            driver,
             inode_resolver: Mutex::new(InodeResolver::new()),
-            mount_uid: uid,
-            mount_gid: gid,
+            options,
             direct_io,
-            tp: ThreadPoolBuilder::new().build().unwrap()
-        }
+            timeouts,
+            read_only: mount_options.contains(&MountOption::RO),
+        };
+
+        Ok(Self {
+            volume: Arc::new(volume),
+            tp: ThreadPoolBuilder::new().num_threads(threads).build().unwrap()
+        })
+    }
+    /// Runs an operation on the thread pool, so a slow one doesn't hold up the rest
+    fn spawn<F>(&self, operation: F)
+    where F: FnOnce(&Volume) + Send + 'static {
+        let volume = self.volume.clone();
+        self.tp.spawn(move || operation(&volume));
     }
-    fn file_attr_of(&self, directory: &FatDirectory, inode: u64, req: &fuser::Request) -> Fat32Result<FileAttr> {
+}
+impl Volume {
+    fn file_attr_of(&self, directory: &FatDirectory, inode: u64) -> Fat32Result<FileAttr> {
         let file_attr = FileAttr {
             ino: inode,
             size: directory.file_size() as u64,
@@ -97,10 +147,10 @@ impl Fat32 {
             ctime: directory.write_time(),
             crtime: directory.create_time(),
             kind: file_type_of(directory),
-            perm: self.mount_permissions_mask,
+            perm: self.permissions(directory),
             nlink: 1,
-            uid: self.mount_uid,
-            gid: self.mount_gid,
+            uid: self.options.uid,
+            gid: self.options.gid,
             rdev: 0,
             blksize: self.driver.bytes_per_cluster() as u32,
             flags: 0,
@@ -108,10 +158,108 @@ impl Fat32 {
 
         Ok(file_attr)
     }
+    /// Mode bits the way vfat derives them from the masks and attributes
     fn permissions(&self, directory: &FatDirectory) -> u16 {
+        if directory.is_dir() {
+            return 0o777 & !self.options.dmask;
+        }
+
+        let mut mode = 0o777 & !self.options.fmask;
+
+        if self.options.showexec {
+            let short_name = directory.entry().short_name();
+            let extension = Path::new(&short_name).extension().map(|extension| extension.to_ascii_uppercase());
+
+            let executable = extension.is_some_and(|extension| EXEC_EXTENSIONS.iter().any(|exec| extension == *exec));
+            if !executable {
+                mode &= !0o111;
+            }
+        }
 
+        // The read-only attribute only applies to files
+        if directory.matches_attr(DIR_ATTR_READ_ONLY) {
+            mode &= !0o222;
+        }
+
+        mode
+    }
+    /// Whether the caller may change timestamps, which on FAT isn't tied to write permission
+    fn may_set_times(&self, uid: u32, gid: u32) -> bool {
+        let allow_utime = self.options.allow_utime();
 
-        todo!()
+        if uid == 0 || uid == self.options.uid {
+            true
+        } else if gid == self.options.gid {
+            allow_utime & 0o020 != 0
+        } else {
+            allow_utime & 0o002 != 0
+        }
+    }
+    fn is_hidden(directory: &FatDirectory) -> bool {
+        directory.matches_attr(DIR_ATTR_HIDDEN) || directory.matches_attr(DIR_ATTR_SYSTEM)
+    }
+    /// The name readdir lists `directory` under, None if it isn't listed
+    fn listed_name(&self, directory: &FatDirectory) -> Option<OsString> {
+        if directory.is_deleted() || directory.is_volume_id() {
+            return None;
+        }
+
+        let name = directory.name();
+        let dotted = name.as_encoded_bytes().starts_with(b".");
+
+        match self.options.hidden {
+            HiddenPolicy::Hide if Self::is_hidden(directory) => None,
+            HiddenPolicy::DotPrefix if Self::is_hidden(directory) && !dotted => {
+                let mut prefixed = OsString::from(".");
+                prefixed.push(name);
+                Some(prefixed)
+            },
+            _=> Some(name.to_owned()),
+        }
+    }
+    /// Resolves a dot prefixed name listed by readdir back to the hidden entry it stands for
+    fn hidden_alias(&self, parent_path: &Path, name: &OsStr) -> Option<(OsString, FatDirectory)> {
+        if self.options.hidden != HiddenPolicy::DotPrefix {
+            return None;
+        }
+
+        let real_name = OsStr::from_bytes(name.as_bytes().strip_prefix(b".")?);
+        let found = self.driver.search_by_path(&parent_path.join(real_name)).ok()?;
+
+        Self::is_hidden(&found).then(|| (real_name.to_owned(), found))
+    }
+    /// Hands the entries of directory `ino` from `offset` on to `add`, with the offset of the next one, until it returns true for a full buffer.
+    /// The root has no . and .. entries on disk, so they're made up for every directory and come without an entry.
+    /// They take offsets 0 and 1, and entry n of the directory is at n + 2.
+    fn list_dir<F>(&self, ino: u64, fh: u64, offset: i64, mut add: F) -> Fat32Result<()>
+    where F: FnMut(u64, i64, &OsStr, Option<&FatDirectory>) -> Fat32Result<bool> {
+        let parent = self.inode_resolver.lock().parent(ino);
+        let mut offset = offset as usize;
+
+        for (inode, name) in [(ino, "."), (parent, "..")].into_iter().skip(offset) {
+            offset += 1;
+
+            if add(inode, offset as i64, OsStr::new(name), None)? {
+                return Ok(());
+            }
+        }
+
+        while let Some(file) = self.driver.read_dir(fh, offset - 2)? {
+            offset += 1;
+
+            if file.name() == "." || file.name() == ".." {
+                continue;
+            }
+            let Some(name) = self.listed_name(&file) else {
+                continue;
+            };
+
+            if add(inode_of(&file), offset as i64, &name, Some(&file))? {
+                break;
+            }
+        }
+
+        Ok(())
     }
     fn get_path(&self, inode: u64) -> PathBuf {
         let inode_resolver = self.inode_resolver.lock();
@@ -128,31 +276,218 @@ impl Fat32 {
 }
 impl Filesystem for Fat32 {
     fn init(&mut self, req: &fuser::Request<'_>, config: &mut fuser::KernelConfig) -> Result<(), c_int> {
- 
+        if config.add_capabilities(consts::FUSE_DO_READDIRPLUS).is_err() {
+            log::info!("Kernel doesn't support readdirplus, falling back to readdir");
+        }
+
         Ok(())
     }
-    fn lookup(&mut self, req: &fuser::Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEntry) {
-        
-        let mut inode_resolver = self.inode_resolver.lock();
-        
-        let parent_path = inode_resolver.path(parent);
-        let path = parent_path.join(name);
-        log::debug!("lookup {:?}", name);
-        let found = try_io!(self.driver.search_by_path(&path), reply);
-        let inode = inode_resolver.get_or_assign_inode(parent, name);
-        log::debug!("lookup {:?} = {}", path, inode);
-        
-        let file_attr = try_io!(self.file_attr_of(&found, inode, req), reply);
-        reply.entry(&Duration::new(0, 0), &file_attr, 0);
+    fn destroy(&mut self) {
+        for open in self.volume.driver.open_handles() {
+            log::debug!("Handle {:#x} still open at unmount: {}", open.handle, open.path.display());
+        }
+    }
+    fn lookup(&mut self, _req: &fuser::Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEntry) {
+        let name = name.to_owned();
+        self.spawn(move |fs| {
+            // The resolver isn't held while searching, lookups in other directories go on meanwhile
+            let parent_path = fs.get_path(parent);
+            let path = if name == "." {
+                parent_path.clone()
+            } else if name == ".." {
+                parent_path.parent().unwrap_or(&parent_path).to_owned()
+            } else {
+                parent_path.join(&name)
+            };
+            log::debug!("lookup {:?}", name);
+            let (name, found) = match fs.driver.search_by_path(&path) {
+                Err(fat32::Fat32Error::NotFound) => {
+                    let alias = fs.hidden_alias(&parent_path, &name);
+                    try_io!(alias.ok_or(fat32::Fat32Error::NotFound), reply)
+                },
+                found => (name, try_io!(found, reply)),
+            };
+            let inode = fs.inode_resolver.lock().register(parent, &name, &found);
+            log::debug!("lookup {:?} = {}", path, inode);
+
+            let file_attr = try_io!(fs.file_attr_of(&found, inode), reply);
+            reply.entry(&fs.timeouts.entry, &file_attr, 0);
+        });
+    }
+    fn forget(&mut self, _req: &fuser::Request<'_>, inode: u64, nlookup: u64) {
+        self.volume.inode_resolver.lock().forget(inode, nlookup);
+    }
+    fn batch_forget(&mut self, _req: &fuser::Request<'_>, nodes: &[fuser::fuse_forget_one]) {
+        let mut inode_resolver = self.volume.inode_resolver.lock();
+
+        for node in nodes {
+            inode_resolver.forget(node.nodeid, node.nlookup);
+        }
+        log::debug!("batch_forget {} inodes, {} left", nodes.len(), inode_resolver.len());
+    }
+    fn getattr(&mut self, _req: &fuser::Request<'_>, inode: u64, reply: fuser::ReplyAttr) {
+        self.spawn(move |fs| {
+            let path = fs.get_path(inode);
+
+            let file = try_io!(fs.driver.search_by_path(&path), reply);
+            let attr = try_io!(fs.file_attr_of(&file, inode), reply);
+
+            reply.attr(&fs.timeouts.attr, &attr);
+        });
+    }
+    fn statfs(&mut self, _req: &fuser::Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
+        self.spawn(move |fs| {
+            let blocks = fs.driver.cluster_count() as u64;
+            let free = try_io!(fs.driver.free_clusters(), reply) as u64;
+            let bsize = fs.driver.bytes_per_cluster() as u32;
+
+            // FAT has no inode table, but every non-empty file takes at least one cluster
+            let files = blocks;
+            let ffree = free;
+
+            reply.statfs(blocks, free, free, files, ffree, bsize, MAX_NAME_LEN, bsize);
+        });
+    }
+    fn setattr(
+            &mut self,
+            req: &fuser::Request<'_>,
+            _inode: u64,
+            mode: Option<u32>,
+            uid: Option<u32>,
+            gid: Option<u32>,
+            _size: Option<u64>,
+            atime: Option<fuser::TimeOrNow>,
+            mtime: Option<fuser::TimeOrNow>,
+            _ctime: Option<SystemTime>,
+            _fh: Option<u64>,
+            _crtime: Option<SystemTime>,
+            _chgtime: Option<SystemTime>,
+            _bkuptime: Option<SystemTime>,
+            _flags: Option<u32>,
+            reply: fuser::ReplyAttr,
+        ) {
+        // Ownership and modes come from the mount options, there's nowhere to store them
+        if mode.is_some() || uid.is_some() || gid.is_some() {
+            reply.error(libc::EPERM);
+            return;
+        }
+
+        if (atime.is_some() || mtime.is_some()) && !self.volume.may_set_times(req.uid(), req.gid()) {
+            reply.error(libc::EPERM);
+            return;
+        }
+
+        reply.error(libc::EROFS);
+    }
+    fn getxattr(&mut self, _req: &fuser::Request<'_>, inode: u64, name: &OsStr, size: u32, reply: fuser::ReplyXattr) {
+        let name = name.to_owned();
+        self.spawn(move |fs| {
+            let path = fs.get_path(inode);
+            let file = try_io!(fs.driver.search_by_path(&path), reply);
+
+            match xattr::get(&file, &name) {
+                Some(value) => reply_xattr(reply, size, &value),
+                None => reply.error(libc::ENODATA),
+            }
+        });
+    }
+    fn listxattr(&mut self, _req: &fuser::Request<'_>, inode: u64, size: u32, reply: fuser::ReplyXattr) {
+        self.spawn(move |fs| {
+            let path = fs.get_path(inode);
+            let file = try_io!(fs.driver.search_by_path(&path), reply);
+
+            let mut names = vec![];
+            for name in xattr::names(&file) {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
 
+            reply_xattr(reply, size, &names);
+        });
     }
-    fn getattr(&mut self, req: &fuser::Request<'_>, inode: u64, reply: fuser::ReplyAttr) {
-        let path = self.get_path(inode);
+    fn setxattr(
+            &mut self,
+            req: &fuser::Request<'_>,
+            inode: u64,
+            name: &OsStr,
+            value: &[u8],
+            flags: i32,
+            _position: u32,
+            reply: fuser::ReplyEmpty,
+        ) {
+        let attr = match xattr::parse_attributes(name, value) {
+            Ok(attr) => attr,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+
+        // The attribute byte always exists, so it can't be created
+        if flags & libc::XATTR_CREATE != 0 {
+            reply.error(libc::EEXIST);
+            return;
+        }
+
+        let uid = req.uid();
+        self.spawn(move |fs| {
+            if let Err(errno) = xattr::may_set_attributes(fs.read_only, uid, fs.options.uid) {
+                reply.error(errno);
+                return;
+            }
 
-        let file = try_io!(self.driver.search_by_path(&path), reply);
-        let attr = try_io!(self.file_attr_of(&file, inode, req), reply);
+            let path = fs.get_path(inode);
+            try_io!(fs.driver.set_attributes(&path, attr), reply);
 
-        reply.attr(&Duration::new(0, 0), &attr);
+            reply.ok();
+        });
+    }
+    fn ioctl(
+            &mut self,
+            req: &fuser::Request<'_>,
+            inode: u64,
+            _fh: u64,
+            _flags: u32,
+            cmd: u32,
+            in_data: &[u8],
+            _out_size: u32,
+            reply: fuser::ReplyIoctl,
+        ) {
+        let uid = req.uid();
+        let in_data = in_data.to_owned();
+        self.spawn(move |fs| {
+            match cmd {
+                FAT_IOCTL_GET_ATTRIBUTES => {
+                    let path = fs.get_path(inode);
+                    let file = try_io!(fs.driver.search_by_path(&path), reply);
+
+                    reply.ioctl(0, &(file.attributes() as u32).to_ne_bytes());
+                },
+                FAT_IOCTL_SET_ATTRIBUTES => {
+                    let Ok(value) = <[u8; 4]>::try_from(in_data.as_slice()) else {
+                        reply.error(libc::EINVAL);
+                        return;
+                    };
+
+                    if let Err(errno) = xattr::may_set_attributes(fs.read_only, uid, fs.options.uid) {
+                        reply.error(errno);
+                        return;
+                    }
+
+                    let path = fs.get_path(inode);
+                    let file = try_io!(fs.driver.search_by_path(&path), reply);
+
+                    let attr = xattr::ioctl_attributes(u32::from_ne_bytes(value), file.attributes());
+                    try_io!(fs.driver.set_attributes(&path, attr), reply);
+
+                    reply.ioctl(0, &[]);
+                },
+                FAT_IOCTL_GET_VOLUME_ID => {
+                    reply.ioctl(0, &fs.driver.volume_id().to_ne_bytes());
+                },
+                _=> reply.error(libc::ENOTTY),
+            }
+        });
     }
     fn opendir(&mut self, _req: &fuser::Request<'_>, inode: u64, flags: i32, reply: fuser::ReplyOpen) {
         let (_access_mask, read, write) = match flags & libc::O_ACCMODE {
@@ -173,18 +508,20 @@ impl Filesystem for Fat32 {
             }
         };
 
-        if !self.check_access(read, write, false) {
+        if !self.volume.check_access(read, write, false) {
             reply.error(libc::EACCES);
             return;
         }
         
-        let path = self.get_path(inode);
+        self.spawn(move |fs| {
+            let path = fs.get_path(inode);
 
-        println!("Open {}", path.display());
-        let fh = try_io!(self.driver.open_dir(&path), reply);
-        println!("Opened {}={}", path.display(), fh);
+            log::debug!("Open {}", path.display());
+            let fh = try_io!(fs.driver.open_dir(&path), reply);
+            log::debug!("Opened {}={}", path.display(), fh);
 
-        reply.opened(fh, 0);
+            reply.opened(fh, 0);
+        });
     }
     fn releasedir(
             &mut self,
@@ -194,8 +531,10 @@ impl Filesystem for Fat32 {
             _flags: i32,
             reply: fuser::ReplyEmpty,
         ) {
-        try_io!(self.driver.close_dir(fh), reply);
-        reply.ok();
+        self.spawn(move |fs| {
+            try_io!(fs.driver.close_dir(fh), reply);
+            reply.ok();
+        });
     }
     fn readdir(
             &mut self,
@@ -205,19 +544,46 @@ impl Filesystem for Fat32 {
             offset: i64,
             mut reply: fuser::ReplyDirectory,
         ) {
-            let mut offset = offset as usize;
-            
-            while let Some(file) = try_io!(self.driver.read_dir(fh, offset), reply) {
-                offset += 1;
-                println!("{:?}", file.name());
-                let buffer_full = reply.add(ino, offset as i64, file_type_of(&file), file.name());
-            
-                if buffer_full {
-                    break;
-                }
-            }
+        self.spawn(move |fs| {
+                try_io!(fs.list_dir(ino, fh, offset, |inode, offset, name, file| {
+                    let kind = file.map_or(FileType::Directory, file_type_of);
+                    Ok(reply.add(inode, offset, kind, name))
+                }), reply);
 
-            reply.ok()
+                reply.ok()
+        });
+    }
+    fn readdirplus(
+            &mut self,
+            _req: &fuser::Request<'_>,
+            ino: u64,
+            fh: u64,
+            offset: i64,
+            mut reply: fuser::ReplyDirectoryPlus,
+        ) {
+        self.spawn(move |fs| {
+                try_io!(fs.list_dir(ino, fh, offset, |inode, offset, name, file| {
+                    let Some(file) = file else {
+                        // The kernel doesn't look up . and .., but their attributes are sent all the same
+                        let directory = fs.driver.search_by_path(&fs.get_path(inode))?;
+                        let file_attr = fs.file_attr_of(&directory, inode)?;
+
+                        return Ok(reply.add(inode, offset, name, &fs.timeouts.entry, &file_attr, 0));
+                    };
+
+                    let file_attr = fs.file_attr_of(file, inode)?;
+                    let buffer_full = reply.add(inode, offset, name, &fs.timeouts.entry, &file_attr, 0);
+
+                    // Every entry that makes it into the reply counts as a lookup
+                    if !buffer_full {
+                        fs.inode_resolver.lock().register(ino, file.name(), file);
+                    }
+
+                    Ok(buffer_full)
+                }), reply);
+
+                reply.ok()
+        });
     }
     fn open(&mut self, _req: &fuser::Request<'_>, inode: u64, flags: i32, reply: fuser::ReplyOpen) {
         let (access_mask, read, write, exec) = match flags & libc::O_ACCMODE {
@@ -243,21 +609,23 @@ impl Filesystem for Fat32 {
             }
         };
 
-        let path = self.get_path(inode);
-        let fh = try_io!(self.driver.open(&path), reply);
+        self.spawn(move |fs| {
+            let path = fs.get_path(inode);
+            let fh = try_io!(fs.driver.open(&path), reply);
 
-        if !self.check_access(read, write, exec) {
-            reply.error(libc::EACCES);
-            return;
-        }
+            if !fs.check_access(read, write, exec) {
+                reply.error(libc::EACCES);
+                return;
+            }
 
-        let flags = if self.direct_io {
-            FOPEN_DIRECT_IO
-        } else {
-            0
-        };
+            let flags = if fs.direct_io {
+                FOPEN_DIRECT_IO
+            } else {
+                0
+            };
 
-        reply.opened(fh, flags)
+            reply.opened(fh, flags)
+        });
     }
     fn release(
             &mut self,
@@ -269,9 +637,11 @@ impl Filesystem for Fat32 {
             _flush: bool,
             reply: fuser::ReplyEmpty,
         ) {
-        try_io!(self.driver.close(fh), reply);
+        self.spawn(move |fs| {
+            try_io!(fs.driver.close(fh), reply);
 
-        reply.ok()
+            reply.ok()
+        });
     }
     fn read(
             &mut self,
@@ -284,8 +654,6 @@ impl Filesystem for Fat32 {
             _lock_owner: Option<u64>,
             reply: fuser::ReplyData,
         ) {
-        let driver = self.driver.clone();
-
         let (_access_mask, read, write, exec) = match flags & libc::O_ACCMODE {
             libc::O_RDONLY => {
                 // Behavior is undefined, but most filesystems return EACCES
@@ -309,18 +677,18 @@ impl Filesystem for Fat32 {
             }
         };
 
-        if !self.check_access(read, write, exec) {
+        if !self.volume.check_access(read, write, exec) {
             reply.error(libc::EACCES);
             return;
         }
 
-        self.tp.spawn(move|| {
+        self.spawn(move |fs| {
             let offset = offset as usize;
 
             let mut read_buf = vec![0; size as usize];
             let byte_offset = offset as usize;
     
-            let nbytes = try_io!(driver.read(fh, &mut read_buf, byte_offset), reply);
+            let nbytes = try_io!(fs.driver.read(fh, &mut read_buf, byte_offset), reply);
      
             reply.data(&read_buf[0..nbytes])
         });
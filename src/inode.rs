@@ -1,58 +1,99 @@
 use std::{collections::HashMap, ffi::OsStr, path::{Path, PathBuf}};
 
+use fat32::FatDirectory;
+
 pub const ROOT_INODE: u64 = 1;
+/// Inodes of entries without a first cluster are tagged with this bit, keeping them apart from cluster numbers
+const LOCATION_INODE: u64 = 1 << 48;
+
+/// Derives an inode number from where the entry lives on disk, so it's the same on every mount.
+/// Entries with data use their first cluster, which stays put across renames.
+/// Empty files have none and fall back to the cluster and index of their directory entry.
+pub fn inode_of(directory: &FatDirectory) -> u64 {
+    let Some(location) = directory.location() else {
+        return ROOT_INODE;
+    };
+
+    match directory.cluster_num() {
+        0 => LOCATION_INODE | (location.cluster as u64) << 16 | location.index as u64,
+        cluster => cluster as u64,
+    }
+}
+
+struct Inode {
+    path: PathBuf,
+    parent: u64,
+    /// Number of lookups the kernel hasn't forgotten yet
+    lookups: u64,
+}
+
+/// Maps the inodes the kernel knows about to paths, dropping them once the kernel forgets them
 pub struct InodeResolver {
-    path_to_inode: HashMap<PathBuf, u64>,
-    inode_to_path: HashMap<u64, PathBuf>,
-    parent_inode: HashMap<u64, u64>,
-    next_inode: u64,
+    inodes: HashMap<u64, Inode>,
 }
 
 impl InodeResolver {
     pub fn new() -> Self {
-        let parent_inode =HashMap::new();
-        
-        let mut path_to_inode = HashMap::new();
-        let mut inode_to_path = HashMap::new();
-
-        path_to_inode.insert(Path::new("/").to_owned(), ROOT_INODE);
-        inode_to_path.insert(ROOT_INODE, Path::new("/").to_owned());
-        
-        let next_inode = ROOT_INODE;
+        let mut inodes = HashMap::new();
+
+        // The root is never looked up or forgotten, and is its own parent
+        inodes.insert(ROOT_INODE, Inode {
+            path: Path::new("/").to_owned(),
+            parent: ROOT_INODE,
+            lookups: 1,
+        });
+
         Self {
-            path_to_inode,
-            parent_inode,
-            inode_to_path,
-            next_inode
+            inodes
         }
     }
-    fn register_path_inode(&mut self, path: PathBuf, inode: u64) {
-        self.inode_to_path.insert(inode, path.clone());
-        self.path_to_inode.insert(path, inode);
-    }
     pub fn path(&self, inode: u64) -> &Path {
-        &self.inode_to_path[&inode]
+        &self.inodes[&inode].path
     }
-    pub fn get_or_assign_inode(&mut self, parent: u64, name: &OsStr) -> u64 {
-        if name == "." {
+    pub fn parent(&self, inode: u64) -> u64 {
+        self.inodes[&inode].parent
+    }
+    /// Records the path of `directory`, found as `name` in `parent`, and returns its inode.
+    /// Every call counts as a lookup to be balanced by `forget`.
+    pub fn register(&mut self, parent: u64, name: &OsStr, directory: &FatDirectory) -> u64 {
+        let inode = if name == "." {
             parent
         } else if name == ".." {
-            let parent_inode = self.parent_inode[&parent];
-            parent_inode
-        }  else {
-            let parent_path = self.inode_to_path.get(&parent).expect("Parent path lookup must have happened before child");
-            let current_path = parent_path.join(name);
-
-            if let Some(existing_inode) = self.path_to_inode.get(&current_path) {
-                return *existing_inode;
-            }
+            self.inodes[&parent].parent
+        } else {
+            let parent_path = &self.inodes.get(&parent).expect("Parent path lookup must have happened before child").path;
+            let path = parent_path.join(name);
 
-            self.next_inode += 1;
-            let inode = self.next_inode;
-            self.register_path_inode(current_path, inode);
-            self.parent_inode.insert(inode, parent);
+            let inode = inode_of(directory);
+            let entry = self.inodes.entry(inode).or_insert(Inode { path: PathBuf::new(), parent, lookups: 0 });
+            // The entry may have been renamed since it was last looked up
+            entry.path = path;
+            entry.parent = parent;
 
             inode
+        };
+
+        self.inodes.get_mut(&inode).unwrap().lookups += 1;
+
+        inode
+    }
+    /// Drops `n_lookups` lookups of `inode`, evicting it once none are left
+    pub fn forget(&mut self, inode: u64, n_lookups: u64) {
+        if inode == ROOT_INODE {
+            return;
+        }
+
+        let Some(entry) = self.inodes.get_mut(&inode) else {
+            log::warn!("forget of unknown inode {}", inode);
+            return;
+        };
+
+        entry.lookups = entry.lookups.saturating_sub(n_lookups);
+        if entry.lookups == 0 {
+            self.inodes.remove(&inode);
         }
     }
-}
\ No newline at end of file
+    pub fn len(&self) -> usize {
+        self.inodes.len()
+    }
+}
@@ -1,58 +1,213 @@
 mod filesystem;
 mod inode;
+mod options;
+mod xattr;
 
 use std::error::Error;
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use fat32::{Drive, Driver, Fat32Result, FatDirectory, Files};
-use filesystem::Fat32;
+use clap::Parser;
+use fat32::{Drive, Driver};
+use filesystem::{CacheTimeouts, Fat32};
+use options::{is_mount_helper_option, parse_mount_option, FatOptions};
 use fuser::MountOption;
-use std::collections::VecDeque;
 
-fn all_files(driver: &Driver) -> Fat32Result<()> {
-    let mut queue = VecDeque::new();
+/// Mount a FAT32 volume with FUSE.
+/// Also works as a mount(8) helper when installed or linked as /sbin/mount.fat32rs
+#[derive(Parser)]
+#[command(name = "fat32_at_home")]
+struct Cli {
+    /// Image file or block device holding the volume
+    device: PathBuf,
+    /// Directory to mount the volume on
+    mount_point: PathBuf,
+    /// Comma separated mount options: FUSE ones like ro, allow_other or fsname=,
+    /// and uid=, gid=, umask=, fmask=, dmask=, allow_utime=, showexec, entry_timeout=, attr_timeout=
+    #[arg(short = 'o', value_delimiter = ',')]
+    options: Vec<String>,
+    /// Stay in the foreground, the default unless run as mount.fat32rs
+    #[arg(long, conflicts_with = "daemon")]
+    foreground: bool,
+    /// Detach from the terminal once the volume is opened
+    #[arg(long)]
+    daemon: bool,
+    /// Bypass the page cache for file contents
+    #[arg(long)]
+    direct_io: bool,
+    /// Number of threads running filesystem operations, 0 for one per CPU
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+    /// One of off, error, warn, info, debug or trace
+    #[arg(long, default_value = "warn")]
+    log_level: log::LevelFilter,
+    /// Mount partition N, counting from 1, of a partitioned image or disk
+    #[arg(long)]
+    partition: Option<usize>,
+    /// Ignore unknown mount options, passed by mount(8)
+    #[arg(short = 's', hide = true)]
+    sloppy: bool,
+    /// Do everything but mount, passed by mount(8)
+    #[arg(short = 'f', hide = true)]
+    fake: bool,
+    /// Don't write to /etc/mtab, passed by mount(8) and ignored
+    #[arg(short = 'n', hide = true)]
+    _no_mtab: bool,
+    /// Verbose, passed by mount(8)
+    #[arg(short = 'v', hide = true)]
+    verbose: bool,
+    /// Filesystem type, passed by mount(8) and ignored
+    #[arg(short = 't', hide = true)]
+    _fs_type: Option<String>,
+}
 
-    queue.push_back(Files::new(driver, &FatDirectory::root(driver)));
+/// Everything the -o options set
+struct ParsedOptions {
+    fat: FatOptions,
+    mount: Vec<MountOption>,
+    timeouts: CacheTimeouts,
+}
 
-    while !queue.is_empty() {
-        let n = queue.len();
+fn parse_options(cli: &Cli) -> Result<ParsedOptions, Box<dyn Error>> {
+    let mut parsed = ParsedOptions {
+        fat: FatOptions::new(nix::unistd::geteuid().as_raw(), nix::unistd::getegid().as_raw()),
+        mount: vec![],
+        timeouts: CacheTimeouts::default(),
+    };
 
-        for _ in 0..n {
-            let mut current = queue.pop_front().unwrap();
+    let seconds = |value: &str| value.parse::<f64>().ok()
+    .filter(|seconds| *seconds >= 0.0)
+    .map(Duration::from_secs_f64)
+    .ok_or_else(|| format!("Invalid timeout {}", value));
 
-            while let Some(directory) = current.next()? {
-                let special_dir = directory.is_current_dir() || directory.is_parent_dir();
-                if !special_dir {
-                    println!("File: {:?}", directory.name());
-                }
-                if directory.is_dir() && !special_dir {
-                    queue.push_back(Files::new(driver, &directory));
-                }
+    for option in cli.options.iter().filter(|option| !option.is_empty()) {
+        if is_mount_helper_option(option) || parsed.fat.parse_option(option)? {
+            continue;
+        }
+
+        match option.split_once('=') {
+            Some(("entry_timeout", value)) => parsed.timeouts.entry = seconds(value)?,
+            Some(("attr_timeout", value)) => parsed.timeouts.attr = seconds(value)?,
+            _=> match parse_mount_option(option) {
+                Some(MountOption::RW) => log::warn!("Writing isn't supported yet, mounting read-only"),
+                Some(mount_option) => parsed.mount.push(mount_option),
+                None if cli.sloppy => log::warn!("Ignoring unknown option {}", option),
+                None => return Err(format!("Unknown option {}", option).into()),
             }
         }
     }
 
-    Ok(())
+    let mut defaults = vec![MountOption::RO, MountOption::DefaultPermissions, MountOption::AutoUnmount];
+    if !parsed.mount.contains(&MountOption::AllowRoot) {
+        defaults.push(MountOption::AllowOther);
+    }
+    for option in defaults {
+        if !parsed.mount.contains(&option) {
+            parsed.mount.push(option);
+        }
+    }
+
+    Ok(parsed)
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    simple_logger::init().unwrap();
+    let cli = Cli::parse();
+
+    let program = std::env::args().next().unwrap_or_default();
+    let as_mount_helper = Path::new(&program).file_name().is_some_and(|name| name.to_string_lossy().starts_with("mount."));
+    let daemon = cli.daemon || (as_mount_helper && !cli.foreground);
+
+    let log_level = if cli.verbose { cli.log_level.max(log::LevelFilter::Info) } else { cli.log_level };
+    simple_logger::SimpleLogger::new()
+    .with_level(log_level)
+    .init()
+    .unwrap();
 
-    let args: Vec<_> = std::env::args().collect();
+    let mut options = parse_options(&cli)?;
 
-    let drive_path = args.get(1).expect("Please provide the path to the drive to mount");
-    let mount_point = args.get(2).expect("Please provide a mount point");
+    let file = std::fs::OpenOptions::new().read(true).open(&cli.device)?;
+    let mut drive = Drive::from_file(file)?;
+
+    if let Some(number) = cli.partition {
+        let partition = fat32::partition::partition(&drive, number)?;
+        log::info!("Partition {} starts at byte {}", number, partition.start);
+        drive = drive.with_offset(partition.start);
+    }
 
-    
-    let file = std::fs::OpenOptions::new().read(true).open(drive_path)?;
-    
-    let drive = Drive::from_file(file)?;
-    
     let driver = Driver::new(drive)?;
-    // all_files(&driver)?;
 
-    let mount_options = &vec![MountOption::RO, MountOption::AllowOther, MountOption::AutoUnmount];
-    let filesystem = Fat32::new(driver, nix::unistd::geteuid().as_raw(), nix::unistd::getegid().as_raw(), mount_options, false);
-    fuser::mount2(filesystem, mount_point, mount_options)?;
+    let has_fs_name = options.mount.iter().any(|option| matches!(option, MountOption::FSName(_)));
+    if !has_fs_name {
+        // FUSE has no way to report an fsid, so the volume serial is shown as the source instead, like blkid's UUID
+        let volume_id = driver.volume_id();
+        options.mount.push(MountOption::FSName(format!("fat32:{:04X}-{:04X}", volume_id >> 16, volume_id & 0xFFFF)));
+    }
+
+    if cli.fake {
+        return Ok(());
+    }
+
+    // Relative paths would break once the daemon changes to /
+    let mount_point = cli.mount_point.canonicalize()?;
+
+    let mount = move || {
+        let filesystem = Fat32::new(driver, options.fat, &options.mount, cli.direct_io, options.timeouts, cli.threads)?;
+        fuser::Session::new(filesystem, &mount_point, &options.mount)
+    };
+
+    if daemon {
+        return daemonize(mount);
+    }
+
+    mount()?.run()?;
 
     Ok(())
 }
+
+/// Forks a detached child that mounts the volume and serves it. The parent waits until the child reports whether the
+/// mount worked, so mount(8) and fstab see a failed mount as an error. This happens before the filesystem is created,
+/// as its thread pool wouldn't survive a fork
+fn daemonize<FS: fuser::Filesystem>(mount: impl FnOnce() -> std::io::Result<fuser::Session<FS>>) -> Result<(), Box<dyn Error>> {
+    let (mut reader, mut writer) = std::io::pipe()?;
+
+    match unsafe { nix::unistd::fork()? } {
+        nix::unistd::ForkResult::Parent { .. } => {
+            drop(writer);
+
+            let mut status = vec![];
+            reader.read_to_end(&mut status)?;
+
+            match status.split_first() {
+                Some((0, _)) => Ok(()),
+                Some((_, message)) => Err(String::from_utf8_lossy(message).into()),
+                None => Err("The mount process exited before mounting".into()),
+            }
+        }
+        nix::unistd::ForkResult::Child => {
+            drop(reader);
+            nix::unistd::setsid()?;
+
+            let mut session = match mount() {
+                Ok(session) => session,
+                Err(err) => {
+                    let _ = writer.write_all(format!("\x01{}", err).as_bytes());
+                    std::process::exit(1);
+                }
+            };
+
+            nix::unistd::chdir("/")?;
+            let null = std::fs::OpenOptions::new().read(true).write(true).open("/dev/null")?;
+            for fd in 0..=2 {
+                nix::unistd::dup2(null.as_raw_fd(), fd)?;
+            }
+
+            writer.write_all(&[0])?;
+            drop(writer);
+
+            session.run()?;
+            Ok(())
+        }
+    }
+}
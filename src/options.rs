@@ -0,0 +1,121 @@
+use fuser::MountOption;
+
+/// What readdir does with entries marked hidden or system
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HiddenPolicy {
+    Show,
+    Hide,
+    /// List them with a leading dot, so they're hidden the Unix way
+    DotPrefix,
+}
+
+/// Ownership and permission options, following the Linux vfat driver
+#[derive(Clone, Debug)]
+pub struct FatOptions {
+    pub uid: u32,
+    pub gid: u32,
+    /// Permission bits cleared for files
+    pub fmask: u16,
+    /// Permission bits cleared for directories
+    pub dmask: u16,
+    /// Permission bits of other users that allow them to change timestamps, like write permission would
+    pub allow_utime: Option<u16>,
+    /// Only set execute bits on files with an .EXE, .COM or .BAT extension
+    pub showexec: bool,
+    pub hidden: HiddenPolicy,
+}
+
+impl FatOptions {
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Self {
+            uid,
+            gid,
+            fmask: 0o022,
+            dmask: 0o022,
+            allow_utime: None,
+            showexec: false,
+            hidden: HiddenPolicy::Show,
+        }
+    }
+    /// Applies a single `name=value` option, returning false if it's not one of ours
+    pub fn parse_option(&mut self, option: &str) -> Result<bool, String> {
+        let (name, value) = match option.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (option, None),
+        };
+
+        let value = || value.ok_or_else(|| format!("Option {} needs a value", name));
+        let octal = |value: &str| u16::from_str_radix(value, 8)
+        .ok()
+        .filter(|mask| *mask <= 0o777)
+        .ok_or_else(|| format!("Invalid octal mask for {}: {}", name, value));
+        let decimal = |value: &str| value.parse::<u32>().map_err(|_| format!("Invalid id for {}: {}", name, value));
+
+        match name {
+            "uid" => self.uid = decimal(value()?)?,
+            "gid" => self.gid = decimal(value()?)?,
+            "umask" => {
+                let mask = octal(value()?)?;
+                self.fmask = mask;
+                self.dmask = mask;
+            },
+            "fmask" => self.fmask = octal(value()?)?,
+            "dmask" => self.dmask = octal(value()?)?,
+            "allow_utime" => self.allow_utime = Some(octal(value()?)?),
+            "showexec" => self.showexec = true,
+            "hidden" => self.hidden = match value()? {
+                "show" => HiddenPolicy::Show,
+                "hide" => HiddenPolicy::Hide,
+                "dotprefix" => HiddenPolicy::DotPrefix,
+                other => return Err(format!("Invalid value for hidden: {}, expected show, hide or dotprefix", other))
+            },
+            _=> return Ok(false)
+        }
+
+        Ok(true)
+    }
+    /// Unless given, other users may change timestamps if `dmask` allows them to write
+    pub fn allow_utime(&self) -> u16 {
+        self.allow_utime.unwrap_or(!self.dmask & 0o022)
+    }
+}
+
+/// Translates one of the generic FUSE mount options, None if it isn't one
+pub fn parse_mount_option(option: &str) -> Option<MountOption> {
+    let mount_option = match option {
+        "ro" => MountOption::RO,
+        "rw" => MountOption::RW,
+        "exec" => MountOption::Exec,
+        "noexec" => MountOption::NoExec,
+        "suid" => MountOption::Suid,
+        "nosuid" => MountOption::NoSuid,
+        "dev" => MountOption::Dev,
+        "nodev" => MountOption::NoDev,
+        "atime" => MountOption::Atime,
+        "noatime" => MountOption::NoAtime,
+        "sync" => MountOption::Sync,
+        "async" => MountOption::Async,
+        "dirsync" => MountOption::DirSync,
+        "allow_other" => MountOption::AllowOther,
+        "allow_root" => MountOption::AllowRoot,
+        "auto_unmount" => MountOption::AutoUnmount,
+        "default_permissions" => MountOption::DefaultPermissions,
+        _=> {
+            let (name, value) = option.split_once('=')?;
+            match name {
+                "fsname" => MountOption::FSName(value.to_owned()),
+                "subtype" => MountOption::Subtype(value.to_owned()),
+                _=> return None
+            }
+        }
+    };
+
+    Some(mount_option)
+}
+
+/// Options from fstab that are meant for mount(8) itself and are passed along to helpers anyway
+pub fn is_mount_helper_option(option: &str) -> bool {
+    matches!(option, "defaults" | "auto" | "noauto" | "user" | "nouser" | "users" | "owner" | "group" | "nofail" | "_netdev")
+    || option.starts_with("x-")
+    || option.starts_with("comment=")
+}
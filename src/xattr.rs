@@ -0,0 +1,128 @@
+use std::ffi::{c_int, OsStr};
+
+use fat32::{FatDirectory, DIR_ATTR_CHANGEABLE};
+use nix::libc;
+
+/// Attribute byte as text, e.g. 0x22
+pub const DOS_ATTRIB: &str = "user.dos.attrib";
+/// Attribute byte as a little endian u32, like ntfs-3g's system.ntfs_attrib
+pub const SYSTEM_DOS_ATTRIB: &str = "system.dos_attrib";
+/// The 8.3 alias of the entry, read-only
+pub const SHORT_NAME: &str = "user.dos.short_name";
+/// First cluster of the entry in decimal, read-only
+pub const FIRST_CLUSTER: &str = "user.dos.first_cluster";
+
+pub fn names(directory: &FatDirectory) -> Vec<&'static str> {
+    let mut names = vec![DOS_ATTRIB, SYSTEM_DOS_ATTRIB];
+
+    // The root directory has no entry, so no short name
+    if directory.location().is_some() {
+        names.push(SHORT_NAME);
+    }
+    names.push(FIRST_CLUSTER);
+
+    names
+}
+
+pub fn get(directory: &FatDirectory, name: &OsStr) -> Option<Vec<u8>> {
+    let name = name.to_str()?;
+    if !names(directory).contains(&name) {
+        return None;
+    }
+
+    let value = match name {
+        DOS_ATTRIB => format!("{:#04x}", directory.attributes()).into_bytes(),
+        SYSTEM_DOS_ATTRIB => (directory.attributes() as u32).to_le_bytes().to_vec(),
+        SHORT_NAME => directory.entry().short_name().into_encoded_bytes(),
+        FIRST_CLUSTER => directory.cluster_num().to_string().into_bytes(),
+        _=> unreachable!()
+    };
+
+    Some(value)
+}
+
+/// Whether `uid` may change the attributes of an entry on a volume owned by `owner`. Like chmod, only the owner
+/// and root may, and nobody on a read-only mount, which is every mount until writing is supported
+pub fn may_set_attributes(read_only: bool, uid: u32, owner: u32) -> Result<(), c_int> {
+    if read_only {
+        return Err(libc::EROFS);
+    }
+    if uid != 0 && uid != owner {
+        return Err(libc::EPERM);
+    }
+
+    Ok(())
+}
+
+/// The attribute byte after `requested` is set through the ioctl. As in vfat, the bits saying what the entry is
+/// are kept whatever was passed
+pub fn ioctl_attributes(requested: u32, current: u8) -> u8 {
+    (requested as u8 & DIR_ATTR_CHANGEABLE) | (current & !DIR_ATTR_CHANGEABLE)
+}
+
+/// Decodes a new attribute byte written to `name`, or the errno to fail with
+pub fn parse_attributes(name: &OsStr, value: &[u8]) -> Result<u8, c_int> {
+    let name = name.to_str().ok_or(libc::ENODATA)?;
+
+    let attr = match name {
+        DOS_ATTRIB => {
+            let text = std::str::from_utf8(value).map_err(|_| libc::EINVAL)?;
+            let text = text.trim_end_matches('\0').trim();
+
+            let parsed = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => text.parse::<u32>(),
+            };
+            parsed.map_err(|_| libc::EINVAL)?
+        },
+        SYSTEM_DOS_ATTRIB => {
+            let bytes: [u8; 4] = value.try_into().map_err(|_| libc::EINVAL)?;
+            u32::from_le_bytes(bytes)
+        },
+        SHORT_NAME | FIRST_CLUSTER => return Err(libc::EPERM),
+        _=> return Err(libc::ENOTSUP)
+    };
+
+    u8::try_from(attr).map_err(|_| libc::EINVAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use fat32::{DIR_ATTR_ARCHIVE, DIR_ATTR_DIRECTORY, DIR_ATTR_HIDDEN, DIR_ATTR_READ_ONLY, DIR_ATTR_VOLUME_ID};
+
+    use super::*;
+
+    #[test]
+    fn parses_attribute_values() {
+        assert_eq!(parse_attributes(OsStr::new(DOS_ATTRIB), b"0x21"), Ok(0x21));
+        assert_eq!(parse_attributes(OsStr::new(DOS_ATTRIB), b"0X21\0"), Ok(0x21));
+        assert_eq!(parse_attributes(OsStr::new(DOS_ATTRIB), b" 33\n"), Ok(0x21));
+        assert_eq!(parse_attributes(OsStr::new(SYSTEM_DOS_ATTRIB), &0x21u32.to_le_bytes()), Ok(0x21));
+
+        assert_eq!(parse_attributes(OsStr::new(DOS_ATTRIB), b"0x121"), Err(libc::EINVAL));
+        assert_eq!(parse_attributes(OsStr::new(DOS_ATTRIB), b"hidden"), Err(libc::EINVAL));
+        assert_eq!(parse_attributes(OsStr::new(SYSTEM_DOS_ATTRIB), &[0x21]), Err(libc::EINVAL));
+        assert_eq!(parse_attributes(OsStr::new(SHORT_NAME), b"NAME.TXT"), Err(libc::EPERM));
+        assert_eq!(parse_attributes(OsStr::new(FIRST_CLUSTER), b"2"), Err(libc::EPERM));
+        assert_eq!(parse_attributes(OsStr::new("user.other"), b"0x21"), Err(libc::ENOTSUP));
+    }
+
+    #[test]
+    fn only_the_owner_sets_attributes_on_writable_mounts() {
+        assert_eq!(may_set_attributes(true, 0, 1000), Err(libc::EROFS));
+        assert_eq!(may_set_attributes(true, 1000, 1000), Err(libc::EROFS));
+
+        assert_eq!(may_set_attributes(false, 0, 1000), Ok(()));
+        assert_eq!(may_set_attributes(false, 1000, 1000), Ok(()));
+        assert_eq!(may_set_attributes(false, 1001, 1000), Err(libc::EPERM));
+    }
+
+    #[test]
+    fn the_ioctl_keeps_what_the_entry_is() {
+        let requested = (DIR_ATTR_HIDDEN | DIR_ATTR_READ_ONLY | DIR_ATTR_VOLUME_ID) as u32;
+        assert_eq!(ioctl_attributes(requested, DIR_ATTR_DIRECTORY | DIR_ATTR_ARCHIVE), DIR_ATTR_DIRECTORY | DIR_ATTR_HIDDEN | DIR_ATTR_READ_ONLY);
+        assert_eq!(ioctl_attributes(0, DIR_ATTR_DIRECTORY | DIR_ATTR_ARCHIVE), DIR_ATTR_DIRECTORY);
+        // Only the low byte counts
+        assert_eq!(ioctl_attributes(0x100 | DIR_ATTR_ARCHIVE as u32, 0), DIR_ATTR_ARCHIVE);
+    }
+}